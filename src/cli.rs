@@ -1,17 +1,200 @@
 //! CLI for testing OCI distribution servers
-use crate::tester::{load_test_pull, load_test_push};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs,
+    io::{self, Read},
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    client::{platform_name, PullPlatform},
+    conformance::{self as checks, Outcome},
+    docker_config,
+    metrics::{self, Operation, Timer},
+    registry::{Credentials, HttpOptions, Registry, RetryPolicy, Timeouts, UploadMode},
+    report::{self, ReportOptions, Run},
+    scheduler::{self, Stage},
+    slo::{self, Thresholds},
+    tester::{
+        load_test_mount, load_test_pull, load_test_push, ErrorCategory, LoadOptions, LoadTestError,
+        PushOptions, Sample,
+    },
+    token_server::{self, TokenServerOptions},
+};
 use anyhow::{anyhow, bail, Context, Result};
-use oci_distribution::{client::ClientProtocol, secrets::RegistryAuth, Reference};
-use tracing::{error, info};
+use oci_distribution::{client::ClientProtocol, manifest::Platform, Reference};
+use tracing::{debug, error, info, warn};
+
+/// Parses credentials in the format `user:password`. The password may contain colons.
+fn parse_userpass(userpass: &str) -> Result<(String, String)> {
+    let (user, password) = userpass
+        .split_once(':')
+        .ok_or_else(|| anyhow!("credentials must be in the format user:password"))?;
+    if user.is_empty() {
+        bail!("the user of the credentials is empty");
+    }
+    Ok((user.to_string(), password.to_string()))
+}
+
+/// How to authenticate against a registry. The first of the token, the identity token, the user
+/// and password, the credentials file, stdin and the Docker config is used, and requests are
+/// anonymous without any.
+#[derive(Clone, Default)]
+pub struct AuthOptions {
+    /// The user and password, as `user:password`, sent with basic authentication or exchanged for
+    /// a token with the realm of the registry.
+    pub userpass: Option<String>,
+    /// A bearer token sent as is.
+    pub token: Option<String>,
+    /// An identity token exchanged for access tokens with the realm of the registry.
+    pub identity_token: Option<String>,
+    /// A file holding the user and password, as `user:password`.
+    pub creds_file: Option<PathBuf>,
+    /// Whether to read the user and password, as `user:password`, from stdin.
+    pub creds_stdin: bool,
+    /// A Docker config file to look the credentials of the registry up in.
+    pub docker_config: Option<PathBuf>,
+}
+
+/// Returns the credentials `auth` asks for to authenticate against `registry`.
+fn credentials(auth: AuthOptions, registry: &str) -> Result<Credentials> {
+    if let Some(token) = auth.token {
+        return Ok(Credentials::Bearer(token));
+    }
+    if let Some(token) = auth.identity_token {
+        return Ok(Credentials::IdentityToken(token));
+    }
+    let userpass = if let Some(userpass) = auth.userpass {
+        userpass
+    } else if let Some(path) = auth.creds_file {
+        fs::read_to_string(&path)
+            .with_context(|| format!("failed to read the credentials file {}", path.display()))?
+    } else if auth.creds_stdin {
+        let mut userpass = String::new();
+        io::stdin()
+            .read_to_string(&mut userpass)
+            .context("failed to read the credentials from stdin")?;
+        userpass
+    } else if let Some(path) = auth.docker_config {
+        return docker_config::lookup(&path, registry);
+    } else {
+        return Ok(Credentials::Anonymous);
+    };
+    let (user, password) = parse_userpass(userpass.trim_end_matches(['\r', '\n']))?;
+    Ok(Credentials::Basic(user, password))
+}
 
-fn parse_userpass(userpass: &str) -> (String, String) {
-    let parts: Vec<&str> = userpass.split(':').collect();
-    let username = parts[0].to_string();
-    let password = parts[1].to_string();
-    (username, password)
+/// How to connect to a registry.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkOptions {
+    /// PEM files of root certificates to trust on top of the built in ones.
+    pub ca_certs: Vec<PathBuf>,
+    /// A PEM file of the client certificate presented to registries asking for mutual TLS.
+    pub client_cert: Option<PathBuf>,
+    /// A PEM file of the private key of the client certificate.
+    pub client_key: Option<PathBuf>,
+    /// Whether to skip the verification of the certificates of registries.
+    pub insecure_skip_verify: bool,
+    /// Hosts (with an optional port) reached over plain HTTP, even when the registry URL, a token
+    /// realm or an upload location is HTTPS.
+    pub plain_http_hosts: Vec<String>,
+    /// The URL of a proxy every request goes through. The proxies of `HTTP_PROXY`, `HTTPS_PROXY`
+    /// and `ALL_PROXY` are used when `None`. Hosts in `NO_PROXY` are reached directly either way.
+    pub proxy: Option<String>,
+    /// How long requests may take before they time out.
+    pub timeouts: Timeouts,
 }
 
-fn parse_reg(registry_url: &str) -> Result<(String, ClientProtocol)> {
+/// Loads the certificates and key `network` points at, and sets up its proxy.
+fn http_options(network: &NetworkOptions) -> Result<HttpOptions> {
+    let mut root_certificates = Vec::new();
+    for path in &network.ca_certs {
+        let pem = fs::read(path)
+            .with_context(|| format!("failed to read the CA certificates {}", path.display()))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("invalid CA certificates {}", path.display()))?;
+        if certificates.is_empty() {
+            bail!("no CA certificates in {}", path.display());
+        }
+        root_certificates.extend(certificates);
+    }
+
+    let identity = match (&network.client_cert, &network.client_key) {
+        (Some(cert), Some(key)) => {
+            let mut pem = fs::read(cert).with_context(|| {
+                format!("failed to read the client certificate {}", cert.display())
+            })?;
+            pem.push(b'\n');
+            pem.extend(
+                fs::read(key)
+                    .with_context(|| format!("failed to read the client key {}", key.display()))?,
+            );
+            Some(reqwest::Identity::from_pem(&pem).with_context(|| {
+                format!(
+                    "invalid client certificate {} or key {}",
+                    cert.display(),
+                    key.display()
+                )
+            })?)
+        }
+        (None, None) => None,
+        _ => bail!("the client certificate and key must be given together"),
+    };
+    if network.insecure_skip_verify {
+        warn!("Skipping the verification of the certificates of registries");
+    }
+
+    let proxy = if let Some(url) = &network.proxy {
+        info!(
+            proxy = url,
+            no_proxy = env_proxy("NO_PROXY"),
+            "Using a proxy"
+        );
+        Some(
+            reqwest::Proxy::all(url)
+                .with_context(|| format!("invalid proxy {url}"))?
+                .no_proxy(reqwest::NoProxy::from_env()),
+        )
+    } else {
+        for var in ["HTTP_PROXY", "HTTPS_PROXY", "ALL_PROXY"] {
+            if let Some(url) = env_proxy(var) {
+                info!(
+                    proxy = url,
+                    no_proxy = env_proxy("NO_PROXY"),
+                    "Using the proxy of ${var}"
+                );
+            }
+        }
+        None
+    };
+
+    let http = HttpOptions {
+        root_certificates,
+        identity,
+        accept_invalid_certificates: network.insecure_skip_verify,
+        proxy,
+        retry: RetryPolicy::default(),
+        timeouts: network.timeouts,
+    };
+    http.client()
+        .context("invalid TLS or proxy configuration")?;
+    Ok(http)
+}
+
+/// Returns the value of a proxy environment variable, in upper or lower case.
+fn env_proxy(var: &str) -> Option<String> {
+    std::env::var(var)
+        .or_else(|_| std::env::var(var.to_lowercase()))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+/// Parses the registry URL into the registry (a host with an optional port) and the protocol to
+/// reach it with. The `plain_http_hosts` are reached over HTTP even when the URL is HTTPS.
+fn parse_reg(registry_url: &str, plain_http_hosts: &[String]) -> Result<(String, ClientProtocol)> {
     let reg_url =
         url::Url::parse(registry_url).context("failed to parse the url: {registry_url}")?;
     let reg_host = reg_url
@@ -30,58 +213,364 @@ fn parse_reg(registry_url: &str) -> Result<(String, ClientProtocol)> {
 
     let protocol = match reg_protocol {
         "http" => ClientProtocol::Http,
-        "https" => ClientProtocol::Https,
+        "https" if plain_http_hosts.is_empty() => ClientProtocol::Https,
+        "https" => ClientProtocol::HttpsExcept(plain_http_hosts.to_vec()),
         _ => bail!("unknown protocol: {reg_protocol}"),
     };
 
     Ok((reg, protocol))
 }
 
+fn check_load_options(options: &LoadOptions) -> Result<()> {
+    if options.concurrency == Some(0) {
+        bail!("concurrency must be at least 1");
+    }
+    if let Some(rate) = options.rate {
+        if !rate.is_finite() || rate <= 0.0 {
+            bail!("rate must be a positive number of operations per second: {rate}");
+        }
+    }
+    Ok(())
+}
+
+/// Formats a latency in milliseconds.
+fn millis(latency: Duration) -> String {
+    format!("{:.2}ms", latency.as_secs_f64() * 1000.0)
+}
+
+/// How many operations of a load test succeeded, eventually or at the first attempt, and what
+/// the others failed with.
+#[derive(Debug, Clone, Default)]
+struct Totals {
+    total: usize,
+    success: usize,
+    /// The operations which succeeded without retrying any request.
+    first_attempt: usize,
+    /// The requests retried by all the operations.
+    retries: u64,
+    /// The failed operations by the category of their error.
+    errors: BTreeMap<ErrorCategory, usize>,
+}
+
+impl Totals {
+    fn add<T>(&mut self, sample: &Sample<T>) {
+        self.total += 1;
+        self.retries += u64::from(sample.retries);
+        match &sample.result {
+            Ok(_) => {
+                self.success += 1;
+                if sample.retries == 0 {
+                    self.first_attempt += 1;
+                }
+            }
+            Err(e) => *self.errors.entry(e.category()).or_default() += 1,
+        }
+    }
+
+    /// Returns the errors, most frequent first, such as `412 × 503 UNAVAILABLE, 3 × TLS
+    /// handshake`.
+    fn errors(&self) -> String {
+        let mut errors: Vec<_> = self.errors.iter().collect();
+        errors.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        errors
+            .iter()
+            .map(|(category, count)| format!("{count} × {category}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Display for Totals {
+    #[allow(clippy::cast_precision_loss)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = |count: usize| 100.0 * count as f64 / self.total.max(1) as f64;
+        write!(
+            f,
+            "Total: {}, Success: {} ({:.1}%), First attempt success: {} ({:.1}%)",
+            self.total,
+            self.success,
+            percent(self.success),
+            self.first_attempt,
+            percent(self.first_attempt)
+        )?;
+        if !self.errors.is_empty() {
+            write!(f, ", Errors: {}", self.errors())?;
+        }
+        Ok(())
+    }
+}
+
+/// Logs how many operations of a load test succeeded and what the others failed with, per stage
+/// when the load test was time driven, followed by the latency and throughput of every registry
+/// operation. Only the first error of every category is logged on its own, the others at the
+/// debug level.
+fn summarize<T>(samples: Vec<Sample<T>>, stages: &[Stage], elapsed: Duration) {
+    let stats = metrics::stats(samples.iter().flat_map(|s| &s.timings));
+
+    let mut stage_totals = vec![Totals::default(); stages.len()];
+    let mut totals = Totals::default();
+    for sample in samples {
+        totals.add(&sample);
+        if let Some(stage_totals) = sample.stage.and_then(|s| stage_totals.get_mut(s)) {
+            stage_totals.add(&sample);
+        }
+        if let Err(e) = sample.result {
+            if totals.errors.get(&e.category()) == Some(&1) {
+                error!(category = %e.category(), "{e}");
+            } else {
+                debug!(category = %e.category(), "{e}");
+            }
+        }
+    }
+
+    let phases = scheduler::phases(stages);
+    for (i, ((stage, phase), totals)) in stages.iter().zip(phases).zip(stage_totals).enumerate() {
+        info!(
+            stage = i,
+            phase = %phase,
+            duration = ?stage.duration,
+            target = stage.target,
+            retries = totals.retries,
+            "{totals}"
+        );
+    }
+    if totals.errors.is_empty() {
+        info!(retries = totals.retries, "{totals}");
+    } else {
+        error!(retries = totals.retries, "{totals}");
+    }
+
+    for (operation, stats) in stats {
+        info!(
+            operation = %operation,
+            success = stats.success,
+            errors = stats.errors,
+            "min: {}, mean: {}, p50: {}, p90: {}, p99: {}, p99.9: {}, max: {}, {:.2} ops/s, {:.2} MB/s",
+            millis(stats.min()),
+            millis(stats.mean()),
+            millis(stats.quantile(0.5)),
+            millis(stats.quantile(0.9)),
+            millis(stats.quantile(0.99)),
+            millis(stats.quantile(0.999)),
+            millis(stats.max()),
+            stats.ops_per_sec(elapsed),
+            stats.megabytes_per_sec(elapsed),
+        );
+    }
+}
+
+/// Writes the report of a run when one was asked for, logs the summary of the run and checks it
+/// against the thresholds.
+///
+/// # Errors
+///
+/// * If the report can't be written
+/// * If the run didn't meet the thresholds
+fn conclude<T>(
+    run: &Run<'_>,
+    samples: Vec<Sample<T>>,
+    report: Option<&ReportOptions>,
+    thresholds: &Thresholds,
+) -> Result<()> {
+    let checks = slo::check(thresholds, &samples);
+    if let Some(report) = report {
+        report::write(report, run, &samples, &checks)?;
+        info!(path = %report.path.display(), "Wrote the report");
+    }
+    summarize(samples, run.stages, run.elapsed);
+
+    let mut failed = Vec::new();
+    for check in checks {
+        if check.passed {
+            info!(
+                threshold = check.name,
+                limit = check.limit,
+                "Passed with {}",
+                check.actual
+            );
+        } else {
+            error!(
+                threshold = check.name,
+                limit = check.limit,
+                "Failed with {}",
+                check.actual
+            );
+            failed.push(format!("{} {} > {}", check.name, check.actual, check.limit));
+        }
+    }
+    if !failed.is_empty() {
+        bail!("the run didn't meet its thresholds: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+fn check_push_options(push: &PushOptions) -> Result<()> {
+    if push.chunk_size == 0 {
+        bail!("chunk size must be at least 1 byte");
+    }
+    if push.files == 0 {
+        bail!("layers must have at least 1 file");
+    }
+    if push.repositories == Some(0) {
+        bail!("images must be spread over at least 1 repository");
+    }
+    Ok(())
+}
+
+/// Returns the configuration of a push load test, as recorded in reports.
+fn push_config(options: &LoadOptions, push: &PushOptions) -> BTreeMap<&'static str, String> {
+    let mut config = load_config(options);
+    config.insert("upload_mode", push.upload_mode.to_string());
+    if push.upload_mode == UploadMode::Chunked || push.stream_layers {
+        config.insert("chunk_size", push.chunk_size.to_string());
+    }
+    config.insert("check_exists", push.check_exists.to_string());
+    config.insert("layers", push.layers.to_string());
+    config.insert("layer_size", push.layer_size.to_string());
+    config.insert("compression", push.compression.to_string());
+    config.insert("compressibility", push.compressibility.to_string());
+    config.insert("files", push.files.to_string());
+    config.insert("depth", push.depth.to_string());
+    config.insert("stream_layers", push.stream_layers.to_string());
+    if push.shared_layers > 0.0 {
+        config.insert("shared_layers", push.shared_layers.to_string());
+        config.insert("layer_pool", push.layer_pool.to_string());
+    }
+    if let Some(repositories) = push.repositories {
+        config.insert("repositories", repositories.to_string());
+    }
+    if let Some(seed) = push.seed {
+        config.insert("seed", seed.to_string());
+    }
+    config
+}
+
+/// Counts the successful timings of `operation` in the samples of a load test.
+fn count_timings<T>(samples: &[Sample<T>], operation: Operation) -> usize {
+    samples
+        .iter()
+        .flat_map(|s| &s.timings)
+        .filter(|t| t.operation == operation && t.success)
+        .count()
+}
+
+/// Returns the percentage of the mounts the registry performed instead of falling back to an
+/// upload, or 0 without any mount.
+#[allow(clippy::cast_precision_loss)]
+fn mount_rate(mounted: usize, fallback: usize) -> f64 {
+    if mounted + fallback == 0 {
+        0.0
+    } else {
+        mounted as f64 / (mounted + fallback) as f64 * 100.0
+    }
+}
+
+/// Logs how many blobs the registry already had, when the pushes checked for them.
+fn log_existing_blobs<T>(samples: &[Sample<T>], push: &PushOptions) {
+    if push.check_exists {
+        info!(
+            "Existing blobs: {}, Missing blobs: {}",
+            count_timings(samples, Operation::BlobExists),
+            count_timings(samples, Operation::BlobMissing)
+        );
+    }
+}
+
+/// Returns the configuration of a load test, as recorded in reports.
+fn load_config(options: &LoadOptions) -> BTreeMap<&'static str, String> {
+    let mut config = BTreeMap::new();
+    config.insert("connections", options.connections.to_string());
+    retry_config(&mut config, &options.http.retry);
+    if let Some(concurrency) = options.concurrency {
+        config.insert("concurrency", concurrency.to_string());
+    }
+    if let Some(rate) = options.rate {
+        config.insert("rate", rate.to_string());
+    }
+    if !options.stages.is_empty() {
+        let stages: Vec<String> = options
+            .stages
+            .iter()
+            .map(|stage| format!("{:?}:{}", stage.duration, stage.target))
+            .collect();
+        config.insert("stages", stages.join(" "));
+    }
+    config
+}
+
+/// Records the retry policy in the config of a run, when requests are retried.
+fn retry_config(config: &mut BTreeMap<&'static str, String>, retry: &RetryPolicy) {
+    if retry.max_retries > 0 {
+        config.insert("retries", retry.max_retries.to_string());
+        config.insert("retry_backoff", format!("{:?}", retry.initial_backoff));
+        config.insert("retry_max_backoff", format!("{:?}", retry.max_backoff));
+    }
+}
+
 /// Pulls images from a registry.
 ///
 /// # Errors
 ///
 /// * If the image is not valid
 /// * If the registry URL is not valid
+/// * If the credentials can't be loaded
+/// * If the certificates or the key can't be loaded
+/// * If the concurrency or rate is not valid
+/// * If the report can't be written
+/// * If any pull failed or the run didn't meet the thresholds
+#[allow(clippy::too_many_arguments)]
 pub async fn pull_images(
     reg_url: String,
     count: usize,
-    reg_userpass: Option<String>,
+    auth: AuthOptions,
+    network: NetworkOptions,
+    retry: RetryPolicy,
     image: String,
+    platform: PullPlatform,
+    mut options: LoadOptions,
+    report: Option<ReportOptions>,
+    thresholds: Thresholds,
 ) -> Result<()> {
-    let (reg, protocol) = parse_reg(&reg_url).context("couldn't parse the reg url: {reg_url}")?;
+    check_load_options(&options)?;
+    let (reg, protocol) = parse_reg(&reg_url, &network.plain_http_hosts)
+        .context("couldn't parse the reg url: {reg_url}")?;
 
     let image = Reference::try_from(format!("{reg}/{image}"))
         .context("failed to parse the image: {image}")?;
 
-    let mut auth = RegistryAuth::Anonymous;
-    if let Some(userpass) = reg_userpass {
-        let (user, password) = parse_userpass(&userpass);
-        auth = RegistryAuth::Basic(user, password);
-    }
+    let auth = credentials(auth, &reg)?;
+    options.http = http_options(&network)?;
+    options.http.retry = retry;
 
     info!(
         image = image.whole(),
+        platform = %platform,
         count = count,
+        concurrency = options.concurrency,
+        rate = options.rate,
+        stages = options.stages.len(),
         registry_url = reg_url,
         "Pulling images"
     );
 
-    let results = load_test_pull(count, image, auth, protocol).await;
+    let mut config = load_config(&options);
+    config.insert("registry_url", reg_url);
+    config.insert("image", image.whole());
+    config.insert("platform", platform.to_string());
+    config.insert("count", count.to_string());
 
-    let total = results.len();
-    let success = results
-        .into_iter()
-        .map(|r| r.map_err(|e| error!("{e}")))
-        .filter(Result::is_ok)
-        .count();
-
-    info!(
-        "Total: {total}, Success: {success}",
-        total = total,
-        success = success
-    );
-    Ok(())
+    let stages = options.stages.clone();
+    let started_at = SystemTime::now();
+    let start = Instant::now();
+    let results = load_test_pull(count, image, auth, protocol, platform, options).await;
+    let run = Run {
+        command: "pull-images",
+        config,
+        stages: &stages,
+        started_at,
+        elapsed: start.elapsed(),
+    };
+    conclude(&run, results, report.as_ref(), &thresholds)
 }
 
 /// Pushes images to a registry.
@@ -90,68 +579,635 @@ pub async fn pull_images(
 /// * If the count is not a valid number
 /// * If the registry URL is not provided
 /// * If the registry URL is not valid
+/// * If the credentials can't be loaded
+/// * If the certificates or the key can't be loaded
+/// * If the concurrency or rate is not valid
+/// * If the report can't be written
+/// * If any push failed or the run didn't meet the thresholds
+#[allow(clippy::too_many_arguments)]
 pub async fn push_images(
     reg_url: String,
     count: usize,
-    reg_userpass: Option<String>,
+    auth: AuthOptions,
+    network: NetworkOptions,
+    retry: RetryPolicy,
     namespace: String,
     image: String,
     tag: String,
+    mut options: LoadOptions,
+    mut push: PushOptions,
+    report: Option<ReportOptions>,
+    thresholds: Thresholds,
 ) -> Result<()> {
-    let (reg, protocol) = parse_reg(&reg_url).context("couldn't parse the reg url: {reg_url}")?;
+    check_load_options(&options)?;
+    check_push_options(&push)?;
+    // Pick the seed up front, so it can be logged and reported to reproduce the run.
+    let seed = *push.seed.get_or_insert_with(rand::random);
+    let (reg, protocol) = parse_reg(&reg_url, &network.plain_http_hosts)
+        .context("couldn't parse the reg url: {reg_url}")?;
 
-    let mut auth = RegistryAuth::Anonymous;
-    if let Some(userpass) = reg_userpass {
-        let (user, password) = parse_userpass(&userpass);
-        auth = RegistryAuth::Basic(user, password);
-    }
+    let auth = credentials(auth, &reg)?;
+    options.http = http_options(&network)?;
+    options.http.retry = retry;
 
-    info!(count = count, registry_url = reg_url, "Pushing images");
+    info!(
+        count = count,
+        concurrency = options.concurrency,
+        rate = options.rate,
+        stages = options.stages.len(),
+        upload_mode = %push.upload_mode,
+        seed = seed,
+        registry_url = reg_url,
+        "Pushing images"
+    );
 
-    let results = load_test_push(count, reg, auth, protocol, namespace, image, tag).await;
+    let mut config = push_config(&options, &push);
+    config.insert("registry_url", reg_url);
+    config.insert("namespace", namespace.clone());
+    config.insert("image", image.clone());
+    config.insert("tag", tag.clone());
+    config.insert("count", count.to_string());
 
-    let total = results.len();
-    let success = results
-        .into_iter()
-        .map(|r| r.map_err(|e| error!("{e}")))
-        .filter(Result::is_ok)
-        .count();
+    let stages = options.stages.clone();
+    let started_at = SystemTime::now();
+    let start = Instant::now();
+    let results = load_test_push(
+        count,
+        reg,
+        auth,
+        protocol,
+        namespace,
+        image,
+        tag,
+        options,
+        push.clone(),
+    )
+    .await;
+    let run = Run {
+        command: "push-images",
+        config,
+        stages: &stages,
+        started_at,
+        elapsed: start.elapsed(),
+    };
+    log_existing_blobs(&results, &push);
+    conclude(&run, results, report.as_ref(), &thresholds)
+}
+
+/// Pushes a base image to a registry, then images derived from it into other repositories,
+/// mounting the blobs they share with the base image.
+///
+/// # Errors
+/// * If the registry URL is not valid
+/// * If the credentials can't be loaded
+/// * If the certificates or the key can't be loaded
+/// * If the concurrency, rate or chunk size is not valid
+/// * If the base image can't be pushed
+/// * If the report can't be written
+/// * If any push failed or the run didn't meet the thresholds
+#[allow(clippy::too_many_arguments)]
+pub async fn mount_images(
+    reg_url: String,
+    count: usize,
+    auth: AuthOptions,
+    network: NetworkOptions,
+    retry: RetryPolicy,
+    namespace: String,
+    image: String,
+    tag: String,
+    mut options: LoadOptions,
+    mut push: PushOptions,
+    report: Option<ReportOptions>,
+    thresholds: Thresholds,
+) -> Result<()> {
+    check_load_options(&options)?;
+    check_push_options(&push)?;
+    // Pick the seed up front, so it can be logged and reported to reproduce the run.
+    let seed = *push.seed.get_or_insert_with(rand::random);
+    let (reg, protocol) = parse_reg(&reg_url, &network.plain_http_hosts)
+        .context("couldn't parse the reg url: {reg_url}")?;
+
+    let auth = credentials(auth, &reg)?;
+    options.http = http_options(&network)?;
+    options.http.retry = retry;
 
     info!(
-        "Total: {total}, Success: {success}",
-        total = total,
-        success = success
+        count = count,
+        concurrency = options.concurrency,
+        rate = options.rate,
+        stages = options.stages.len(),
+        upload_mode = %push.upload_mode,
+        seed = seed,
+        registry_url = reg_url,
+        "Pushing images mounting the blobs of a base image"
     );
-    Ok(())
+
+    let mut config = push_config(&options, &push);
+    config.insert("registry_url", reg_url);
+    config.insert("namespace", namespace.clone());
+    config.insert("image", image.clone());
+    config.insert("tag", tag.clone());
+    config.insert("count", count.to_string());
+
+    let stages = options.stages.clone();
+    let started_at = SystemTime::now();
+    let start = Instant::now();
+    let results = load_test_mount(
+        count,
+        reg,
+        auth,
+        protocol,
+        namespace,
+        image,
+        tag,
+        options,
+        push.clone(),
+    )
+    .await
+    .context("failed to push the base image")?;
+    let run = Run {
+        command: "mount-images",
+        config,
+        stages: &stages,
+        started_at,
+        elapsed: start.elapsed(),
+    };
+
+    let mounted = count_timings(&results, Operation::BlobMount);
+    let fallback = count_timings(&results, Operation::BlobMountFallback);
+    info!(
+        "Mounted: {mounted}, Fallback: {fallback}, Mount rate: {:.2}%",
+        mount_rate(mounted, fallback)
+    );
+    log_existing_blobs(&results, &push);
+
+    conclude(&run, results, report.as_ref(), &thresholds)
 }
-/// Pushes an image index to a registry.
+
+/// Parses a platform from `os/architecture` or `os/architecture/variant`.
+///
+/// # Errors
+///
+/// * If the platform doesn't have two or three parts
+pub fn parse_platform(platform: &str) -> Result<Platform> {
+    let parts: Vec<&str> = platform.trim().split('/').collect();
+    let (os, architecture, variant) = match parts[..] {
+        [os, architecture] => (os, architecture, None),
+        [os, architecture, variant] => (os, architecture, Some(variant)),
+        _ => bail!("expected os/architecture[/variant]: {platform}"),
+    };
+    if os.is_empty() || architecture.is_empty() || variant.is_some_and(str::is_empty) {
+        bail!("expected os/architecture[/variant]: {platform}");
+    }
+    Ok(Platform {
+        architecture: architecture.to_string(),
+        os: os.to_string(),
+        os_version: None,
+        os_features: None,
+        variant: variant.map(str::to_string),
+        features: None,
+    })
+}
+
+/// Pushes an image index to a registry, along with an image for every platform of `platforms`.
 ///
 /// # Errors
 /// * If the registry URL is not valid
+/// * If the credentials can't be loaded
+/// * If the certificates or the key can't be loaded
 /// * If the image is not valid
+/// * If the push options are not valid
+/// * If the report can't be written
+/// * If the push failed or the run didn't meet the thresholds
+#[allow(clippy::too_many_arguments)]
 pub async fn push_image_index(
     reg_url: String,
-    reg_userpass: Option<String>,
+    auth: AuthOptions,
+    network: NetworkOptions,
+    retry: RetryPolicy,
     image: String,
+    mut push: PushOptions,
+    platforms: Vec<Platform>,
+    report: Option<ReportOptions>,
+    thresholds: Thresholds,
 ) -> Result<()> {
-    let (reg, protocol) = parse_reg(&reg_url).context("couldn't parse the reg url: {reg_url}")?;
+    check_push_options(&push)?;
+    let seed = *push.seed.get_or_insert_with(rand::random);
+    let (reg, protocol) = parse_reg(&reg_url, &network.plain_http_hosts)
+        .context("couldn't parse the reg url: {reg_url}")?;
 
-    let mut auth = RegistryAuth::Anonymous;
-    if let Some(userpass) = reg_userpass {
-        let (user, password) = parse_userpass(&userpass);
-        auth = RegistryAuth::Basic(user, password);
-    }
+    let auth = credentials(auth, &reg)?;
+    let mut http = http_options(&network)?;
+    http.retry = retry;
 
-    info!(registry_url = reg_url, "Pushing image list");
+    let platform_names: Vec<String> = platforms.iter().map(platform_name).collect();
+    info!(
+        platforms = platform_names.join(","),
+        seed = seed,
+        registry_url = reg_url,
+        "Pushing image list"
+    );
 
     let reference: Reference = format!("{reg}/{image}")
         .parse()
         .context("couldn't create a reference from {reg}/{image}")?;
-    match crate::tester::push_image_index(reference, auth, protocol).await {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            error!("{e}");
-            Ok(())
+
+    let mut config = if platforms.is_empty() {
+        BTreeMap::new()
+    } else {
+        push_config(&LoadOptions::default(), &push)
+    };
+    config.insert("registry_url", reg_url);
+    config.insert("image", reference.whole());
+    config.insert("platforms", platform_names.join(","));
+    retry_config(&mut config, &http.retry);
+
+    let timer = Timer::default();
+    let started_at = SystemTime::now();
+    let start = Instant::now();
+    let result = crate::tester::push_image_index(
+        reference, auth, protocol, &push, &platforms, &http, &timer,
+    )
+    .await;
+    let sample = Sample {
+        stage: None,
+        started: Duration::ZERO,
+        elapsed: start.elapsed(),
+        timings: timer.take(),
+        retries: timer.retries(),
+        result: result.map_err(LoadTestError::OciDistributionError),
+    };
+    let run = Run {
+        command: "push-image-list",
+        config,
+        stages: &[],
+        started_at,
+        elapsed: start.elapsed(),
+    };
+    conclude(&run, vec![sample], report.as_ref(), &thresholds)
+}
+
+/// Runs the distribution specification conformance checks against a registry.
+///
+/// # Errors
+///
+/// * If the registry url can't be parsed
+/// * If the credentials can't be loaded
+/// * If the certificates or the key can't be loaded
+/// * If authenticating against the registry fails
+/// * If the report can't be written
+/// * If any check fails
+pub async fn conformance(
+    reg_url: String,
+    auth: AuthOptions,
+    network: NetworkOptions,
+    repository: String,
+    report: Option<ReportOptions>,
+) -> Result<()> {
+    let (reg, protocol) = parse_reg(&reg_url, &network.plain_http_hosts)
+        .context("couldn't parse the reg url: {reg_url}")?;
+
+    let auth = credentials(auth, &reg)?;
+    let http = http_options(&network)?;
+
+    info!(registry_url = reg_url, repository, "Checking conformance");
+
+    let registry =
+        Registry::new(http.client()?, &protocol, &reg, auth).with_options(&http, &Timer::default());
+    let results = checks::run(registry, &repository).await?;
+
+    if let Some(report) = &report {
+        checks::write_report(report, &results)?;
+        info!(path = %report.path.display(), "Wrote the report");
+    }
+    println!("{}", checks::matrix(&results));
+
+    let failed = results
+        .iter()
+        .filter(|r| matches!(r.outcome, Outcome::Fail(_)))
+        .count();
+    if failed > 0 {
+        bail!("{failed} of {} conformance checks failed", results.len());
+    }
+    Ok(())
+}
+
+/// Runs a mock token service in front of the registry at `upstream`, until it fails.
+///
+/// # Errors
+///
+/// * If the upstream registry url can't be parsed
+/// * If the user and password are malformed
+/// * If the server can't listen on the address
+pub async fn token_server(
+    listen: SocketAddr,
+    upstream: String,
+    userpass: Option<String>,
+    identity_token: Option<String>,
+    expires_in: Duration,
+    latency: Duration,
+) -> Result<()> {
+    url::Url::parse(&upstream).with_context(|| format!("failed to parse the url: {upstream}"))?;
+    info!(
+        upstream,
+        expires_in = ?expires_in,
+        latency = ?latency,
+        "Starting the token server"
+    );
+    token_server::serve(TokenServerOptions {
+        listen,
+        upstream,
+        userpass: userpass.as_deref().map(parse_userpass).transpose()?,
+        identity_token,
+        expires_in,
+        latency,
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use hyper::{
+        server::conn::Http,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{Certificate, PrivateKey, ServerConfig},
+        TlsAcceptor,
+    };
+
+    use super::*;
+    use crate::metrics::Timing;
+
+    /// The self-signed certificate of `localhost` and its key.
+    const CERTIFICATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.crt");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.key");
+
+    /// Serves plain HTTP, answering every request with `status` and recording the URI it was sent
+    /// to. Proxied requests are sent to absolute URIs.
+    fn serve_recording(status: u16, requests: Arc<Mutex<Vec<String>>>) -> SocketAddr {
+        let make_service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    requests.lock().unwrap().push(req.uri().to_string());
+                    async move {
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[test]
+    fn parse_platform_reads_os_architecture_and_variant() {
+        let platform = parse_platform("linux/arm64/v8").unwrap();
+        assert_eq!(
+            (platform.os.as_str(), platform.architecture.as_str()),
+            ("linux", "arm64")
+        );
+        assert_eq!(platform.variant.as_deref(), Some("v8"));
+        assert_eq!(parse_platform(" linux/amd64 ").unwrap().variant, None);
+        assert_eq!(
+            platform_name(&parse_platform("windows/amd64").unwrap()),
+            "windows/amd64"
+        );
+    }
+
+    #[test]
+    fn parse_platform_rejects_malformed_platforms() {
+        for platform in [
+            "linux",
+            "linux/",
+            "/amd64",
+            "linux/arm/",
+            "linux/arm/v7/x",
+            "",
+        ] {
+            assert!(parse_platform(platform).is_err(), "{platform}");
         }
     }
+
+    #[test]
+    fn parse_userpass_splits_at_the_first_colon() {
+        assert_eq!(
+            parse_userpass("user:pass:word").unwrap(),
+            ("user".to_string(), "pass:word".to_string())
+        );
+        assert_eq!(
+            parse_userpass("user:").unwrap(),
+            ("user".to_string(), String::new())
+        );
+        assert!(parse_userpass("user").is_err());
+        assert!(parse_userpass(":password").is_err());
+    }
+
+    #[test]
+    fn totals_count_errors_by_category_most_frequent_first() {
+        let sample = |retries, code: Option<u16>| Sample {
+            stage: None,
+            started: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            timings: Vec::new(),
+            retries,
+            result: code.map_or(Ok(()), |code| {
+                Err(LoadTestError::OciDistributionError(
+                    oci_distribution::errors::OciDistributionError::ServerError {
+                        code,
+                        url: String::new(),
+                        message: String::new(),
+                    },
+                ))
+            }),
+        };
+        let mut totals = Totals::default();
+        for sample in [
+            sample(0, None),
+            sample(2, None),
+            sample(1, Some(401)),
+            sample(0, Some(503)),
+            sample(0, Some(503)),
+        ] {
+            totals.add(&sample);
+        }
+        assert_eq!(totals.retries, 3);
+        assert_eq!(
+            totals.to_string(),
+            "Total: 5, Success: 2 (40.0%), First attempt success: 1 (20.0%), \
+             Errors: 2 × 503, 1 × auth failure"
+        );
+        assert_eq!(
+            Totals::default().to_string(),
+            "Total: 0, Success: 0 (0.0%), First attempt success: 0 (0.0%)"
+        );
+    }
+
+    #[test]
+    fn mount_rates_count_successful_mounts_against_fallbacks() {
+        let timing = |operation, success| Timing {
+            operation,
+            elapsed: Duration::ZERO,
+            bytes: 0,
+            success,
+        };
+        let samples = vec![Sample {
+            stage: None,
+            started: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            timings: vec![
+                timing(Operation::BlobMount, true),
+                timing(Operation::BlobMount, true),
+                timing(Operation::BlobMount, true),
+                timing(Operation::BlobMount, false),
+                timing(Operation::BlobMountFallback, true),
+                timing(Operation::BlobUpload, true),
+            ],
+            retries: 0,
+            result: Ok(()),
+        }];
+        let mounted = count_timings(&samples, Operation::BlobMount);
+        let fallback = count_timings(&samples, Operation::BlobMountFallback);
+        assert_eq!((mounted, fallback), (3, 1));
+        assert!((mount_rate(mounted, fallback) - 75.0).abs() < f64::EPSILON);
+        assert!(mount_rate(0, 0).abs() < f64::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_the_proxy_unless_no_proxy_lists_their_host() {
+        std::env::set_var("NO_PROXY", "localhost");
+        let proxied = Arc::new(Mutex::new(Vec::new()));
+        let proxy = serve_recording(200, proxied.clone());
+        let direct = serve_recording(204, Arc::default());
+        let network = NetworkOptions {
+            proxy: Some(format!("http://{proxy}")),
+            ..NetworkOptions::default()
+        };
+        let http = http_options(&network).unwrap().client().unwrap();
+
+        let res = http
+            .get("http://registry.invalid/v2/")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        let res = http
+            .get(format!("http://localhost:{}/v2/", direct.port()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 204);
+        assert_eq!(*proxied.lock().unwrap(), ["http://registry.invalid/v2/"]);
+    }
+
+    #[test]
+    fn invalid_proxies_are_rejected() {
+        let network = NetworkOptions {
+            proxy: Some("http://[::1".to_string()),
+            ..NetworkOptions::default()
+        };
+        assert!(http_options(&network).is_err());
+    }
+
+    /// Serves HTTPS with the self-signed certificate of `localhost`, answering every request with
+    /// a 200.
+    async fn serve_self_signed() -> SocketAddr {
+        let certificates = rustls_pemfile::certs(&mut &*fs::read(CERTIFICATE).unwrap()).unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut &*fs::read(KEY).unwrap())
+            .unwrap()
+            .remove(0);
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                certificates.into_iter().map(Certificate).collect(),
+                PrivateKey(key),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(tls) = acceptor.accept(socket).await else {
+                        return;
+                    };
+                    let service =
+                        service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::empty())) });
+                    let _ = Http::new().serve_connection(tls, service).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn skipping_verification_accepts_self_signed_registries() {
+        let addr = serve_self_signed().await;
+        let url = format!("https://localhost:{}/v2/", addr.port());
+        let http = |insecure_skip_verify| {
+            let network = NetworkOptions {
+                insecure_skip_verify,
+                ..NetworkOptions::default()
+            };
+            http_options(&network).unwrap().client().unwrap()
+        };
+        assert!(http(false).get(&url).send().await.is_err());
+        let res = http(true).get(&url).send().await.unwrap();
+        assert_eq!(res.status(), 200);
+    }
+
+    #[test]
+    fn ca_bundles_without_certificates_are_rejected() {
+        let network = NetworkOptions {
+            ca_certs: vec![PathBuf::from(KEY)],
+            ..NetworkOptions::default()
+        };
+        let error = http_options(&network).unwrap_err();
+        assert!(error.to_string().starts_with("no CA certificates in"));
+
+        let network = NetworkOptions {
+            ca_certs: vec![PathBuf::from(CERTIFICATE)],
+            ..NetworkOptions::default()
+        };
+        assert!(http_options(&network).is_ok());
+    }
+
+    #[test]
+    fn client_certificates_need_their_key() {
+        let network = NetworkOptions {
+            client_cert: Some(PathBuf::from(CERTIFICATE)),
+            ..NetworkOptions::default()
+        };
+        let error = http_options(&network).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the client certificate and key must be given together"
+        );
+
+        let network = NetworkOptions {
+            client_cert: Some(PathBuf::from(CERTIFICATE)),
+            client_key: Some(PathBuf::from(KEY)),
+            ..NetworkOptions::default()
+        };
+        assert!(http_options(&network).is_ok());
+    }
 }
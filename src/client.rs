@@ -1,95 +1,848 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::Read,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
 use oci_distribution::{
-    client::{ClientConfig, ClientProtocol, Config, ImageLayer, PushResponse},
+    client::{ClientProtocol, Config, ImageLayer, PushResponse},
     errors::OciDistributionError,
-    manifest::{OciImageIndex, OciImageManifest},
-    secrets::RegistryAuth,
+    manifest::{
+        ImageIndexEntry, OciImageIndex, OciImageManifest, OciManifest, Platform,
+        OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+    },
     Reference,
 };
+use reqwest::Url;
 use tracing::instrument;
 
-use crate::image::Image;
+use crate::{
+    fake::{Layer, StreamedLayer},
+    image::Image,
+    metrics::{Operation, Timer, Timing},
+    registry::{scope, Credentials, HttpOptions, Mount, Registry, UploadMode},
+    tester::PushOptions,
+};
 
-#[instrument(level = "trace", err)]
-pub async fn pull_image(
-    protocol: ClientProtocol,
-    image: Reference,
-    auth: RegistryAuth,
-) -> Result<Image, OciDistributionError> {
-    let mut client = oci_distribution::client::Client::new(ClientConfig {
-        protocol,
-        platform_resolver: Some(Box::new(oci_distribution::client::linux_amd64_resolver)),
-        ..ClientConfig::default()
-    });
+/// The images of an image index to pull.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PullPlatform {
+    /// The image of a platform. Any variant matches a platform without one.
+    Only(Platform),
+    /// The images of every platform.
+    All,
+}
+
+impl Default for PullPlatform {
+    fn default() -> Self {
+        PullPlatform::Only(Platform {
+            architecture: "amd64".to_string(),
+            os: "linux".to_string(),
+            os_version: None,
+            os_features: None,
+            variant: None,
+            features: None,
+        })
+    }
+}
+
+impl Display for PullPlatform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PullPlatform::Only(platform) => write!(f, "{}", platform_name(platform)),
+            PullPlatform::All => write!(f, "all"),
+        }
+    }
+}
+
+/// Returns the `os/architecture[/variant]` name of a platform.
+pub(crate) fn platform_name(platform: &Platform) -> String {
+    match &platform.variant {
+        Some(variant) => format!("{}/{}/{variant}", platform.os, platform.architecture),
+        None => format!("{}/{}", platform.os, platform.architecture),
+    }
+}
+
+/// Returns whether an image of `platform` is an image of `wanted`.
+fn platform_matches(platform: &Platform, wanted: &Platform) -> bool {
+    platform.os == wanted.os
+        && platform.architecture == wanted.architecture
+        && (wanted.variant.is_none() || platform.variant == wanted.variant)
+}
+
+/// Whether the operations of a load test share connections and tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Connections {
+    /// Every operation opens its own connections and authenticates, like a new client would.
+    #[default]
+    Cold,
+    /// Operations share a pool of connections, and reuse the tokens of earlier operations on the
+    /// same repositories until they expire.
+    Warm,
+}
+
+impl Display for Connections {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Connections::Cold => write!(f, "cold"),
+            Connections::Warm => write!(f, "warm"),
+        }
+    }
+}
+
+/// A registry and the token scopes a client is authenticated for on it.
+type ScopedRegistry = (String, Vec<String>);
+
+/// A warm registry client, locked while it authenticates so that concurrent operations wait for
+/// its token instead of fetching their own.
+type WarmRegistry = Arc<tokio::sync::Mutex<Option<Registry>>>;
+
+/// The clients the operations of a load test reach the registry with. Clones share the
+/// connections and tokens of warm clients.
+#[derive(Debug, Clone, Default)]
+pub struct Clients {
+    connections: Connections,
+    options: HttpOptions,
+    /// The HTTP client of warm clients, built on first use.
+    http: Arc<tokio::sync::OnceCell<reqwest::Client>>,
+    /// The authenticated registry clients, by registry and token scopes.
+    registries: Arc<Mutex<HashMap<ScopedRegistry, WarmRegistry>>>,
+}
 
-    if auth != RegistryAuth::Anonymous {
-        client
-            .auth(&image, &auth, oci_distribution::RegistryOperation::Pull)
+impl Clients {
+    /// Creates the clients of a load test.
+    #[must_use]
+    pub fn new(connections: Connections, options: HttpOptions) -> Clients {
+        Clients {
+            connections,
+            options,
+            ..Clients::default()
+        }
+    }
+
+    /// Returns a client for `registry` authenticated for the token `scopes`. Only authenticates,
+    /// as a timed [`Operation::Auth`], when there is no warm client with a valid token.
+    async fn registry(
+        &self,
+        protocol: &ClientProtocol,
+        registry: &str,
+        auth: &Credentials,
+        scopes: Vec<String>,
+        timer: &Timer,
+    ) -> Result<Registry, OciDistributionError> {
+        if self.connections == Connections::Cold {
+            let mut client =
+                Registry::new(self.options.client()?, protocol, registry, auth.clone())
+                    .with_options(&self.options, timer);
+            timer
+                .time(Operation::Auth, 0, client.authenticate(&scopes))
+                .await?;
+            return Ok(client);
+        }
+
+        let warm = self
+            .registries
+            .lock()
+            .unwrap()
+            .entry((registry.to_string(), scopes.clone()))
+            .or_default()
+            .clone();
+        let mut warm = warm.lock().await;
+        if let Some(client) = warm.as_ref().filter(|client| !client.is_expired()) {
+            return Ok(client.clone().with_options(&self.options, timer));
+        }
+        let http = self
+            .http
+            .get_or_try_init(|| async { self.options.client() })
+            .await?;
+        let mut client = Registry::new(http.clone(), protocol, registry, auth.clone())
+            .with_options(&self.options, timer);
+        timer
+            .time(Operation::Auth, 0, client.authenticate(&scopes))
             .await?;
+        *warm = Some(client.clone());
+        Ok(client)
     }
+}
 
-    let image = client
-        .pull(
-            &image,
+/// Pulls an image, or the images of an index `platform` asks for.
+#[instrument(level = "trace", skip(clients, timer))]
+pub async fn pull_image(
+    clients: &Clients,
+    protocol: ClientProtocol,
+    image: Reference,
+    auth: Credentials,
+    platform: &PullPlatform,
+    timer: &Timer,
+) -> Result<Vec<Image>, OciDistributionError> {
+    let repository = image.repository();
+    let registry = clients
+        .registry(
+            &protocol,
+            image.resolve_registry(),
             &auth,
-            vec![
-                oci_distribution::manifest::IMAGE_LAYER_MEDIA_TYPE,
-                oci_distribution::manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE,
-                oci_distribution::manifest::IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE,
-                oci_distribution::manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
-            ],
+            vec![scope(repository, "pull")],
+            timer,
         )
         .await?;
 
+    let reference = image.digest().or(image.tag()).unwrap_or("latest");
+    let (manifest, digest) = timer
+        .time(
+            Operation::ManifestGet,
+            0,
+            registry.get_manifest(repository, reference),
+        )
+        .await?;
+    let index = match manifest {
+        OciManifest::Image(manifest) => {
+            return Ok(vec![
+                pull_image_blobs(&registry, repository, manifest, digest, timer).await?,
+            ]);
+        }
+        OciManifest::ImageIndex(index) => index,
+    };
+
+    let entries: Vec<ImageIndexEntry> = match platform {
+        PullPlatform::Only(wanted) => {
+            let entry = index
+                .manifests
+                .into_iter()
+                .find(|entry| {
+                    entry
+                        .platform
+                        .as_ref()
+                        .is_some_and(|platform| platform_matches(platform, wanted))
+                })
+                .ok_or_else(|| {
+                    OciDistributionError::ImageManifestNotFoundError(format!(
+                        "no image of {} in {}",
+                        platform_name(wanted),
+                        image.whole()
+                    ))
+                })?;
+            vec![entry]
+        }
+        PullPlatform::All => index.manifests,
+    };
+    let mut images = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let (manifest, digest) = timer
+            .time(
+                Operation::ManifestGet,
+                0,
+                registry.get_manifest(repository, &entry.digest),
+            )
+            .await?;
+        let OciManifest::Image(manifest) = manifest else {
+            return Err(OciDistributionError::ManifestParsingError(format!(
+                "{} is an index nested in {}",
+                entry.digest,
+                image.whole()
+            )));
+        };
+        images.push(pull_image_blobs(&registry, repository, manifest, digest, timer).await?);
+    }
+    Ok(images)
+}
+
+/// Pulls the config and the layers of the image of `manifest`.
+async fn pull_image_blobs(
+    registry: &Registry,
+    repository: &str,
+    manifest: OciImageManifest,
+    digest: String,
+    timer: &Timer,
+) -> Result<Image, OciDistributionError> {
+    let config = pull_blob(registry, repository, &manifest.config.digest, timer).await?;
+    let mut layers = Vec::with_capacity(manifest.layers.len());
+    for layer in &manifest.layers {
+        let data = pull_blob(registry, repository, &layer.digest, timer).await?;
+        layers.push(ImageLayer::new(
+            data,
+            layer.media_type.clone(),
+            layer.annotations.clone(),
+        ));
+    }
+
     Ok(Image {
-        manifest: image.manifest,
-        config: image.config,
-        layers: image.layers,
-        digest: image.digest,
+        config: Config::new(config, manifest.config.media_type.clone(), None),
+        manifest: Some(manifest),
+        layers,
+        digest: Some(digest),
     })
 }
 
-#[instrument(level = "trace", skip(layers, config), err)]
+async fn pull_blob(
+    registry: &Registry,
+    repository: &str,
+    digest: &str,
+    timer: &Timer,
+) -> Result<Vec<u8>, OciDistributionError> {
+    let start = Instant::now();
+    let result = registry.get_blob(repository, digest).await;
+    timer.record(Timing {
+        operation: Operation::BlobGet,
+        elapsed: start.elapsed(),
+        bytes: result.as_ref().map_or(0, |data| data.len() as u64),
+        success: result.is_ok(),
+    });
+    result
+}
+
+/// Blobs to mount from another repository instead of uploading them.
+#[derive(Debug, Clone)]
+pub struct MountFrom {
+    /// The repository the blobs are mounted from.
+    pub repository: String,
+    /// The digests of the blobs to mount.
+    pub digests: HashSet<String>,
+}
+
+/// The content of a blob to push.
+enum Blob {
+    /// Content held in memory.
+    Memory(Vec<u8>),
+    /// Content generated as it's uploaded.
+    Streamed(StreamedLayer),
+}
+
+impl Blob {
+    fn len(&self) -> u64 {
+        match self {
+            Blob::Memory(data) => data.len() as u64,
+            Blob::Streamed(layer) => layer.len(),
+        }
+    }
+}
+
+impl From<Layer> for Blob {
+    fn from(layer: Layer) -> Self {
+        match layer {
+            Layer::Memory { layer, .. } => Blob::Memory(layer.data),
+            Layer::Streamed(layer) => Blob::Streamed(layer),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[instrument(level = "trace", skip(clients, layers, config, mount, timer))]
 pub async fn push_image(
-    layers: Vec<ImageLayer>,
+    clients: &Clients,
+    layers: Vec<Layer>,
     config: Config,
     image: Reference,
-    manifest: Option<OciImageManifest>,
-    auth: &RegistryAuth,
+    manifest: OciImageManifest,
+    auth: &Credentials,
     protocol: ClientProtocol,
+    options: &PushOptions,
+    mount: Option<&MountFrom>,
+    timer: &Timer,
 ) -> Result<PushResponse, OciDistributionError> {
-    let mut client = oci_distribution::client::Client::new(ClientConfig {
-        protocol,
-        platform_resolver: Some(Box::new(oci_distribution::client::linux_amd64_resolver)),
-        ..ClientConfig::default()
-    });
+    let repository = image.repository();
+    let mut scopes = vec![scope(repository, "pull,push")];
+    if let Some(mount) = mount {
+        scopes.push(scope(&mount.repository, "pull"));
+    }
+    let registry = clients
+        .registry(&protocol, image.resolve_registry(), auth, scopes, timer)
+        .await?;
 
-    if *auth != RegistryAuth::Anonymous {
-        client
-            .auth(&image, auth, oci_distribution::RegistryOperation::Push)
-            .await?;
+    for layer in layers {
+        let digest = layer.digest();
+        push_blob(
+            &registry,
+            repository,
+            &digest,
+            layer.into(),
+            options,
+            mount,
+            timer,
+        )
+        .await?;
     }
+    let config_url = push_blob(
+        &registry,
+        repository,
+        &manifest.config.digest,
+        Blob::Memory(config.data),
+        options,
+        mount,
+        timer,
+    )
+    .await?;
 
-    client.push(&image, layers, config, auth, manifest).await
+    let media_type = manifest
+        .media_type
+        .clone()
+        .unwrap_or_else(|| OCI_IMAGE_MEDIA_TYPE.to_string());
+    let body = serde_json::to_vec(&manifest)?;
+    let size = body.len() as u64;
+    let manifest_url = timer
+        .time(
+            Operation::ManifestPut,
+            size,
+            registry.put_manifest(
+                repository,
+                image.tag().or(image.digest()).unwrap_or("latest"),
+                &media_type,
+                body,
+            ),
+        )
+        .await?;
+
+    Ok(PushResponse {
+        config_url,
+        manifest_url,
+    })
 }
 
-#[instrument(level = "trace", err)]
+/// Pushes a blob and returns its location. Skips blobs the registry already has when `options`
+/// asks to check for them, and mounts blobs `mount` lists instead of uploading them.
+async fn push_blob(
+    registry: &Registry,
+    repository: &str,
+    digest: &str,
+    blob: Blob,
+    options: &PushOptions,
+    mount: Option<&MountFrom>,
+    timer: &Timer,
+) -> Result<String, OciDistributionError> {
+    if options.check_exists {
+        let start = Instant::now();
+        let result = registry.blob_exists(repository, digest).await;
+        let operation = match result {
+            Ok(false) => Operation::BlobMissing,
+            _ => Operation::BlobExists,
+        };
+        timer.record(Timing {
+            operation,
+            elapsed: start.elapsed(),
+            bytes: 0,
+            success: result.is_ok(),
+        });
+        if result? {
+            return Ok(format!("/v2/{repository}/blobs/{digest}"));
+        }
+    }
+
+    let mut upload = None;
+    if let Some(mount) = mount.filter(|m| m.digests.contains(digest)) {
+        let start = Instant::now();
+        let result = registry
+            .mount_blob(repository, digest, &mount.repository)
+            .await;
+        let operation = match result {
+            Ok(Mount::Upload(_)) => Operation::BlobMountFallback,
+            _ => Operation::BlobMount,
+        };
+        timer.record(Timing {
+            operation,
+            elapsed: start.elapsed(),
+            bytes: 0,
+            success: result.is_ok(),
+        });
+        match result? {
+            Mount::Mounted(location) => return Ok(location),
+            Mount::Upload(location) => upload = Some(location),
+        }
+    }
+
+    timer
+        .time(
+            Operation::BlobUpload,
+            blob.len(),
+            upload_blob(registry, repository, digest, blob, upload, options, timer),
+        )
+        .await
+}
+
+/// Uploads a blob the way `options` asks for, timing every chunk of a chunked upload. Starts a new
+/// upload unless `upload` is the location of one the registry already started. Streamed blobs are
+/// always uploaded in chunks, so that only a couple of chunks are ever held in memory.
+async fn upload_blob(
+    registry: &Registry,
+    repository: &str,
+    digest: &str,
+    blob: Blob,
+    upload: Option<Url>,
+    options: &PushOptions,
+    timer: &Timer,
+) -> Result<String, OciDistributionError> {
+    let mut upload = match upload {
+        Some(upload) => upload,
+        None => registry.start_upload(repository).await?,
+    };
+    match (blob, options.upload_mode) {
+        (Blob::Memory(data), UploadMode::Monolithic) => {
+            registry.finish_upload(upload, digest, data).await
+        }
+        (Blob::Memory(data), UploadMode::Chunked) => {
+            let mut offset = 0;
+            for chunk in data.chunks(options.chunk_size) {
+                upload = timer
+                    .time(
+                        Operation::BlobChunkUpload,
+                        chunk.len() as u64,
+                        registry.upload_chunk(upload, offset, chunk.to_vec()),
+                    )
+                    .await?;
+                offset += chunk.len();
+            }
+            registry.finish_upload(upload, digest, Vec::new()).await
+        }
+        (Blob::Streamed(layer), _) => {
+            // Generating the content is CPU bound, so it's read on the blocking thread pool, a
+            // chunk ahead of the upload. The reading stops when the upload fails.
+            let (tx, mut chunks) = tokio::sync::mpsc::channel(1);
+            let chunk_size = options.chunk_size;
+            tokio::task::spawn_blocking(move || {
+                let mut reader = layer.reader();
+                loop {
+                    let mut chunk = Vec::with_capacity(chunk_size);
+                    let chunk = (&mut reader)
+                        .take(chunk_size as u64)
+                        .read_to_end(&mut chunk)
+                        .map(|_| chunk);
+                    if matches!(&chunk, Ok(chunk) if chunk.is_empty()) {
+                        break;
+                    }
+                    let failed = chunk.is_err();
+                    if tx.blocking_send(chunk).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+            let mut offset = 0;
+            while let Some(chunk) = chunks.recv().await {
+                let chunk = chunk?;
+                let len = chunk.len();
+                upload = timer
+                    .time(
+                        Operation::BlobChunkUpload,
+                        len as u64,
+                        registry.upload_chunk(upload, offset, chunk),
+                    )
+                    .await?;
+                offset += len;
+            }
+            registry.finish_upload(upload, digest, Vec::new()).await
+        }
+    }
+}
+
+#[instrument(level = "trace", skip(http, timer))]
 pub async fn push_image_list(
     reference: Reference,
     manifest: OciImageIndex,
-    auth: &RegistryAuth,
+    auth: &Credentials,
     protocol: ClientProtocol,
+    http: &HttpOptions,
+    timer: &Timer,
 ) -> Result<String, OciDistributionError> {
-    let mut client = oci_distribution::client::Client::new(ClientConfig {
-        protocol,
-        platform_resolver: Some(Box::new(oci_distribution::client::linux_amd64_resolver)),
-        ..ClientConfig::default()
-    });
+    let mut registry = Registry::new(
+        http.client()?,
+        &protocol,
+        reference.resolve_registry(),
+        auth.clone(),
+    )
+    .with_options(http, timer);
+    let repository = reference.repository();
+    registry
+        .authenticate(&[scope(repository, "pull,push")])
+        .await?;
 
-    if *auth != RegistryAuth::Anonymous {
-        client
-            .auth(&reference, auth, oci_distribution::RegistryOperation::Push)
-            .await?;
+    let media_type = manifest
+        .media_type
+        .clone()
+        .unwrap_or_else(|| OCI_IMAGE_INDEX_MEDIA_TYPE.to_string());
+    let body = serde_json::to_vec(&manifest)?;
+    registry
+        .put_manifest(
+            repository,
+            reference.tag().or(reference.digest()).unwrap_or("latest"),
+            &media_type,
+            body,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use hyper::{
+        header,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+
+    use super::*;
+    use crate::cli::parse_platform;
+
+    /// Serves a registry which doesn't require authentication, counting the requests it gets.
+    fn serve_registry(requests: Arc<AtomicUsize>) -> SocketAddr {
+        let make_service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
     }
 
-    client.push_manifest_list(&reference, auth, manifest).await
+    /// Serves a registry which doesn't require authentication, answering requests with the status
+    /// `respond` returns for their method and path and query. Records the requests it gets.
+    fn serve_recording(
+        respond: fn(&str, &str) -> StatusCode,
+        requests: Arc<Mutex<Vec<String>>>,
+    ) -> SocketAddr {
+        let make_service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let path = req.uri().path_and_query().map(ToString::to_string);
+                    let path = path.unwrap_or_default();
+                    let status = respond(req.method().as_str(), &path);
+                    requests
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} {path}", req.method()));
+                    async move {
+                        let response = Response::builder()
+                            .status(status)
+                            .header(header::LOCATION, "/v2/test/image/blobs/uploads/1")
+                            .body(Body::empty())
+                            .unwrap();
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Pushes a blob to a registry answering with `respond`, with `options` and mounting from
+    /// `mount`. Returns the requests the registry got and the operations timed.
+    async fn push_recorded_blob(
+        respond: fn(&str, &str) -> StatusCode,
+        options: &PushOptions,
+        mount: Option<&MountFrom>,
+    ) -> (Vec<String>, Vec<Operation>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let registry = serve_recording(respond, requests.clone()).to_string();
+        let clients = Clients::new(Connections::Cold, HttpOptions::default());
+        let timer = Timer::default();
+        let registry = clients
+            .registry(
+                &ClientProtocol::Http,
+                &registry,
+                &Credentials::Anonymous,
+                vec![scope("test/image", "pull,push")],
+                &timer,
+            )
+            .await
+            .unwrap();
+        push_blob(
+            &registry,
+            "test/image",
+            "sha256:0",
+            Blob::Memory(vec![0; 16]),
+            options,
+            mount,
+            &timer,
+        )
+        .await
+        .unwrap();
+        let requests = requests.lock().unwrap().clone();
+        // The first request is the authentication.
+        let requests = requests.into_iter().skip(1).collect();
+        (requests, timer.take().iter().map(|t| t.operation).collect())
+    }
+
+    #[tokio::test]
+    async fn existing_blobs_are_not_uploaded() {
+        let options = PushOptions {
+            check_exists: true,
+            ..PushOptions::default()
+        };
+        let (requests, operations) =
+            push_recorded_blob(|_, _| StatusCode::OK, &options, None).await;
+        assert_eq!(requests, ["HEAD /v2/test/image/blobs/sha256:0"]);
+        assert_eq!(operations, [Operation::Auth, Operation::BlobExists]);
+    }
+
+    #[tokio::test]
+    async fn missing_blobs_are_uploaded() {
+        let respond = |method: &str, _: &str| match method {
+            "HEAD" => StatusCode::NOT_FOUND,
+            "POST" => StatusCode::ACCEPTED,
+            _ => StatusCode::CREATED,
+        };
+        let options = PushOptions {
+            check_exists: true,
+            ..PushOptions::default()
+        };
+        let (requests, operations) = push_recorded_blob(respond, &options, None).await;
+        assert_eq!(
+            requests,
+            [
+                "HEAD /v2/test/image/blobs/sha256:0",
+                "POST /v2/test/image/blobs/uploads/",
+                "PUT /v2/test/image/blobs/uploads/1?digest=sha256%3A0",
+            ]
+        );
+        assert_eq!(
+            operations,
+            [
+                Operation::Auth,
+                Operation::BlobMissing,
+                Operation::BlobUpload
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn mounted_blobs_are_not_uploaded() {
+        let mount = MountFrom {
+            repository: "test/base".to_string(),
+            digests: HashSet::from(["sha256:0".to_string()]),
+        };
+        let (requests, operations) = push_recorded_blob(
+            |_, _| StatusCode::CREATED,
+            &PushOptions::default(),
+            Some(&mount),
+        )
+        .await;
+        assert_eq!(
+            requests,
+            ["POST /v2/test/image/blobs/uploads/?mount=sha256%3A0&from=test%2Fbase"]
+        );
+        assert_eq!(operations, [Operation::Auth, Operation::BlobMount]);
+    }
+
+    #[tokio::test]
+    async fn refused_mounts_fall_back_to_the_upload_started() {
+        let respond = |method: &str, _: &str| match method {
+            "POST" => StatusCode::ACCEPTED,
+            _ => StatusCode::CREATED,
+        };
+        let mount = MountFrom {
+            repository: "test/base".to_string(),
+            digests: HashSet::from(["sha256:0".to_string()]),
+        };
+        let (requests, operations) =
+            push_recorded_blob(respond, &PushOptions::default(), Some(&mount)).await;
+        assert_eq!(
+            requests,
+            [
+                "POST /v2/test/image/blobs/uploads/?mount=sha256%3A0&from=test%2Fbase",
+                "PUT /v2/test/image/blobs/uploads/1?digest=sha256%3A0",
+            ]
+        );
+        assert_eq!(
+            operations,
+            [
+                Operation::Auth,
+                Operation::BlobMountFallback,
+                Operation::BlobUpload
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn blobs_of_other_images_are_not_mounted() {
+        let respond = |method: &str, _: &str| match method {
+            "POST" => StatusCode::ACCEPTED,
+            _ => StatusCode::CREATED,
+        };
+        let mount = MountFrom {
+            repository: "test/base".to_string(),
+            digests: HashSet::from(["sha256:1".to_string()]),
+        };
+        let (requests, _) =
+            push_recorded_blob(respond, &PushOptions::default(), Some(&mount)).await;
+        assert_eq!(requests[0], "POST /v2/test/image/blobs/uploads/");
+    }
+
+    /// Returns how many requests two operations on the same repository send to authenticate.
+    async fn auth_requests(connections: Connections) -> usize {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let registry = serve_registry(requests.clone()).to_string();
+        let clients = Clients::new(connections, HttpOptions::default());
+        for _ in 0..2 {
+            let timer = Timer::default();
+            clients
+                .registry(
+                    &ClientProtocol::Http,
+                    &registry,
+                    &Credentials::Anonymous,
+                    vec![scope("test/image", "pull")],
+                    &timer,
+                )
+                .await
+                .unwrap();
+        }
+        requests.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn cold_clients_authenticate_every_operation() {
+        assert_eq!(auth_requests(Connections::Cold).await, 2);
+    }
+
+    #[tokio::test]
+    async fn warm_clients_reuse_their_authentication() {
+        assert_eq!(auth_requests(Connections::Warm).await, 1);
+    }
+
+    #[test]
+    fn platforms_match_any_variant_unless_one_is_wanted() {
+        let platform = |name| parse_platform(name).unwrap();
+        assert!(platform_matches(
+            &platform("linux/arm64/v8"),
+            &platform("linux/arm64")
+        ));
+        assert!(platform_matches(
+            &platform("linux/arm64/v8"),
+            &platform("linux/arm64/v8")
+        ));
+        assert!(!platform_matches(
+            &platform("linux/arm/v6"),
+            &platform("linux/arm/v7")
+        ));
+        assert!(!platform_matches(
+            &platform("linux/arm64"),
+            &platform("linux/arm64/v8")
+        ));
+        assert!(!platform_matches(
+            &platform("windows/amd64"),
+            &platform("linux/amd64")
+        ));
+    }
+
+    #[test]
+    fn pull_platforms_are_displayed_by_name() {
+        assert_eq!(PullPlatform::default().to_string(), "linux/amd64");
+        assert_eq!(PullPlatform::All.to_string(), "all");
+        assert_eq!(
+            PullPlatform::Only(parse_platform("linux/arm/v7").unwrap()).to_string(),
+            "linux/arm/v7"
+        );
+    }
 }
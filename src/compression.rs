@@ -0,0 +1,324 @@
+//! The compression of generated layers.
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{bail, Context};
+use oci_distribution::manifest::{IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE};
+use rand::Rng;
+
+/// The media type of zstd compressed layers, which `oci_distribution` doesn't define.
+pub const IMAGE_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+
+/// How a layer is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// An uncompressed tarball.
+    None,
+    /// A gzip compressed tarball.
+    Gzip,
+    /// A zstd compressed tarball.
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the media type of layers compressed this way.
+    #[must_use]
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Compression::None => IMAGE_LAYER_MEDIA_TYPE,
+            Compression::Gzip => IMAGE_LAYER_GZIP_MEDIA_TYPE,
+            Compression::Zstd => IMAGE_LAYER_ZSTD_MEDIA_TYPE,
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(compression: &str) -> Result<Self, Self::Err> {
+        match compression.trim() {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => bail!("unknown compression {compression}, expected none, gzip or zstd"),
+        }
+    }
+}
+
+/// A weighted mix of compressions, parsed from `COMPRESSION[=WEIGHT],...`, where every layer is
+/// compressed a way picked with a probability proportional to its weight. The weight is 1 when
+/// omitted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionMix {
+    /// Every compression of the mix and its weight.
+    pub weights: Vec<(Compression, f64)>,
+}
+
+impl CompressionMix {
+    /// Picks a compression from the mix.
+    pub fn sample(&self, rng: &mut impl Rng) -> Compression {
+        let total: f64 = self.weights.iter().map(|(_, weight)| weight).sum();
+        let mut pick = rng.gen_range(0.0..total);
+        for (compression, weight) in &self.weights {
+            if pick < *weight {
+                return *compression;
+            }
+            pick -= weight;
+        }
+        self.weights
+            .last()
+            .map_or(Compression::None, |(compression, _)| *compression)
+    }
+}
+
+/// Half of the layers are uncompressed and half are gzip compressed.
+impl Default for CompressionMix {
+    fn default() -> Self {
+        CompressionMix {
+            weights: vec![(Compression::None, 1.0), (Compression::Gzip, 1.0)],
+        }
+    }
+}
+
+impl From<Compression> for CompressionMix {
+    fn from(compression: Compression) -> Self {
+        CompressionMix {
+            weights: vec![(compression, 1.0)],
+        }
+    }
+}
+
+impl Display for CompressionMix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (compression, weight)) in self.weights.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{compression}={weight}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CompressionMix {
+    type Err = anyhow::Error;
+
+    fn from_str(mix: &str) -> Result<Self, Self::Err> {
+        let mut weights: Vec<(Compression, f64)> = Vec::new();
+        for part in mix.split(',') {
+            let (compression, weight) = part.split_once('=').unwrap_or((part, "1"));
+            let compression: Compression = compression.parse()?;
+            let weight: f64 = weight
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid weight of {compression}: {mix}"))?;
+            if !weight.is_finite() || weight < 0.0 {
+                bail!("the weight of {compression} must be a positive number: {mix}");
+            }
+            if weights.iter().any(|(c, _)| *c == compression) {
+                bail!("{compression} is repeated: {mix}");
+            }
+            weights.push((compression, weight));
+        }
+        if weights.iter().map(|(_, weight)| weight).sum::<f64>() <= 0.0 {
+            bail!("the compression mix has no weight: {mix}");
+        }
+        Ok(CompressionMix { weights })
+    }
+}
+
+/// How well the content of generated files compresses, parsed from `random`, `binary`, `text` or
+/// a RATIO of at least 1, the size of the content divided by its compressed size.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Compressibility {
+    /// Random bytes, which don't compress, like already compressed content.
+    #[default]
+    Random,
+    /// Content which compresses about 2:1, like executables and libraries.
+    Binary,
+    /// Content which compresses about 4:1, like source code and text.
+    Text,
+    /// Content which compresses about RATIO:1.
+    Ratio(f64),
+}
+
+/// The size of the segments [`Compressibility::fill`] fills at once, well within the 32 KiB window
+/// of gzip.
+const SEGMENT_SIZE: usize = 4096;
+
+impl Compressibility {
+    /// Returns the size of the content divided by its compressed size.
+    #[must_use]
+    pub fn ratio(self) -> f64 {
+        match self {
+            Compressibility::Random => 1.0,
+            Compressibility::Binary => 2.0,
+            Compressibility::Text => 4.0,
+            Compressibility::Ratio(ratio) => ratio,
+        }
+    }
+
+    /// Fills `data` with content which compresses about as well as asked for. Every segment of the
+    /// content starts with random bytes, repeated up to the end of the segment, so that
+    /// compressors only have to store the random bytes.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn fill(self, rng: &mut impl Rng, data: &mut [u8]) {
+        let ratio = self.ratio();
+        if ratio <= 1.0 {
+            rng.fill_bytes(data);
+            return;
+        }
+        for segment in data.chunks_mut(SEGMENT_SIZE) {
+            let random = ((segment.len() as f64 / ratio).ceil() as usize).clamp(1, segment.len());
+            rng.fill_bytes(&mut segment[..random]);
+            for i in random..segment.len() {
+                segment[i] = segment[i % random];
+            }
+        }
+    }
+}
+
+impl Display for Compressibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compressibility::Random => write!(f, "random"),
+            Compressibility::Binary => write!(f, "binary"),
+            Compressibility::Text => write!(f, "text"),
+            Compressibility::Ratio(ratio) => write!(f, "{ratio}"),
+        }
+    }
+}
+
+impl FromStr for Compressibility {
+    type Err = anyhow::Error;
+
+    fn from_str(compressibility: &str) -> Result<Self, Self::Err> {
+        match compressibility.trim() {
+            "random" => Ok(Compressibility::Random),
+            "binary" => Ok(Compressibility::Binary),
+            "text" => Ok(Compressibility::Text),
+            ratio => {
+                let ratio: f64 = ratio.parse().with_context(|| {
+                    format!("expected random, binary, text or a ratio: {compressibility}")
+                })?;
+                if !ratio.is_finite() || ratio < 1.0 {
+                    bail!("the ratio must be a number of at least 1: {compressibility}");
+                }
+                Ok(Compressibility::Ratio(ratio))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn compression_mix_from_str() {
+        assert_eq!(
+            "zstd".parse::<CompressionMix>().unwrap(),
+            CompressionMix::from(Compression::Zstd)
+        );
+        assert_eq!(
+            "none,gzip".parse::<CompressionMix>().unwrap(),
+            CompressionMix::default()
+        );
+        assert_eq!(
+            "gzip=3, zstd=1".parse::<CompressionMix>().unwrap().weights,
+            [(Compression::Gzip, 3.0), (Compression::Zstd, 1.0)]
+        );
+        assert_eq!(
+            "none=0,zstd".parse::<CompressionMix>().unwrap().weights,
+            [(Compression::None, 0.0), (Compression::Zstd, 1.0)]
+        );
+    }
+
+    #[test]
+    fn compression_mix_from_str_rejects_malformed_mixes() {
+        assert!("brotli".parse::<CompressionMix>().is_err());
+        assert!("gzip=x".parse::<CompressionMix>().is_err());
+        assert!("gzip=-1".parse::<CompressionMix>().is_err());
+        assert!("gzip,gzip=2".parse::<CompressionMix>().is_err());
+        assert!("gzip=0,zstd=0".parse::<CompressionMix>().is_err());
+        assert!("".parse::<CompressionMix>().is_err());
+    }
+
+    #[test]
+    fn compression_mix_samples_in_proportion_to_the_weights() {
+        let mix: CompressionMix = "none=0,gzip=3,zstd=1".parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut counts = [0; 3];
+        for _ in 0..4000 {
+            counts[mix.sample(&mut rng) as usize] += 1;
+        }
+        assert_eq!(counts[Compression::None as usize], 0);
+        assert!((2800..3200).contains(&counts[Compression::Gzip as usize]));
+        assert!((800..1200).contains(&counts[Compression::Zstd as usize]));
+    }
+
+    #[test]
+    fn compressibility_from_str() {
+        assert_eq!(
+            "random".parse::<Compressibility>().unwrap(),
+            Compressibility::Random
+        );
+        assert_eq!(
+            "binary".parse::<Compressibility>().unwrap(),
+            Compressibility::Binary
+        );
+        assert_eq!(
+            " text ".parse::<Compressibility>().unwrap(),
+            Compressibility::Text
+        );
+        assert_eq!(
+            "3".parse::<Compressibility>().unwrap(),
+            Compressibility::Ratio(3.0)
+        );
+        assert_eq!(
+            "1".parse::<Compressibility>().unwrap(),
+            Compressibility::Ratio(1.0)
+        );
+        assert!("0.5".parse::<Compressibility>().is_err());
+        assert!("inf".parse::<Compressibility>().is_err());
+        assert!("NaN".parse::<Compressibility>().is_err());
+        assert!("json".parse::<Compressibility>().is_err());
+    }
+
+    #[test]
+    fn content_compresses_about_as_asked() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for compressibility in [
+            Compressibility::Random,
+            Compressibility::Binary,
+            Compressibility::Text,
+            Compressibility::Ratio(8.0),
+        ] {
+            let mut data = vec![0; 1024 * 1024];
+            compressibility.fill(&mut rng, &mut data);
+            let compressed = zstd::encode_all(&data[..], 0).unwrap();
+            #[allow(clippy::cast_precision_loss)]
+            let ratio = data.len() as f64 / compressed.len() as f64;
+            let expected = compressibility.ratio();
+            assert!(
+                ratio > expected * 0.9 && ratio < expected * 1.1,
+                "{compressibility}: {ratio}"
+            );
+        }
+    }
+}
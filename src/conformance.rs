@@ -0,0 +1,883 @@
+//! Checks that a registry behaves as the OCI distribution specification requires.
+use std::{fmt::Display, fmt::Write as _};
+
+use anyhow::Context;
+use oci_distribution::manifest::OCI_IMAGE_MEDIA_TYPE;
+use reqwest::{header, Method, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, info};
+
+use crate::{
+    compression::{Compressibility, CompressionMix},
+    distribution::Distribution,
+    fake::{self, LayerOptions, Tree, MEGABYTE},
+    registry::{oci_error_code, scope, Registry},
+    report::{csv_field, xml_escape, ReportFormat, ReportOptions},
+};
+
+/// The header registries return the digest of content in.
+const DOCKER_CONTENT_DIGEST: &str = "Docker-Content-Digest";
+
+/// A category of the distribution specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Pull,
+    Push,
+    ContentDiscovery,
+    ContentManagement,
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Category::Pull => write!(f, "pull"),
+            Category::Push => write!(f, "push"),
+            Category::ContentDiscovery => write!(f, "content-discovery"),
+            Category::ContentManagement => write!(f, "content-management"),
+        }
+    }
+}
+
+/// The outcome of a single conformance check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// The check couldn't run, because the registry doesn't support an optional part of the
+    /// specification or a check it depends on failed.
+    Skip(String),
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail(_) => write!(f, "fail"),
+            Outcome::Skip(_) => write!(f, "skip"),
+        }
+    }
+}
+
+/// The result of a single conformance check.
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub category: Category,
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+type CheckOutcome = Result<(), Outcome>;
+
+fn fail(reason: impl Into<String>) -> Outcome {
+    Outcome::Fail(reason.into())
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+/// Sends a request, turning connection errors into failures.
+async fn send(req: reqwest::RequestBuilder) -> Result<Response, Outcome> {
+    req.send()
+        .await
+        .map_err(|e| fail(format!("request failed: {e}")))
+}
+
+fn expect_status(res: &Response, expected: &[StatusCode]) -> CheckOutcome {
+    if expected.contains(&res.status()) {
+        Ok(())
+    } else {
+        let expected: Vec<String> = expected.iter().map(|s| s.as_u16().to_string()).collect();
+        Err(fail(format!(
+            "expected status {} but got {} from {} {}",
+            expected.join(" or "),
+            res.status().as_u16(),
+            res.url().path(),
+            res.url().query().unwrap_or_default(),
+        )))
+    }
+}
+
+fn expect_header<'a>(res: &'a Response, name: &str) -> Result<&'a str, Outcome> {
+    res.headers()
+        .get(name)
+        .ok_or_else(|| fail(format!("missing {name} header")))?
+        .to_str()
+        .map_err(|e| fail(format!("invalid {name} header: {e}")))
+}
+
+fn expect_digest_header(res: &Response, digest: &str) -> CheckOutcome {
+    let actual = expect_header(res, DOCKER_CONTENT_DIGEST)?;
+    if actual == digest {
+        Ok(())
+    } else {
+        Err(fail(format!(
+            "expected {DOCKER_CONTENT_DIGEST} {digest} but got {actual}"
+        )))
+    }
+}
+
+/// Checks the response is an error with the given OCI error code in its body.
+async fn expect_error(res: Response, status: StatusCode, code: &str) -> CheckOutcome {
+    expect_status(&res, &[status])?;
+    let body = res
+        .text()
+        .await
+        .map_err(|e| fail(format!("failed to read the error body: {e}")))?;
+    let envelope: serde_json::Value = serde_json::from_str(&body)
+        .map_err(|e| fail(format!("error body is not JSON ({e}): {body}")))?;
+    let codes: Vec<&str> = envelope
+        .get("errors")
+        .and_then(serde_json::Value::as_array)
+        .map(|errors| {
+            errors
+                .iter()
+                .filter_map(|e| e.get("code").and_then(serde_json::Value::as_str))
+                .collect()
+        })
+        .unwrap_or_default();
+    if codes.contains(&code) {
+        Ok(())
+    } else {
+        Err(fail(format!(
+            "expected error code {code} but got {codes:?}"
+        )))
+    }
+}
+
+/// The content pushed by the checks, and whether it made it into the registry.
+struct Conformance {
+    registry: Registry,
+    repository: String,
+    results: Vec<CheckResult>,
+
+    layer: Vec<u8>,
+    layer_digest: String,
+    config: Vec<u8>,
+    config_digest: String,
+    manifest: Vec<u8>,
+    manifest_digest: String,
+
+    layer_pushed: bool,
+    config_pushed: bool,
+    manifest_pushed: bool,
+}
+
+const TAG: &str = "conformance";
+const SECOND_TAG: &str = "conformance-2";
+/// A digest no registry will have content for.
+const UNKNOWN_DIGEST: &str =
+    "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+
+impl Conformance {
+    fn record(&mut self, category: Category, name: &'static str, outcome: CheckOutcome) {
+        let outcome = outcome.err().unwrap_or(Outcome::Pass);
+        match &outcome {
+            Outcome::Pass => debug!(%category, check = name, "Passed"),
+            Outcome::Fail(reason) => error!(%category, check = name, "Failed: {reason}"),
+            Outcome::Skip(reason) => info!(%category, check = name, "Skipped: {reason}"),
+        }
+        self.results.push(CheckResult {
+            category,
+            name,
+            outcome,
+        });
+    }
+
+    fn path(&self, path: &str) -> String {
+        format!("/v2/{}/{path}", self.repository)
+    }
+
+    async fn start_upload(&self) -> Result<reqwest::Url, Outcome> {
+        let res = send(
+            self.registry
+                .request(Method::POST, &self.path("blobs/uploads/")),
+        )
+        .await?;
+        expect_status(&res, &[StatusCode::ACCEPTED])?;
+        let location = expect_header(&res, header::LOCATION.as_str())?;
+        self.registry
+            .resolve(location)
+            .map_err(|e| fail(e.to_string()))
+    }
+
+    async fn put_upload(
+        &self,
+        mut location: reqwest::Url,
+        digest: &str,
+        data: Vec<u8>,
+    ) -> Result<Response, Outcome> {
+        location.query_pairs_mut().append_pair("digest", digest);
+        send(
+            self.registry
+                .request_url(Method::PUT, location)
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(header::CONTENT_LENGTH, data.len())
+                .body(data),
+        )
+        .await
+    }
+
+    async fn push_blob(&self, digest: &str, data: Vec<u8>) -> CheckOutcome {
+        let location = self.start_upload().await?;
+        let res = self.put_upload(location, digest, data).await?;
+        expect_status(&res, &[StatusCode::CREATED])?;
+        expect_header(&res, header::LOCATION.as_str())?;
+        Ok(())
+    }
+
+    async fn put_manifest(&self, reference: &str) -> CheckOutcome {
+        if !self.layer_pushed || !self.config_pushed {
+            return Err(Outcome::Skip(
+                "the blobs of the manifest weren't pushed".into(),
+            ));
+        }
+        let res = send(
+            self.registry
+                .request(Method::PUT, &self.path(&format!("manifests/{reference}")))
+                .header(header::CONTENT_TYPE, OCI_IMAGE_MEDIA_TYPE)
+                .body(self.manifest.clone()),
+        )
+        .await?;
+        expect_status(&res, &[StatusCode::CREATED])?;
+        expect_header(&res, header::LOCATION.as_str())?;
+        if res.headers().contains_key(DOCKER_CONTENT_DIGEST) {
+            expect_digest_header(&res, &self.manifest_digest)?;
+        }
+        Ok(())
+    }
+
+    fn require_manifest(&self) -> CheckOutcome {
+        if self.manifest_pushed {
+            Ok(())
+        } else {
+            Err(Outcome::Skip("the manifest wasn't pushed".into()))
+        }
+    }
+
+    async fn push_checks(&mut self) {
+        let outcome = self.start_upload().await.map(|_| ());
+        self.record(Category::Push, "start a blob upload", outcome);
+
+        let outcome = self.push_blob(&self.layer_digest, self.layer.clone()).await;
+        self.layer_pushed = outcome.is_ok();
+        self.record(Category::Push, "push a blob with POST then PUT", outcome);
+
+        let outcome = self
+            .push_blob(&self.config_digest, self.config.clone())
+            .await;
+        self.config_pushed = outcome.is_ok();
+        self.record(Category::Push, "push a config blob", outcome);
+
+        let outcome = async {
+            let location = self.start_upload().await?;
+            let res = self
+                .put_upload(location, UNKNOWN_DIGEST, self.layer.clone())
+                .await?;
+            expect_error(res, StatusCode::BAD_REQUEST, "DIGEST_INVALID").await
+        }
+        .await;
+        self.record(Category::Push, "reject a blob with a wrong digest", outcome);
+
+        let outcome = self.put_manifest(TAG).await;
+        self.manifest_pushed = outcome.is_ok();
+        self.record(Category::Push, "push a manifest by tag", outcome);
+
+        let outcome = self.put_manifest(&self.manifest_digest).await;
+        self.record(Category::Push, "push a manifest by digest", outcome);
+    }
+
+    async fn pull_checks(&mut self) {
+        let outcome = async {
+            let res = send(self.registry.request(Method::GET, "/v2/")).await?;
+            expect_status(&res, &[StatusCode::OK])
+        }
+        .await;
+        self.record(Category::Pull, "check the API version", outcome);
+
+        let outcome = async {
+            if !self.layer_pushed {
+                return Err(Outcome::Skip("the blob wasn't pushed".into()));
+            }
+            let path = self.path(&format!("blobs/{}", self.layer_digest));
+            let res = send(self.registry.request(Method::HEAD, &path)).await?;
+            expect_status(&res, &[StatusCode::OK])?;
+            expect_digest_header(&res, &self.layer_digest)?;
+            let length = expect_header(&res, header::CONTENT_LENGTH.as_str())?;
+            if length == self.layer.len().to_string() {
+                Ok(())
+            } else {
+                Err(fail(format!(
+                    "expected Content-Length {} but got {length}",
+                    self.layer.len()
+                )))
+            }
+        }
+        .await;
+        self.record(Category::Pull, "check a blob exists", outcome);
+
+        let outcome = async {
+            if !self.layer_pushed {
+                return Err(Outcome::Skip("the blob wasn't pushed".into()));
+            }
+            let path = self.path(&format!("blobs/{}", self.layer_digest));
+            let res = send(self.registry.request(Method::GET, &path)).await?;
+            expect_status(&res, &[StatusCode::OK])?;
+            let body = res
+                .bytes()
+                .await
+                .map_err(|e| fail(format!("failed to read the blob: {e}")))?;
+            let digest = sha256_digest(&body);
+            if digest == self.layer_digest {
+                Ok(())
+            } else {
+                Err(fail(format!(
+                    "expected a blob with digest {} but got {digest}",
+                    self.layer_digest
+                )))
+            }
+        }
+        .await;
+        self.record(Category::Pull, "pull a blob", outcome);
+
+        let outcome = async {
+            let path = self.path(&format!("blobs/{UNKNOWN_DIGEST}"));
+            let res = send(self.registry.request(Method::GET, &path)).await?;
+            expect_error(res, StatusCode::NOT_FOUND, "BLOB_UNKNOWN").await
+        }
+        .await;
+        self.record(Category::Pull, "pull an unknown blob", outcome);
+    }
+
+    async fn manifest_pull_checks(&mut self) {
+        let outcome = async {
+            self.require_manifest()?;
+            let res = send(
+                self.registry
+                    .request(Method::HEAD, &self.path(&format!("manifests/{TAG}")))
+                    .header(header::ACCEPT, OCI_IMAGE_MEDIA_TYPE),
+            )
+            .await?;
+            expect_status(&res, &[StatusCode::OK])?;
+            expect_digest_header(&res, &self.manifest_digest)
+        }
+        .await;
+        self.record(Category::Pull, "check a manifest exists", outcome);
+
+        for (name, reference) in [
+            ("pull a manifest by tag", TAG.to_string()),
+            ("pull a manifest by digest", self.manifest_digest.clone()),
+        ] {
+            let outcome = async {
+                self.require_manifest()?;
+                let res = send(
+                    self.registry
+                        .request(Method::GET, &self.path(&format!("manifests/{reference}")))
+                        .header(header::ACCEPT, OCI_IMAGE_MEDIA_TYPE),
+                )
+                .await?;
+                expect_status(&res, &[StatusCode::OK])?;
+                expect_digest_header(&res, &self.manifest_digest)?;
+                let content_type = expect_header(&res, header::CONTENT_TYPE.as_str())?;
+                if content_type != OCI_IMAGE_MEDIA_TYPE {
+                    return Err(fail(format!(
+                        "expected Content-Type {OCI_IMAGE_MEDIA_TYPE} but got {content_type}"
+                    )));
+                }
+                let body = res
+                    .bytes()
+                    .await
+                    .map_err(|e| fail(format!("failed to read the manifest: {e}")))?;
+                if body.as_ref() == self.manifest.as_slice() {
+                    Ok(())
+                } else {
+                    Err(fail("the manifest differs from the one pushed"))
+                }
+            }
+            .await;
+            self.record(Category::Pull, name, outcome);
+        }
+
+        let outcome = async {
+            let path = self.path("manifests/unknown-conformance-tag");
+            let res = send(
+                self.registry
+                    .request(Method::GET, &path)
+                    .header(header::ACCEPT, OCI_IMAGE_MEDIA_TYPE),
+            )
+            .await?;
+            expect_error(res, StatusCode::NOT_FOUND, "MANIFEST_UNKNOWN").await
+        }
+        .await;
+        self.record(Category::Pull, "pull an unknown manifest", outcome);
+    }
+
+    async fn list_tags(&self, query: &str) -> Result<Vec<String>, Outcome> {
+        let res = send(
+            self.registry
+                .request(Method::GET, &format!("{}{query}", self.path("tags/list"))),
+        )
+        .await?;
+        expect_status(&res, &[StatusCode::OK])?;
+        let body: serde_json::Value = res
+            .json()
+            .await
+            .map_err(|e| fail(format!("tag list is not JSON: {e}")))?;
+        let tags = body
+            .get("tags")
+            .and_then(serde_json::Value::as_array)
+            .ok_or_else(|| fail("tag list has no tags"))?;
+        Ok(tags
+            .iter()
+            .filter_map(serde_json::Value::as_str)
+            .map(str::to_string)
+            .collect())
+    }
+
+    async fn content_discovery_checks(&mut self) {
+        let outcome = async {
+            self.require_manifest()?;
+            self.put_manifest(SECOND_TAG).await?;
+            let tags = self.list_tags("").await?;
+            if tags.iter().any(|t| t == TAG) && tags.iter().any(|t| t == SECOND_TAG) {
+                Ok(())
+            } else {
+                Err(fail(format!(
+                    "expected tags {TAG} and {SECOND_TAG} but got {tags:?}"
+                )))
+            }
+        }
+        .await;
+        self.record(Category::ContentDiscovery, "list tags", outcome);
+
+        let outcome = async {
+            self.require_manifest()?;
+            let tags = self.list_tags("?n=1").await?;
+            if tags.len() == 1 {
+                Ok(())
+            } else {
+                Err(fail(format!("expected 1 tag but got {tags:?}")))
+            }
+        }
+        .await;
+        self.record(
+            Category::ContentDiscovery,
+            "list tags with pagination",
+            outcome,
+        );
+    }
+
+    /// Deletes content, skipping when the registry has deletion disabled or doesn't support it.
+    async fn delete(&self, path: &str) -> CheckOutcome {
+        let res = send(self.registry.request(Method::DELETE, path)).await?;
+        if res.status() == StatusCode::METHOD_NOT_ALLOWED {
+            return Err(Outcome::Skip("the registry has deletion disabled".into()));
+        }
+        let outcome = expect_status(&res, &[StatusCode::ACCEPTED]);
+        if res.status() == StatusCode::BAD_REQUEST {
+            let body = res.text().await.unwrap_or_default();
+            if oci_error_code(&body).as_deref() == Some("UNSUPPORTED") {
+                return Err(Outcome::Skip(
+                    "the registry doesn't support deletion".into(),
+                ));
+            }
+        }
+        outcome
+    }
+
+    async fn content_management_checks(&mut self) {
+        let outcome = async {
+            self.require_manifest()?;
+            self.delete(&self.path(&format!("manifests/{SECOND_TAG}")))
+                .await
+        }
+        .await;
+        self.record(Category::ContentManagement, "delete a tag", outcome);
+
+        let outcome = async {
+            self.require_manifest()?;
+            self.delete(&self.path(&format!("manifests/{}", self.manifest_digest)))
+                .await
+        }
+        .await;
+        let manifest_deleted = outcome.is_ok();
+        self.record(Category::ContentManagement, "delete a manifest", outcome);
+
+        let outcome = async {
+            if !manifest_deleted {
+                return Err(Outcome::Skip("the manifest wasn't deleted".into()));
+            }
+            let path = self.path(&format!("manifests/{}", self.manifest_digest));
+            let res = send(
+                self.registry
+                    .request(Method::GET, &path)
+                    .header(header::ACCEPT, OCI_IMAGE_MEDIA_TYPE),
+            )
+            .await?;
+            expect_error(res, StatusCode::NOT_FOUND, "MANIFEST_UNKNOWN").await
+        }
+        .await;
+        self.record(
+            Category::ContentManagement,
+            "pull a deleted manifest",
+            outcome,
+        );
+
+        let outcome = async {
+            if !self.layer_pushed {
+                return Err(Outcome::Skip("the blob wasn't pushed".into()));
+            }
+            self.delete(&self.path(&format!("blobs/{}", self.layer_digest)))
+                .await
+        }
+        .await;
+        let blob_deleted = outcome.is_ok();
+        self.record(Category::ContentManagement, "delete a blob", outcome);
+
+        let outcome = async {
+            if !blob_deleted {
+                return Err(Outcome::Skip("the blob wasn't deleted".into()));
+            }
+            let path = self.path(&format!("blobs/{}", self.layer_digest));
+            let res = send(self.registry.request(Method::GET, &path)).await?;
+            expect_error(res, StatusCode::NOT_FOUND, "BLOB_UNKNOWN").await
+        }
+        .await;
+        self.record(Category::ContentManagement, "pull a deleted blob", outcome);
+    }
+}
+
+/// Runs every conformance check against `repository`, which the checks push to and delete from.
+///
+/// # Errors
+///
+/// * If the test image can't be generated
+/// * If authenticating against the registry fails
+pub async fn run(mut registry: Registry, repository: &str) -> anyhow::Result<Vec<CheckResult>> {
+    registry
+        .authenticate(&[scope(repository, "pull,push,delete")])
+        .await
+        .context("failed to authenticate against the registry")?;
+
+    let options = LayerOptions {
+        sizes: &Distribution::Fixed(MEGABYTE),
+        compression: &CompressionMix::default(),
+        compressibility: Compressibility::default(),
+        tree: Tree::default(),
+        stream: false,
+    };
+    let image = fake::gen_image(fake::gen_rand_layers(&mut rand::thread_rng(), options, 1))
+        .context("failed to generate the test image")?;
+    let layer = image
+        .layers
+        .into_iter()
+        .next()
+        .context("the test image has no layer")?;
+    let manifest = image.manifest.context("the test image has no manifest")?;
+    let manifest = serde_json::to_vec(&manifest).context("failed to serialize the manifest")?;
+
+    let mut conformance = Conformance {
+        registry,
+        repository: repository.to_string(),
+        results: Vec::new(),
+        layer_digest: sha256_digest(&layer.data),
+        layer: layer.data,
+        config_digest: sha256_digest(&image.config.data),
+        config: image.config.data,
+        manifest_digest: sha256_digest(&manifest),
+        manifest,
+        layer_pushed: false,
+        config_pushed: false,
+        manifest_pushed: false,
+    };
+    conformance.push_checks().await;
+    conformance.pull_checks().await;
+    conformance.manifest_pull_checks().await;
+    conformance.content_discovery_checks().await;
+    conformance.content_management_checks().await;
+    Ok(conformance.results)
+}
+
+/// Returns the categories of the results, each once and in the order of the specification.
+fn categories(results: &[CheckResult]) -> Vec<Category> {
+    let mut categories: Vec<Category> = results.iter().map(|r| r.category).collect();
+    categories.sort();
+    categories.dedup();
+    categories
+}
+
+/// Formats the results as a matrix of categories against outcomes, followed by every check.
+pub fn matrix(results: &[CheckResult]) -> String {
+    let mut out = format!(
+        "{:<20} {:>6} {:>6} {:>6}\n",
+        "category", "pass", "fail", "skip"
+    );
+    for category in categories(results) {
+        let count = |outcome: &str| {
+            results
+                .iter()
+                .filter(|r| r.category == category && r.outcome.to_string() == outcome)
+                .count()
+        };
+        let _ = writeln!(
+            out,
+            "{:<20} {:>6} {:>6} {:>6}",
+            category.to_string(),
+            count("pass"),
+            count("fail"),
+            count("skip"),
+        );
+    }
+    out.push('\n');
+    for result in results {
+        let _ = write!(
+            out,
+            "[{}] {}: {}",
+            result.outcome, result.category, result.name
+        );
+        match &result.outcome {
+            Outcome::Pass => out.push('\n'),
+            Outcome::Fail(reason) | Outcome::Skip(reason) => {
+                let _ = writeln!(out, " ({reason})");
+            }
+        }
+    }
+    out
+}
+
+/// Writes the results of the conformance checks as a report.
+///
+/// # Errors
+///
+/// * If the report can't be serialized
+/// * If the report file can't be written
+pub fn write_report(options: &ReportOptions, results: &[CheckResult]) -> anyhow::Result<()> {
+    let reason = |outcome: &Outcome| match outcome {
+        Outcome::Pass => String::new(),
+        Outcome::Fail(reason) | Outcome::Skip(reason) => reason.clone(),
+    };
+
+    let contents = match options.format {
+        ReportFormat::Json => {
+            let checks: Vec<serde_json::Value> = results
+                .iter()
+                .map(|r| {
+                    serde_json::json!({
+                        "category": r.category.to_string(),
+                        "name": r.name,
+                        "outcome": r.outcome.to_string(),
+                        "reason": reason(&r.outcome),
+                    })
+                })
+                .collect();
+            serde_json::to_string_pretty(&serde_json::json!({
+                "command": "conformance",
+                "checks": checks,
+            }))
+            .context("failed to serialize the report")?
+        }
+        ReportFormat::Csv => {
+            let mut csv = String::from("category,name,outcome,reason\n");
+            for r in results {
+                let _ = writeln!(
+                    csv,
+                    "{},{},{},{}",
+                    r.category,
+                    csv_field(r.name),
+                    r.outcome,
+                    csv_field(&reason(&r.outcome)),
+                );
+            }
+            csv
+        }
+        ReportFormat::Junit => {
+            let failures = |category: Option<Category>| {
+                results
+                    .iter()
+                    .filter(|r| category.is_none() || category == Some(r.category))
+                    .filter(|r| matches!(r.outcome, Outcome::Fail(_)))
+                    .count()
+            };
+            let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+            let _ = writeln!(
+                xml,
+                "<testsuites name=\"oci-tester conformance\" tests=\"{}\" failures=\"{}\">",
+                results.len(),
+                failures(None),
+            );
+            for category in categories(results) {
+                let _ = writeln!(
+                    xml,
+                    "  <testsuite name=\"{category}\" tests=\"{}\" failures=\"{}\">",
+                    results.iter().filter(|r| r.category == category).count(),
+                    failures(Some(category)),
+                );
+                for r in results.iter().filter(|r| r.category == category) {
+                    let _ = writeln!(
+                        xml,
+                        "    <testcase classname=\"conformance.{category}\" name=\"{}\">",
+                        xml_escape(r.name),
+                    );
+                    match &r.outcome {
+                        Outcome::Pass => {}
+                        Outcome::Fail(reason) => {
+                            let _ = writeln!(
+                                xml,
+                                "      <failure message=\"{}\"/>",
+                                xml_escape(reason)
+                            );
+                        }
+                        Outcome::Skip(reason) => {
+                            let _ = writeln!(
+                                xml,
+                                "      <skipped message=\"{}\"/>",
+                                xml_escape(reason)
+                            );
+                        }
+                    }
+                    xml.push_str("    </testcase>\n");
+                }
+                xml.push_str("  </testsuite>\n");
+            }
+            xml.push_str("</testsuites>\n");
+            xml
+        }
+    };
+    std::fs::write(&options.path, contents).with_context(|| {
+        format!(
+            "failed to write the report to {path}",
+            path = options.path.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Server,
+    };
+    use oci_distribution::client::ClientProtocol;
+
+    use super::*;
+    use crate::registry::Credentials;
+
+    fn result(category: Category, name: &'static str, outcome: Outcome) -> CheckResult {
+        CheckResult {
+            category,
+            name,
+            outcome,
+        }
+    }
+
+    fn results() -> Vec<CheckResult> {
+        vec![
+            result(Category::Push, "push a blob", Outcome::Pass),
+            result(
+                Category::Pull,
+                "pull a blob",
+                Outcome::Fail("500".to_string()),
+            ),
+            result(Category::Push, "push a manifest", Outcome::Pass),
+            result(
+                Category::ContentManagement,
+                "delete a tag",
+                Outcome::Skip("unsupported".to_string()),
+            ),
+        ]
+    }
+
+    #[test]
+    fn categories_are_listed_once_in_order() {
+        assert_eq!(
+            categories(&results()),
+            [Category::Pull, Category::Push, Category::ContentManagement]
+        );
+    }
+
+    #[test]
+    fn matrix_counts_outcomes_per_category() {
+        let matrix = matrix(&results());
+        let rows: Vec<Vec<&str>> = matrix
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                vec!["category", "pass", "fail", "skip"],
+                vec!["pull", "0", "1", "0"],
+                vec!["push", "2", "0", "0"],
+                vec!["content-management", "0", "0", "1"],
+            ]
+        );
+        assert!(matrix.contains("[fail] pull: pull a blob (500)\n"));
+        assert!(matrix.contains("[pass] push: push a blob\n"));
+    }
+
+    /// Serves a registry answering deletes with the status and error code the last segment of
+    /// their path names.
+    fn serve_deletes() -> SocketAddr {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let (status, code) = match req.uri().path().rsplit('/').next() {
+                    Some("disabled") => (405, None),
+                    Some("unsupported") => (400, Some("UNSUPPORTED")),
+                    Some("invalid") => (400, Some("DIGEST_INVALID")),
+                    _ => (202, None),
+                };
+                let body = code.map_or_else(String::new, |code| {
+                    format!(r#"{{"errors":[{{"code":"{code}","message":""}}]}}"#)
+                });
+                hyper::Response::builder()
+                    .status(status)
+                    .body(Body::from(body))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn deletes_are_skipped_when_disabled_or_unsupported() {
+        let registry = Registry::new(
+            reqwest::Client::new(),
+            &ClientProtocol::Http,
+            &serve_deletes().to_string(),
+            Credentials::Anonymous,
+        );
+        let conformance = Conformance {
+            registry,
+            repository: "test".to_string(),
+            results: Vec::new(),
+            layer: Vec::new(),
+            layer_digest: String::new(),
+            config: Vec::new(),
+            config_digest: String::new(),
+            manifest: Vec::new(),
+            manifest_digest: String::new(),
+            layer_pushed: false,
+            config_pushed: false,
+            manifest_pushed: false,
+        };
+        let path = |reference: &str| conformance.path(&format!("manifests/{reference}"));
+        assert_eq!(conformance.delete(&path("deleted")).await, Ok(()));
+        assert!(matches!(
+            conformance.delete(&path("disabled")).await,
+            Err(Outcome::Skip(_))
+        ));
+        assert!(matches!(
+            conformance.delete(&path("unsupported")).await,
+            Err(Outcome::Skip(_))
+        ));
+        assert!(matches!(
+            conformance.delete(&path("invalid")).await,
+            Err(Outcome::Fail(_))
+        ));
+    }
+}
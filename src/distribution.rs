@@ -0,0 +1,264 @@
+//! Distributions of the sizes and counts of generated content, such as layer sizes.
+use std::{fmt::Display, fs, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, bail, Context};
+use rand::Rng;
+use rand_distr::{Distribution as _, LogNormal};
+
+use crate::parse::parse_size;
+
+/// A distribution of sizes in bytes, or of counts, parsed from `SIZE`, `fixed:SIZE`,
+/// `uniform:MIN..MAX`, `lognormal:MEDIAN:SIGMA` or `histogram:FILE`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Distribution {
+    /// Always the same value.
+    Fixed(usize),
+    /// Uniformly between two values, both included.
+    Uniform {
+        /// The smallest value.
+        min: usize,
+        /// The largest value.
+        max: usize,
+    },
+    /// Log-normally around a median.
+    LogNormal {
+        /// The median value.
+        median: usize,
+        /// The standard deviation of the logarithm of the values. 1.0 to 2.0 is typical of image
+        /// layers.
+        sigma: f64,
+    },
+    /// The empirical distribution in a file of `SIZE WEIGHT` lines sorted by size. Every line is a
+    /// bucket of the values up to SIZE, above the SIZE of the previous line, picked with a
+    /// probability proportional to WEIGHT.
+    Histogram {
+        /// The file the histogram was read from.
+        file: PathBuf,
+        /// The largest value and the weight of every bucket.
+        buckets: Vec<(usize, f64)>,
+    },
+}
+
+impl Distribution {
+    /// Samples a value from the distribution.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn sample(&self, rng: &mut impl Rng) -> usize {
+        match self {
+            Distribution::Fixed(value) => *value,
+            Distribution::Uniform { min, max } => rng.gen_range(*min..=*max),
+            Distribution::LogNormal { median, sigma } => {
+                LogNormal::new((*median as f64).ln(), *sigma)
+                    .map_or(*median, |d| d.sample(rng).round() as usize)
+            }
+            Distribution::Histogram { buckets, .. } => {
+                let total: f64 = buckets.iter().map(|(_, weight)| weight).sum();
+                let mut pick = rng.gen_range(0.0..total);
+                let mut min = 0;
+                for (max, weight) in buckets {
+                    if pick < *weight {
+                        return rng.gen_range(min..=*max);
+                    }
+                    pick -= weight;
+                    min = max + 1;
+                }
+                buckets.last().map_or(0, |(max, _)| *max)
+            }
+        }
+    }
+}
+
+impl Display for Distribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Distribution::Fixed(value) => write!(f, "{value}"),
+            Distribution::Uniform { min, max } => write!(f, "uniform:{min}..{max}"),
+            Distribution::LogNormal { median, sigma } => write!(f, "lognormal:{median}:{sigma}"),
+            Distribution::Histogram { file, .. } => write!(f, "histogram:{}", file.display()),
+        }
+    }
+}
+
+impl FromStr for Distribution {
+    type Err = anyhow::Error;
+
+    fn from_str(distribution: &str) -> Result<Self, Self::Err> {
+        let (kind, params) = distribution
+            .split_once(':')
+            .unwrap_or(("fixed", distribution));
+        match kind {
+            "fixed" => Ok(Distribution::Fixed(parse_size(params)?)),
+            "uniform" => {
+                let (min, max) = params
+                    .split_once("..")
+                    .ok_or_else(|| anyhow!("expected uniform:MIN..MAX: {distribution}"))?;
+                let (min, max) = (parse_size(min)?, parse_size(max)?);
+                if min > max {
+                    bail!("the minimum is above the maximum: {distribution}");
+                }
+                Ok(Distribution::Uniform { min, max })
+            }
+            "lognormal" => {
+                let (median, sigma) = params
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("expected lognormal:MEDIAN:SIGMA: {distribution}"))?;
+                let median = parse_size(median)?;
+                let sigma: f64 = sigma
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid sigma: {distribution}"))?;
+                if median == 0 {
+                    bail!("the median must be above 0: {distribution}");
+                }
+                if !sigma.is_finite() || sigma < 0.0 {
+                    bail!("the sigma must be a positive number: {distribution}");
+                }
+                Ok(Distribution::LogNormal { median, sigma })
+            }
+            "histogram" => {
+                let contents = fs::read_to_string(params)
+                    .with_context(|| format!("failed to read the histogram file {params}"))?;
+                let buckets = parse_histogram(&contents)
+                    .with_context(|| format!("invalid histogram {params}"))?;
+                Ok(Distribution::Histogram {
+                    file: params.into(),
+                    buckets,
+                })
+            }
+            _ => bail!("unknown distribution {kind}: {distribution}"),
+        }
+    }
+}
+
+/// Parses the `SIZE WEIGHT` lines of a histogram file into its buckets. Empty lines and lines
+/// starting with `#` are skipped.
+fn parse_histogram(contents: &str) -> anyhow::Result<Vec<(usize, f64)>> {
+    let mut buckets: Vec<(usize, f64)> = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(|c: char| c.is_whitespace() || c == ',');
+        let (Some(size), Some(weight)) = (fields.next(), fields.find(|f| !f.is_empty())) else {
+            bail!("expected SIZE WEIGHT on line {}: {line}", i + 1);
+        };
+        let size = parse_size(size).with_context(|| format!("on line {}", i + 1))?;
+        let weight: f64 = weight
+            .parse()
+            .with_context(|| format!("invalid weight on line {}: {line}", i + 1))?;
+        if !weight.is_finite() || weight < 0.0 {
+            bail!("the weight must be a positive number on line {}", i + 1);
+        }
+        if buckets.last().is_some_and(|(last, _)| *last >= size) {
+            bail!("the sizes must be sorted and distinct on line {}", i + 1);
+        }
+        buckets.push((size, weight));
+    }
+    if buckets.iter().map(|(_, weight)| weight).sum::<f64>() <= 0.0 {
+        bail!("the histogram has no weight");
+    }
+    Ok(buckets)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn from_str_reads_every_distribution() {
+        assert_eq!(
+            "10MiB".parse::<Distribution>().unwrap(),
+            Distribution::Fixed(10 << 20)
+        );
+        assert_eq!(
+            "fixed:5".parse::<Distribution>().unwrap(),
+            Distribution::Fixed(5)
+        );
+        assert_eq!(
+            "uniform:1MiB..50MiB".parse::<Distribution>().unwrap(),
+            Distribution::Uniform {
+                min: 1 << 20,
+                max: 50 << 20,
+            }
+        );
+        assert_eq!(
+            "lognormal:8MiB:1.5".parse::<Distribution>().unwrap(),
+            Distribution::LogNormal {
+                median: 8 << 20,
+                sigma: 1.5,
+            }
+        );
+    }
+
+    #[test]
+    fn from_str_rejects_malformed_distributions() {
+        assert!("uniform:5".parse::<Distribution>().is_err());
+        assert!("uniform:5..1".parse::<Distribution>().is_err());
+        assert!("lognormal:8MiB".parse::<Distribution>().is_err());
+        assert!("lognormal:0:1.5".parse::<Distribution>().is_err());
+        assert!("lognormal:8MiB:-1".parse::<Distribution>().is_err());
+        assert!("normal:8MiB:1".parse::<Distribution>().is_err());
+        assert!("histogram:/nonexistent".parse::<Distribution>().is_err());
+    }
+
+    #[test]
+    fn parse_histogram_reads_buckets() {
+        let buckets = parse_histogram("# size weight\n1KiB 3\n\n1MiB, 1\n").unwrap();
+        assert_eq!(buckets, vec![(1024, 3.0), (1 << 20, 1.0)]);
+        assert!(parse_histogram("1KiB\n").is_err());
+        assert!(parse_histogram("1MiB 1\n1KiB 1\n").is_err());
+        assert!(parse_histogram("1KiB -1\n").is_err());
+        assert!(parse_histogram("1KiB 0\n").is_err());
+        assert!(parse_histogram("").is_err());
+    }
+
+    #[test]
+    fn samples_stay_within_the_distribution() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let uniform = Distribution::Uniform { min: 10, max: 20 };
+        let histogram = Distribution::Histogram {
+            file: PathBuf::new(),
+            buckets: vec![(100, 1.0), (200, 0.0), (300, 1.0)],
+        };
+        for _ in 0..1000 {
+            assert_eq!(Distribution::Fixed(7).sample(&mut rng), 7);
+            assert!((10..=20).contains(&uniform.sample(&mut rng)));
+            let value = histogram.sample(&mut rng);
+            assert!(value <= 100 || (201..=300).contains(&value), "{value}");
+        }
+    }
+
+    #[test]
+    fn lognormal_samples_center_on_the_median() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let lognormal = Distribution::LogNormal {
+            median: 1000,
+            sigma: 1.0,
+        };
+        let mut samples: Vec<usize> = (0..1001).map(|_| lognormal.sample(&mut rng)).collect();
+        samples.sort_unstable();
+        assert!((800..1250).contains(&samples[500]), "{}", samples[500]);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for distribution in ["5", "uniform:1..10", "lognormal:100:1.5"] {
+            let parsed: Distribution = distribution.parse().unwrap();
+            assert_eq!(parsed.to_string(), distribution);
+            assert_eq!(parsed.to_string().parse::<Distribution>().unwrap(), parsed);
+        }
+
+        let file = std::env::temp_dir().join(format!("histogram-{}", std::process::id()));
+        fs::write(&file, "1KiB 1\n").unwrap();
+        let distribution = format!("histogram:{}", file.display());
+        let parsed: Distribution = distribution.parse().unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(parsed.to_string(), distribution);
+    }
+}
@@ -0,0 +1,296 @@
+//! Looks credentials up in a Docker config file, the way the Docker CLI does: with the credential
+//! helper of the registry, the credential store, or the `auths` of the file.
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::registry::Credentials;
+
+/// The key Docker Hub credentials are stored under.
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+/// The user name credential helpers return along with an identity token.
+const IDENTITY_TOKEN_USER: &str = "<token>";
+
+/// The parts of a Docker config file holding credentials.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+/// The credentials of a registry in the `auths` of a Docker config file.
+#[derive(Debug, Default, Deserialize)]
+struct AuthEntry {
+    /// The base64 encoded `user:password`.
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+    registrytoken: Option<String>,
+}
+
+/// The credentials returned by `docker-credential-<helper> get`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Returns the path of the Docker config file, in `$DOCKER_CONFIG` or `~/.docker`.
+#[must_use]
+pub fn default_path() -> PathBuf {
+    let dir = env::var_os("DOCKER_CONFIG").map_or_else(
+        || {
+            env::var_os("HOME")
+                .map(PathBuf::from)
+                .unwrap_or_default()
+                .join(".docker")
+        },
+        PathBuf::from,
+    );
+    dir.join("config.json")
+}
+
+/// Looks up the credentials of `registry` (a host with an optional port) in the Docker config file
+/// at `path`. The credentials are anonymous when the file has none for the registry.
+///
+/// # Errors
+///
+/// * If the file can't be read or parsed
+/// * If the credential helper fails or returns malformed credentials
+pub fn lookup(path: &Path, registry: &str) -> Result<Credentials> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read the Docker config {}", path.display()))?;
+    let config: DockerConfig = serde_json::from_str(&contents)
+        .with_context(|| format!("invalid Docker config {}", path.display()))?;
+    let host = hostname(registry);
+
+    let helper = config
+        .cred_helpers
+        .iter()
+        .find(|(server, _)| hostname(server) == host)
+        .map(|(_, helper)| helper)
+        .or(config.creds_store.as_ref());
+    if let Some(helper) = helper {
+        let server = if host == hostname(DOCKER_HUB_SERVER) {
+            DOCKER_HUB_SERVER
+        } else {
+            registry
+        };
+        return run_helper(helper, server);
+    }
+
+    let Some((server, entry)) = config
+        .auths
+        .iter()
+        .find(|(server, _)| hostname(server) == host)
+    else {
+        debug!(registry, path = %path.display(), "No credentials in the Docker config");
+        return Ok(Credentials::Anonymous);
+    };
+    entry_credentials(entry)
+        .with_context(|| format!("invalid credentials of {server} in {}", path.display()))
+}
+
+/// Returns the host of a registry or of a key of a Docker config file, which may be a URL, with
+/// the aliases of Docker Hub folded into one.
+fn hostname(server: &str) -> &str {
+    let host = server
+        .strip_prefix("https://")
+        .or_else(|| server.strip_prefix("http://"))
+        .unwrap_or(server);
+    let host = host.split('/').next().unwrap_or(host);
+    match host {
+        "docker.io" | "registry-1.docker.io" => "index.docker.io",
+        host => host,
+    }
+}
+
+fn entry_credentials(entry: &AuthEntry) -> Result<Credentials> {
+    if let Some(token) = &entry.registrytoken {
+        return Ok(Credentials::Bearer(token.clone()));
+    }
+    if let Some(token) = &entry.identitytoken {
+        return Ok(Credentials::IdentityToken(token.clone()));
+    }
+    if let Some(auth) = entry.auth.as_deref().filter(|auth| !auth.is_empty()) {
+        let decoded = STANDARD
+            .decode(auth.trim())
+            .context("auth is not valid base64")?;
+        let decoded = String::from_utf8(decoded).context("auth is not valid UTF-8")?;
+        let (user, password) = decoded
+            .split_once(':')
+            .ok_or_else(|| anyhow!("auth is not in the format user:password"))?;
+        return Ok(Credentials::Basic(user.to_string(), password.to_string()));
+    }
+    match (&entry.username, &entry.password) {
+        (Some(user), Some(password)) => Ok(Credentials::Basic(user.clone(), password.clone())),
+        _ => Ok(Credentials::Anonymous),
+    }
+}
+
+/// Gets the credentials of `server` from `docker-credential-<helper>`.
+fn run_helper(helper: &str, server: &str) -> Result<Credentials> {
+    let program = format!("docker-credential-{helper}");
+    debug!(program, server, "Running the credential helper");
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {program}"))?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("no stdin for {program}"))?
+        .write_all(server.as_bytes())
+        .with_context(|| format!("failed to write to {program}"))?;
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("failed to run {program}"))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        if stdout.contains("credentials not found") {
+            debug!(program, server, "The credential helper has no credentials");
+            return Ok(Credentials::Anonymous);
+        }
+        bail!(
+            "{program} failed for {server}: {}{}",
+            stdout.trim(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let credentials: HelperCredentials = serde_json::from_str(&stdout)
+        .with_context(|| format!("{program} returned malformed credentials for {server}"))?;
+    if credentials.username == IDENTITY_TOKEN_USER {
+        Ok(Credentials::IdentityToken(credentials.secret))
+    } else {
+        Ok(Credentials::Basic(credentials.username, credentials.secret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(json: &str) -> AuthEntry {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// Writes a Docker config to a file of its own, removed when dropped.
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str, contents: &str) -> ConfigFile {
+            let path =
+                env::temp_dir().join(format!("oci-tester-{}-{name}.json", std::process::id()));
+            fs::write(&path, contents).unwrap();
+            ConfigFile(path)
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn hostnames_are_normalised() {
+        assert_eq!(hostname("registry.example.com"), "registry.example.com");
+        assert_eq!(hostname("localhost:5000"), "localhost:5000");
+        assert_eq!(hostname("https://localhost:5000/v2/"), "localhost:5000");
+        assert_eq!(
+            hostname("http://registry.example.com"),
+            "registry.example.com"
+        );
+        assert_eq!(hostname(DOCKER_HUB_SERVER), "index.docker.io");
+        assert_eq!(hostname("docker.io"), "index.docker.io");
+        assert_eq!(hostname("registry-1.docker.io"), "index.docker.io");
+    }
+
+    #[test]
+    fn entry_credentials_prefer_tokens() {
+        assert_eq!(
+            entry_credentials(&entry(
+                r#"{"registrytoken":"r","identitytoken":"i","auth":"dTpw"}"#
+            ))
+            .unwrap(),
+            Credentials::Bearer("r".to_string())
+        );
+        assert_eq!(
+            entry_credentials(&entry(r#"{"identitytoken":"i","auth":"dTpw"}"#)).unwrap(),
+            Credentials::IdentityToken("i".to_string())
+        );
+    }
+
+    #[test]
+    fn entry_credentials_decode_auth() {
+        let auth = STANDARD.encode("user:pass:word");
+        assert_eq!(
+            entry_credentials(&entry(&format!(r#"{{"auth":"{auth}"}}"#))).unwrap(),
+            Credentials::Basic("user".to_string(), "pass:word".to_string())
+        );
+        assert_eq!(
+            entry_credentials(&entry(r#"{"auth":"","username":"u","password":"p"}"#)).unwrap(),
+            Credentials::Basic("u".to_string(), "p".to_string())
+        );
+        assert_eq!(
+            entry_credentials(&entry(r#"{"username":"u"}"#)).unwrap(),
+            Credentials::Anonymous
+        );
+        assert!(entry_credentials(&entry(r#"{"auth":"not base64!"}"#)).is_err());
+        let auth = STANDARD.encode("no-colon");
+        assert!(entry_credentials(&entry(&format!(r#"{{"auth":"{auth}"}}"#))).is_err());
+    }
+
+    #[test]
+    fn lookup_finds_the_auths_of_the_registry() {
+        let auth = STANDARD.encode("user:password");
+        let config = ConfigFile::new(
+            "auths",
+            &format!(
+                r#"{{"auths":{{
+                    "https://index.docker.io/v1/":{{"auth":"{auth}"}},
+                    "localhost:5000":{{"identitytoken":"token"}}
+                }}}}"#
+            ),
+        );
+        assert_eq!(
+            lookup(&config.0, "docker.io").unwrap(),
+            Credentials::Basic("user".to_string(), "password".to_string())
+        );
+        assert_eq!(
+            lookup(&config.0, "localhost:5000").unwrap(),
+            Credentials::IdentityToken("token".to_string())
+        );
+        assert_eq!(
+            lookup(&config.0, "localhost:5001").unwrap(),
+            Credentials::Anonymous
+        );
+    }
+
+    #[test]
+    fn lookup_fails_for_malformed_configs() {
+        let config = ConfigFile::new("malformed", "{");
+        assert!(lookup(&config.0, "localhost:5000").is_err());
+        let config = ConfigFile::new("missing-helper", r#"{"credsStore":"oci-tester-missing"}"#);
+        assert!(lookup(&config.0, "localhost:5000").is_err());
+    }
+}
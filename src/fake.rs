@@ -1,69 +1,648 @@
 use oci_distribution::{
-    client::ImageLayer,
-    config::{Architecture, ConfigFile, Os},
+    client::{Config, ImageLayer},
+    config::{Architecture, ConfigFile, Os, Rootfs},
     errors::OciDistributionError,
     manifest::{
-        ImageIndexEntry, OciImageIndex, Platform, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+        ImageIndexEntry, OciDescriptor, OciImageIndex, OciImageManifest, Platform,
+        OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
     },
 };
-use rand::{distributions::Alphanumeric, Rng, RngCore};
-use std::{io::Write, path::PathBuf};
-use tar::{Builder, Header};
+use rand::{distributions::Alphanumeric, rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rand_distr::{Distribution as _, LogNormal};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+use tar::{Builder, EntryType, Header};
 
-use crate::image::Image;
+use crate::{
+    compression::{Compressibility, Compression, CompressionMix, IMAGE_LAYER_ZSTD_MEDIA_TYPE},
+    distribution::Distribution,
+    image::Image,
+};
 
 pub const MEGABYTE: usize = 1024 * 1024;
 
-pub fn gen_tar_file(size: usize) -> Vec<u8> {
-    let filename = gen_file_name(10);
-    let filepath = gen_file_path(3);
-    let data = gen_file_data(size);
-    let mut header = Header::new_gnu();
-    header.set_size(data.len() as u64);
-    header.set_cksum();
+/// Returns the random number generator for the `index`th image generated with `seed`. The same
+/// seed and index always give the same generator, and so the same content and digests.
+pub fn seeded_rng(seed: u64, index: u64) -> StdRng {
+    let mut key = <StdRng as SeedableRng>::Seed::default();
+    key[..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..16].copy_from_slice(&index.to_le_bytes());
+    StdRng::from_seed(key)
+}
 
-    let mut ar = Builder::new(Vec::new());
+/// The bytes a tarball of a single file adds to the file: its header and the end of archive marker.
+const TAR_OVERHEAD: usize = 3 * 512;
 
-    let p = filepath.join(filename);
+/// The size of the end of archive marker of a tarball.
+const TAR_END: u64 = 2 * 512;
 
-    ar.append_data(&mut header, &p, &*data).unwrap();
-    ar.into_inner().unwrap()
+/// Generates a tarball of random files in the tree `tree` describes, whose content compresses as
+/// `compressibility` asks for. The tarball is `size` bytes when `size` is a multiple of 512 large
+/// enough for the headers of its entries, and rounded up to the next multiple of 512 otherwise.
+pub fn gen_tar_file(
+    rng: &mut impl Rng,
+    size: usize,
+    compressibility: Compressibility,
+    tree: Tree,
+) -> Vec<u8> {
+    let mut tar = Vec::with_capacity(size);
+    TarReader::new(rng, size, compressibility, tree)
+        .read_to_end(&mut tar)
+        .expect("reading generated content never fails");
+    tar
 }
 
-pub fn gen_tar_image_layer(size: usize) -> oci_distribution::client::ImageLayer {
-    let tar_data = gen_tar_file(size);
+/// Generates a layer of a tarball of random files compressed with `compression`, and returns it
+/// with its diff ID: the digest of the uncompressed tarball.
+fn gen_image_layer(
+    rng: &mut impl Rng,
+    size: usize,
+    compression: Compression,
+    compressibility: Compressibility,
+    tree: Tree,
+) -> (oci_distribution::client::ImageLayer, String) {
+    let tar_data = gen_tar_file(rng, size, compressibility, tree);
+    let diff_id = format!("sha256:{:x}", Sha256::digest(&tar_data));
+    let layer = match compression {
+        Compression::None => oci_distribution::client::ImageLayer::oci_v1(tar_data, None),
+        Compression::Gzip => {
+            let mut gz_data =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            gz_data.write_all(&tar_data).unwrap();
+            let gz_data = gz_data.finish().unwrap();
+            oci_distribution::client::ImageLayer::oci_v1_gzip(gz_data, None)
+        }
+        Compression::Zstd => {
+            let zstd_data = zstd::encode_all(&*tar_data, 0).unwrap();
+            oci_distribution::client::ImageLayer::new(
+                zstd_data,
+                IMAGE_LAYER_ZSTD_MEDIA_TYPE.to_string(),
+                None,
+            )
+        }
+    };
+    (layer, diff_id)
+}
 
-    oci_distribution::client::ImageLayer::oci_v1(tar_data, None)
+/// Generates `count` layers the way `options` asks for, held in memory whether `options` asks to
+/// stream them or not, with their diff IDs.
+pub fn gen_rand_layers(
+    rng: &mut impl Rng,
+    options: LayerOptions,
+    count: usize,
+) -> Vec<(oci_distribution::client::ImageLayer, String)> {
+    let mut layers = Vec::with_capacity(count);
+    for _ in 0..count {
+        let size = options.sizes.sample(rng);
+        let compression = options.compression.sample(rng);
+        layers.push(gen_image_layer(
+            rng,
+            size,
+            compression,
+            options.compressibility,
+            options.tree,
+        ));
+    }
+    layers
 }
 
-pub fn gen_gzip_tar_image_layer(size: usize) -> oci_distribution::client::ImageLayer {
-    let tar_data = gen_tar_file(size);
-    let mut gz_data = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
-    gz_data.write_all(&tar_data).unwrap();
-    let gz_data = gz_data.finish().unwrap();
-    oci_distribution::client::ImageLayer::oci_v1_gzip(gz_data, None)
+/// A layer whose content is generated again every time it's read, from a seed, instead of being
+/// held in memory. Layers of any size can be pushed this way in constant memory.
+#[derive(Debug, Clone)]
+pub struct StreamedLayer {
+    seed: <StdRng as SeedableRng>::Seed,
+    size: usize,
+    compression: Compression,
+    compressibility: Compressibility,
+    tree: Tree,
+    digest: String,
+    diff_id: String,
+    len: u64,
 }
 
-pub fn gen_rand_layers(size: usize, count: usize) -> Vec<oci_distribution::client::ImageLayer> {
-    let mut layers = Vec::with_capacity(count);
-    for _ in 0..count {
-        if rand::random::<f32>() > 0.5 {
-            layers.push(gen_gzip_tar_image_layer(size));
+impl StreamedLayer {
+    /// Generates a tarball of random files of about `size` bytes, compressed like
+    /// [`gen_image_layer`] does in memory. Reads the content once to compute its digest, diff ID
+    /// and length, which is CPU bound, so async callers create streamed layers on the blocking
+    /// thread pool.
+    pub fn new(
+        rng: &mut impl Rng,
+        size: usize,
+        compression: Compression,
+        compressibility: Compressibility,
+        tree: Tree,
+    ) -> StreamedLayer {
+        let mut layer = StreamedLayer {
+            seed: rng.gen(),
+            size,
+            compression,
+            compressibility,
+            tree,
+            digest: String::new(),
+            diff_id: String::new(),
+            len: 0,
+        };
+        let mut tar = HashingReader {
+            inner: layer.tar(),
+            hasher: Sha256::new(),
+        };
+        let mut hasher = Sha256::new();
+        layer.len = io::copy(&mut layer.compress(&mut tar), &mut hasher)
+            .expect("reading generated content never fails");
+        layer.digest = format!("sha256:{:x}", hasher.finalize());
+        layer.diff_id = format!("sha256:{:x}", tar.hasher.finalize());
+        layer
+    }
+
+    /// Returns a reader of the content of the layer, which is the same every time.
+    pub fn reader(&self) -> Box<dyn Read + Send> {
+        self.compress(self.tar())
+    }
+
+    /// Returns a reader of the uncompressed tarball of the layer.
+    fn tar(&self) -> TarReader<StdRng> {
+        TarReader::new(
+            StdRng::from_seed(self.seed),
+            self.size,
+            self.compressibility,
+            self.tree,
+        )
+    }
+
+    /// Compresses `tar` the way the layer is compressed.
+    fn compress<'a>(&self, tar: impl Read + Send + 'a) -> Box<dyn Read + Send + 'a> {
+        match self.compression {
+            Compression::None => Box::new(tar),
+            Compression::Gzip => Box::new(flate2::read::GzEncoder::new(
+                tar,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => Box::new(
+                zstd::stream::read::Encoder::new(tar, 0)
+                    .expect("the default compression level is valid"),
+            ),
+        }
+    }
+
+    /// Returns the digest of the content.
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+
+    /// Returns the diff ID of the layer: the digest of its uncompressed tarball.
+    pub fn diff_id(&self) -> &str {
+        &self.diff_id
+    }
+
+    /// Returns the length of the content in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the media type of the content.
+    pub fn media_type(&self) -> &'static str {
+        self.compression.media_type()
+    }
+}
+
+/// Hashes the content read from `inner` as it's read.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// The shape of the filesystem tree of generated layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tree {
+    /// The amount of entries of every layer, directories aside. A single entry is a regular file,
+    /// and a tenth of the entries of larger layers are symlinks, a twentieth are hardlinks and a
+    /// twentieth are whiteouts.
+    pub files: usize,
+    /// The depth of the directories of the files. A single file is always this deep, and the
+    /// files of larger layers are at most this deep.
+    pub depth: usize,
+}
+
+impl Default for Tree {
+    fn default() -> Self {
+        Tree { files: 1, depth: 3 }
+    }
+}
+
+/// The probability that a generated directory is opaque, hiding the directory of lower layers.
+const OPAQUE_RATE: f64 = 0.1;
+
+/// An entry of a generated tarball: its path, its header and the target of links.
+struct Entry {
+    path: PathBuf,
+    header: Header,
+    link: Option<PathBuf>,
+}
+
+impl Entry {
+    fn new(path: PathBuf, entry_type: EntryType, mode: u32, link: Option<PathBuf>) -> Entry {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(0);
+        Entry { path, header, link }
+    }
+
+    /// Returns the header of the entry as written in a tarball, including the extra entries of
+    /// long paths.
+    fn header_bytes(&mut self) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        match &self.link {
+            Some(link) => builder.append_link(&mut self.header, &self.path, link),
+            None => builder.append_data(&mut self.header, &self.path, io::empty()),
+        }
+        .expect("generated paths are valid");
+        // Taking the content leaves the end of archive marker written on drop to the builder.
+        std::mem::take(builder.get_mut())
+    }
+}
+
+/// Generates the entries of a tarball of about `size` bytes in the tree `tree` describes, with the
+/// header of every entry and the size of its content.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+fn gen_entries(rng: &mut impl Rng, size: usize, tree: Tree) -> Vec<(Vec<u8>, u64)> {
+    if tree.files <= 1 {
+        let filename = gen_file_name(rng, 10);
+        let filepath = gen_file_path(rng, tree.depth);
+        let mut entry = Entry::new(filepath.join(filename), EntryType::Regular, 0o644, None);
+        let size = size.saturating_sub(TAR_OVERHEAD) as u64;
+        entry.header.set_size(size);
+        return vec![(entry.header_bytes(), size)];
+    }
+
+    let mut entries = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    if tree.depth > 0 {
+        for _ in 0..tree.files / 4 {
+            let Some(parent) = dirs
+                .iter()
+                .filter(|dir| dir.components().count() < tree.depth)
+                .collect::<Vec<_>>()
+                .choose(rng)
+                .map(|dir| (*dir).clone())
+            else {
+                break;
+            };
+            let dir = parent.join(gen_file_name(rng, 8));
+            entries.push(Entry::new(dir.clone(), EntryType::Directory, 0o755, None));
+            if rng.gen_bool(OPAQUE_RATE) {
+                let opaque = dir.join(".wh..wh..opq");
+                entries.push(Entry::new(opaque, EntryType::Regular, 0o644, None));
+            }
+            dirs.push(dir);
+        }
+    }
+
+    let mut files: Vec<usize> = Vec::new();
+    for _ in 0..tree.files {
+        let dir = dirs
+            .choose(rng)
+            .expect("there is always the root directory");
+        let name = gen_file_name(rng, 10);
+        let target = files.choose(rng).map(|i| entries[*i].path.clone());
+        let entry = match (rng.gen::<f64>(), target) {
+            (p, Some(target)) if p < 0.1 => Entry::new(
+                dir.join(name),
+                EntryType::Symlink,
+                0o777,
+                Some(PathBuf::from("/").join(target)),
+            ),
+            (p, Some(target)) if p < 0.15 => {
+                Entry::new(dir.join(name), EntryType::Link, 0o644, Some(target))
+            }
+            (p, _) if p < 0.2 => Entry::new(
+                dir.join(format!(".wh.{name}")),
+                EntryType::Regular,
+                0o644,
+                None,
+            ),
+            _ => {
+                files.push(entries.len());
+                Entry::new(dir.join(name), EntryType::Regular, 0o644, None)
+            }
+        };
+        entries.push(entry);
+    }
+    // Only regular files carry the space, so there is at least one whatever the other entries.
+    if files.is_empty() {
+        let dir = dirs
+            .choose(rng)
+            .expect("there is always the root directory");
+        let name = gen_file_name(rng, 10);
+        files.push(entries.len());
+        entries.push(Entry::new(dir.join(name), EntryType::Regular, 0o644, None));
+    }
+
+    // Shares the space the headers leave among the files, in whole blocks but for the last file,
+    // with the heavy tail of real file sizes.
+    let headers: usize = entries.iter_mut().map(|e| e.header_bytes().len()).sum();
+    let mut space = size.saturating_sub(headers + TAR_END as usize) as u64;
+    let weights: Vec<f64> = files
+        .iter()
+        .map(|_| LogNormal::new(0.0, 2.0).map_or(1.0, |d| d.sample(rng)))
+        .collect();
+    let mut total: f64 = weights.iter().sum();
+    for (n, (i, weight)) in files.iter().zip(weights).enumerate() {
+        let size = if n + 1 == files.len() {
+            space
         } else {
-            layers.push(gen_tar_image_layer(size));
+            ((space as f64 * weight / total) as u64 / 512 * 512).min(space)
+        };
+        entries[*i].header.set_size(size);
+        space -= size;
+        total -= weight;
+    }
+
+    entries
+        .into_iter()
+        .map(|mut entry| {
+            let size = entry.header.size().unwrap_or(0);
+            (entry.header_bytes(), size)
+        })
+        .collect()
+}
+
+/// The size of the blocks of data [`TarReader`] generates at once.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Reads a generated tarball, generating the content of its files as it's read. The content is
+/// generated in blocks of the same size whatever the size of the reads, so that every reader of
+/// the same random number generator reads the same tarball.
+struct TarReader<R> {
+    entries: std::vec::IntoIter<(Vec<u8>, u64)>,
+    header: Vec<u8>,
+    header_read: usize,
+    rng: R,
+    compressibility: Compressibility,
+    block: Vec<u8>,
+    block_read: usize,
+    data: u64,
+    zeros: u64,
+    finished: bool,
+}
+
+impl<R: Rng> TarReader<R> {
+    fn new(mut rng: R, size: usize, compressibility: Compressibility, tree: Tree) -> TarReader<R> {
+        let entries = gen_entries(&mut rng, size, tree);
+        TarReader {
+            entries: entries.into_iter(),
+            header: Vec::new(),
+            header_read: 0,
+            rng,
+            compressibility,
+            block: vec![0; BLOCK_SIZE],
+            block_read: BLOCK_SIZE,
+            data: 0,
+            zeros: 0,
+            finished: false,
         }
     }
-    layers
 }
 
-pub fn gen_image(layers: Vec<ImageLayer>) -> Result<Image, OciDistributionError> {
+impl<R: Rng> Read for TarReader<R> {
+    #[allow(clippy::cast_possible_truncation)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.header_read < self.header.len() {
+                let n = (self.header.len() - self.header_read).min(buf.len());
+                buf[..n].copy_from_slice(&self.header[self.header_read..self.header_read + n]);
+                self.header_read += n;
+                return Ok(n);
+            }
+            if self.data > 0 {
+                if self.block_read == BLOCK_SIZE {
+                    self.compressibility.fill(&mut self.rng, &mut self.block);
+                    self.block_read = 0;
+                }
+                let n = (BLOCK_SIZE - self.block_read)
+                    .min(buf.len())
+                    .min(self.data.min(BLOCK_SIZE as u64) as usize);
+                buf[..n].copy_from_slice(&self.block[self.block_read..self.block_read + n]);
+                self.block_read += n;
+                self.data -= n as u64;
+                return Ok(n);
+            }
+            if self.zeros > 0 {
+                let n = buf.len().min(self.zeros.min(BLOCK_SIZE as u64) as usize);
+                buf[..n].fill(0);
+                self.zeros -= n as u64;
+                return Ok(n);
+            }
+            match self.entries.next() {
+                Some((header, size)) => {
+                    self.header = header;
+                    self.header_read = 0;
+                    self.data = size;
+                    self.zeros = (512 - size % 512) % 512;
+                }
+                None if !self.finished => {
+                    self.finished = true;
+                    self.zeros = TAR_END;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// A layer of a generated image, held in memory or streamed.
+#[derive(Clone)]
+pub enum Layer {
+    /// A layer held in memory, with its diff ID.
+    Memory { layer: ImageLayer, diff_id: String },
+    /// A layer generated as it's read.
+    Streamed(StreamedLayer),
+}
+
+impl Layer {
+    /// Returns the digest of the content.
+    pub fn digest(&self) -> String {
+        match self {
+            Layer::Memory { layer, .. } => layer.sha256_digest(),
+            Layer::Streamed(layer) => layer.digest().to_string(),
+        }
+    }
+
+    /// Returns the diff ID of the layer: the digest of its uncompressed tarball.
+    pub fn diff_id(&self) -> String {
+        match self {
+            Layer::Memory { diff_id, .. } => diff_id.clone(),
+            Layer::Streamed(layer) => layer.diff_id().to_string(),
+        }
+    }
+
+    /// Returns the length of the content in bytes.
+    pub fn len(&self) -> u64 {
+        match self {
+            Layer::Memory { layer, .. } => layer.data.len() as u64,
+            Layer::Streamed(layer) => layer.len(),
+        }
+    }
+
+    /// Returns the descriptor of the layer in a manifest.
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn descriptor(&self) -> OciDescriptor {
+        let media_type = match self {
+            Layer::Memory { layer, .. } => layer.media_type.clone(),
+            Layer::Streamed(layer) => layer.media_type().to_string(),
+        };
+        OciDescriptor {
+            media_type,
+            digest: self.digest(),
+            size: self.len() as i64,
+            urls: None,
+            annotations: None,
+        }
+    }
+}
+
+/// How [`gen_layers`] generates layers.
+#[derive(Debug, Clone, Copy)]
+pub struct LayerOptions<'a> {
+    /// The distribution of the sizes of the layers.
+    pub sizes: &'a Distribution,
+    /// The mix of compressions of the layers.
+    pub compression: &'a CompressionMix,
+    /// How well the content of the layers compresses.
+    pub compressibility: Compressibility,
+    /// The shape of the filesystem tree of the layers.
+    pub tree: Tree,
+    /// Whether to stream the layers instead of holding them in memory.
+    pub stream: bool,
+}
+
+/// Generates `count` layers the way `options` asks for.
+pub fn gen_layers(rng: &mut impl Rng, options: LayerOptions, count: usize) -> Vec<Layer> {
+    if !options.stream {
+        return gen_rand_layers(rng, options, count)
+            .into_iter()
+            .map(|(layer, diff_id)| Layer::Memory { layer, diff_id })
+            .collect();
+    }
+    (0..count)
+        .map(|_| {
+            let size = options.sizes.sample(rng);
+            let compression = options.compression.sample(rng);
+            Layer::Streamed(StreamedLayer::new(
+                rng,
+                size,
+                compression,
+                options.compressibility,
+                options.tree,
+            ))
+        })
+        .collect()
+}
+
+/// A pool of layers shared by generated images, so the pushes of different images share blobs.
+#[derive(Clone, Default)]
+pub struct LayerPool {
+    layers: Vec<Layer>,
+}
+
+impl LayerPool {
+    pub fn new(rng: &mut impl Rng, options: LayerOptions, count: usize) -> LayerPool {
+        LayerPool {
+            layers: gen_layers(rng, options, count),
+        }
+    }
+
+    /// Generates `count` layers the way `options` asks for. A fraction `shared` (0.0 to 1.0) of
+    /// them is picked from the pool without repetition, as far as the pool is large enough, and the
+    /// rest is new.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn gen_layers(
+        &self,
+        rng: &mut impl Rng,
+        options: LayerOptions,
+        count: usize,
+        shared: f64,
+    ) -> Vec<Layer> {
+        let shared = ((count as f64 * shared).round() as usize)
+            .min(count)
+            .min(self.layers.len());
+        let mut layers: Vec<Layer> = self.layers.choose_multiple(rng, shared).cloned().collect();
+        layers.extend(gen_layers(rng, options, count - shared));
+        layers
+    }
+}
+
+/// Generates the config of a linux/amd64 image of the layers of `diff_ids`, in order.
+fn gen_config(diff_ids: Vec<String>) -> Result<Config, OciDistributionError> {
     let config_file = ConfigFile {
         os: Os::Linux,
         architecture: Architecture::Amd64,
+        rootfs: Rootfs {
+            r#type: "layers".to_string(),
+            diff_ids,
+        },
         ..Default::default()
     };
 
-    let config = oci_distribution::client::Config::oci_v1_from_config_file(config_file, None)?;
+    Config::oci_v1_from_config_file(config_file, None)
+}
+
+/// Generates the config of a linux/amd64 image of `layers`, and its manifest.
+pub fn gen_manifest(layers: &[Layer]) -> Result<(Config, OciImageManifest), OciDistributionError> {
+    let config = gen_config(layers.iter().map(Layer::diff_id).collect())?;
+    let manifest = build_manifest(layers, &config);
+    Ok((config, manifest))
+}
+
+/// Generates the config of an image of `layers` for `platform`, and its manifest.
+pub fn gen_platform_manifest(
+    layers: &[Layer],
+    platform: &Platform,
+) -> Result<(Config, OciImageManifest), OciDistributionError> {
+    let diff_ids: Vec<String> = layers.iter().map(Layer::diff_id).collect();
+    let mut config_file = serde_json::json!({
+        "architecture": platform.architecture,
+        "os": platform.os,
+        "rootfs": { "type": "layers", "diff_ids": diff_ids },
+    });
+    if let Some(variant) = &platform.variant {
+        config_file["variant"] = variant.clone().into();
+    }
+    let config = Config::oci_v1(serde_json::to_vec(&config_file)?, None);
+    let manifest = build_manifest(layers, &config);
+    Ok((config, manifest))
+}
+
+fn build_manifest(layers: &[Layer], config: &Config) -> OciImageManifest {
+    let mut manifest = OciImageManifest::build(&[], config, None);
+    manifest.media_type = Some(OCI_IMAGE_MEDIA_TYPE.to_string());
+    manifest.layers = layers.iter().map(Layer::descriptor).collect();
+    manifest
+}
+
+/// Generates a linux/amd64 image of `layers`, given with their diff IDs.
+pub fn gen_image(layers: Vec<(ImageLayer, String)>) -> Result<Image, OciDistributionError> {
+    let (layers, diff_ids): (Vec<ImageLayer>, Vec<String>) = layers.into_iter().unzip();
+    let config = gen_config(diff_ids)?;
 
     let mut manifest =
         oci_distribution::manifest::OciImageManifest::build(layers.as_ref(), &config, None);
@@ -79,14 +658,8 @@ pub fn gen_image(layers: Vec<ImageLayer>) -> Result<Image, OciDistributionError>
     })
 }
 
-pub fn gen_file_data(size: usize) -> Vec<u8> {
-    let mut data: Vec<u8> = Vec::with_capacity(size);
-    rand::thread_rng().fill_bytes(&mut data);
-    data
-}
-
-pub fn gen_file_name(size: usize) -> String {
-    let filename: String = rand::thread_rng()
+pub fn gen_file_name(rng: &mut impl Rng, size: usize) -> String {
+    let filename: String = rng
         .sample_iter(&Alphanumeric)
         .take(size)
         .map(char::from)
@@ -94,14 +667,16 @@ pub fn gen_file_name(size: usize) -> String {
     filename
 }
 
-pub fn gen_file_path(segments: usize) -> PathBuf {
+pub fn gen_file_path(rng: &mut impl Rng, segments: usize) -> PathBuf {
     let mut path = PathBuf::new();
     for _ in 0..segments {
-        path.push(gen_file_name(10));
+        path.push(gen_file_name(rng, 10));
     }
     path
 }
 
+/// Generates an index of a single linux/ppc64le image which was never pushed, to check that
+/// registries accept indexes of missing images.
 pub fn gen_oci_image_index() -> OciImageIndex {
     OciImageIndex {
         schema_version: 2,
@@ -124,3 +699,306 @@ pub fn gen_oci_image_index() -> OciImageIndex {
         annotations: None,
     }
 }
+
+/// Generates an index of the images in `manifests`.
+pub fn gen_platform_image_index(manifests: Vec<ImageIndexEntry>) -> OciImageIndex {
+    OciImageIndex {
+        schema_version: 2,
+        media_type: Some(OCI_IMAGE_INDEX_MEDIA_TYPE.to_string()),
+        manifests,
+        annotations: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_rngs_are_reproducible() {
+        let gen = |seed, index| -> [u64; 4] { seeded_rng(seed, index).gen() };
+        assert_eq!(gen(42, 0), gen(42, 0));
+        assert_eq!(gen(42, 7), gen(42, 7));
+        assert_ne!(gen(42, 0), gen(42, 1));
+        assert_ne!(gen(42, 0), gen(43, 0));
+        // The seed and the index don't mix, so swapping them gives another generator.
+        assert_ne!(gen(1, 2), gen(2, 1));
+    }
+
+    #[test]
+    fn the_same_seed_generates_the_same_layers() {
+        let sizes = Distribution::Uniform {
+            min: 1024,
+            max: 64 * 1024,
+        };
+        let compression = CompressionMix::default();
+        let options = LayerOptions {
+            sizes: &sizes,
+            compression: &compression,
+            compressibility: Compressibility::Text,
+            tree: Tree::default(),
+            stream: false,
+        };
+        let digests = |seed| -> Vec<String> {
+            gen_layers(&mut seeded_rng(seed, 0), options, 5)
+                .iter()
+                .map(Layer::digest)
+                .collect()
+        };
+        assert_eq!(digests(1), digests(1));
+        assert_ne!(digests(1), digests(2));
+    }
+
+    #[test]
+    fn tar_files_have_the_asked_size() {
+        let mut rng = seeded_rng(1, 0);
+        for size in [4096, MEGABYTE, 3 * MEGABYTE + 512] {
+            let tar = gen_tar_file(&mut rng, size, Compressibility::Random, Tree::default());
+            assert_eq!(tar.len(), size);
+        }
+        // Sizes too small for the headers, or between blocks, are rounded up to whole blocks.
+        let tar = gen_tar_file(&mut rng, 10, Compressibility::Random, Tree::default());
+        assert_eq!(tar.len(), TAR_OVERHEAD);
+        let tar = gen_tar_file(&mut rng, 5000, Compressibility::Random, Tree::default());
+        assert_eq!(tar.len(), 5120);
+    }
+
+    #[test]
+    fn tar_files_hold_a_single_file_of_the_remaining_size() {
+        let mut rng = seeded_rng(1, 0);
+        let tar = gen_tar_file(&mut rng, MEGABYTE, Compressibility::Random, Tree::default());
+        let mut archive = tar::Archive::new(&tar[..]);
+        let entries: Vec<(PathBuf, u64)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.path().unwrap().into_owned(), entry.size())
+            })
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0.components().count(), 4);
+        assert_eq!(entries[0].1, (MEGABYTE - TAR_OVERHEAD) as u64);
+    }
+
+    /// Reads all of `reader` in reads of at most `len` bytes.
+    fn read_in(mut reader: impl Read, len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = vec![0; len];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => return data,
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn tar_reader_output_does_not_depend_on_read_sizes() {
+        let reader = || {
+            TarReader::new(
+                seeded_rng(1, 0),
+                BLOCK_SIZE * 3 + 1000,
+                Compressibility::Binary,
+                Tree { files: 8, depth: 3 },
+            )
+        };
+        let whole = read_in(reader(), BLOCK_SIZE * 4);
+        assert_eq!(read_in(reader(), 7), whole);
+        assert_eq!(read_in(reader(), 512), whole);
+        assert_eq!(read_in(reader(), BLOCK_SIZE + 1), whole);
+    }
+
+    #[test]
+    fn streamed_layers_read_the_same_content_every_time() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let layer = StreamedLayer::new(
+                &mut seeded_rng(1, 0),
+                MEGABYTE,
+                compression,
+                Compressibility::Text,
+                Tree::default(),
+            );
+            for len in [100, BLOCK_SIZE] {
+                let data = read_in(layer.reader(), len);
+                assert_eq!(data.len() as u64, layer.len());
+                assert_eq!(
+                    format!("sha256:{:x}", Sha256::digest(&data)),
+                    layer.digest()
+                );
+            }
+            assert_eq!(layer.media_type(), compression.media_type());
+        }
+    }
+
+    #[test]
+    fn streamed_layers_are_reproducible() {
+        let layer = |seed| {
+            StreamedLayer::new(
+                &mut seeded_rng(seed, 0),
+                MEGABYTE,
+                Compression::Gzip,
+                Compressibility::Random,
+                Tree::default(),
+            )
+        };
+        assert_eq!(layer(1).digest(), layer(1).digest());
+        assert_ne!(layer(1).digest(), layer(2).digest());
+    }
+
+    #[test]
+    fn trees_fill_the_layer_size_whatever_their_entries() {
+        // Two entries are both whiteouts now and then, which still have to make up the size.
+        for index in 0..200 {
+            let tar = gen_tar_file(
+                &mut seeded_rng(1, index),
+                64 * 1024,
+                Compressibility::Random,
+                Tree { files: 2, depth: 2 },
+            );
+            assert_eq!(tar.len(), 64 * 1024, "image {index}");
+        }
+    }
+
+    #[test]
+    fn trees_hold_every_kind_of_entry_within_the_depth() {
+        let tar = gen_tar_file(
+            &mut seeded_rng(1, 0),
+            4 * MEGABYTE,
+            Compressibility::Random,
+            Tree {
+                files: 200,
+                depth: 3,
+            },
+        );
+        assert_eq!(tar.len(), 4 * MEGABYTE);
+
+        let mut archive = tar::Archive::new(&tar[..]);
+        let mut kinds = std::collections::BTreeSet::new();
+        let mut size = 0;
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let kind = match entry.header().entry_type() {
+                EntryType::Directory => "directory",
+                EntryType::Symlink => "symlink",
+                EntryType::Link => "hardlink",
+                EntryType::Regular if name == ".wh..wh..opq" => "opaque",
+                EntryType::Regular if name.starts_with(".wh.") => "whiteout",
+                EntryType::Regular => "file",
+                kind => panic!("unexpected entry {kind:?}"),
+            };
+            kinds.insert(kind);
+            let depth = path.components().count();
+            match kind {
+                "directory" => assert!(depth <= 3, "{path:?}"),
+                _ => assert!(depth <= 4, "{path:?}"),
+            }
+            size += entry.size();
+        }
+        assert_eq!(
+            kinds.into_iter().collect::<Vec<_>>(),
+            [
+                "directory",
+                "file",
+                "hardlink",
+                "opaque",
+                "symlink",
+                "whiteout"
+            ]
+        );
+        assert!(size > 3 * MEGABYTE as u64);
+    }
+
+    #[test]
+    fn layer_pools_share_a_fraction_of_the_layers_up_to_their_size() {
+        let sizes = Distribution::Fixed(4096);
+        let compression = CompressionMix::default();
+        let options = LayerOptions {
+            sizes: &sizes,
+            compression: &compression,
+            compressibility: Compressibility::Random,
+            tree: Tree::default(),
+            stream: false,
+        };
+        let mut rng = seeded_rng(1, 0);
+        let pool = LayerPool::new(&mut rng, options, 4);
+        let pooled: Vec<String> = pool.layers.iter().map(Layer::digest).collect();
+        let shared = |count, fraction, rng: &mut StdRng| {
+            let layers = pool.gen_layers(rng, options, count, fraction);
+            assert_eq!(layers.len(), count);
+            let digests: Vec<String> = layers.iter().map(Layer::digest).collect();
+            let shared = digests.iter().filter(|d| pooled.contains(d)).count();
+            // Shared layers are picked without repetition.
+            let mut unique = digests.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), count);
+            shared
+        };
+
+        assert_eq!(shared(10, 0.0, &mut rng), 0);
+        assert_eq!(shared(4, 0.5, &mut rng), 2);
+        assert_eq!(shared(3, 1.0, &mut rng), 3);
+        // The pool caps the shared layers.
+        assert_eq!(shared(10, 0.8, &mut rng), 4);
+        assert_eq!(
+            LayerPool::default()
+                .gen_layers(&mut rng, options, 3, 1.0)
+                .len(),
+            3
+        );
+    }
+
+    /// Returns the digest of the uncompressed content of `layer`.
+    fn uncompressed_digest(layer: &Layer) -> String {
+        let data = match layer {
+            Layer::Memory { layer, .. } => layer.data.clone(),
+            Layer::Streamed(layer) => read_in(layer.reader(), BLOCK_SIZE),
+        };
+        let media_type = layer.descriptor().media_type;
+        let mut tar = Vec::new();
+        if media_type == Compression::Gzip.media_type() {
+            flate2::read::GzDecoder::new(&*data)
+                .read_to_end(&mut tar)
+                .unwrap();
+        } else if media_type == Compression::Zstd.media_type() {
+            tar = zstd::decode_all(&*data).unwrap();
+        } else {
+            tar = data;
+        }
+        format!("sha256:{:x}", Sha256::digest(&tar))
+    }
+
+    #[test]
+    fn configs_list_the_diff_ids_of_the_layers_in_order() {
+        let sizes = Distribution::Fixed(16 * 1024);
+        let compression: CompressionMix = "none,gzip,zstd".parse().unwrap();
+        for stream in [false, true] {
+            let options = LayerOptions {
+                sizes: &sizes,
+                compression: &compression,
+                compressibility: Compressibility::Text,
+                tree: Tree::default(),
+                stream,
+            };
+            let layers = gen_layers(&mut seeded_rng(1, 0), options, 6);
+            let diff_ids: Vec<String> = layers.iter().map(uncompressed_digest).collect();
+            assert_eq!(
+                diff_ids,
+                layers.iter().map(Layer::diff_id).collect::<Vec<_>>()
+            );
+
+            let platform = crate::cli::parse_platform("linux/arm64/v8").unwrap();
+            for (config, _) in [
+                gen_manifest(&layers).unwrap(),
+                gen_platform_manifest(&layers, &platform).unwrap(),
+            ] {
+                let config: serde_json::Value = serde_json::from_slice(&config.data).unwrap();
+                assert_eq!(config["rootfs"]["type"], "layers");
+                assert_eq!(config["rootfs"]["diff_ids"], serde_json::json!(diff_ids));
+            }
+        }
+    }
+}
@@ -1,8 +1,32 @@
 //! # Test framework for an OCI compliant registry.
 mod cli;
 mod client;
+mod compression;
+mod conformance;
+mod distribution;
+mod docker_config;
 mod fake;
 mod image;
+mod metrics;
+mod parse;
+mod registry;
+mod report;
+mod scheduler;
+mod slo;
 mod tester;
+mod token_server;
 
-pub use cli::{pull_images, push_image_index, push_images};
+pub use cli::{
+    conformance, mount_images, parse_platform, pull_images, push_image_index, push_images,
+    token_server, AuthOptions, NetworkOptions,
+};
+pub use client::{Connections, PullPlatform};
+pub use compression::{Compressibility, Compression, CompressionMix};
+pub use distribution::Distribution;
+pub use docker_config::default_path as docker_config_path;
+pub use parse::{parse_duration, parse_rate, parse_size};
+pub use registry::{RetryPolicy, Timeouts, UploadMode};
+pub use report::{ReportFormat, ReportOptions};
+pub use scheduler::Stage;
+pub use slo::Thresholds;
+pub use tester::{LoadOptions, PushOptions};
@@ -1,6 +1,8 @@
 //! # Load test an OCI compliant registry
 use anyhow::anyhow;
-use clap::{Parser, Subcommand};
+use std::{path::PathBuf, time::Duration};
+
+use clap::{Args, Parser, Subcommand};
 use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 
 /// The CLI Command.
@@ -17,6 +19,376 @@ struct Cli {
     verbose: bool,
 }
 
+/// How to authenticate against the OCI distribution server. Requests are anonymous by default.
+#[derive(Debug, Args)]
+#[group(multiple = false)]
+struct AuthArgs {
+    /// The user+password to authenticate against the OCI distribution server in the format user:password.
+    /// Sent with basic authentication, or exchanged for a token with the realm of the server.
+    /// Prefer the other options, which keep the password out of the shell history and process list.
+    #[arg(long = "reg-userpass", value_name = "REGISTRY_USERPASS")]
+    userpass: Option<String>,
+
+    /// A file holding the user+password in the format user:password.
+    #[arg(long = "reg-creds-file", value_name = "PATH")]
+    creds_file: Option<PathBuf>,
+
+    /// Read the user+password in the format user:password from stdin.
+    #[arg(long = "reg-creds-stdin")]
+    creds_stdin: bool,
+
+    /// Look the credentials of the server up in a Docker config file, with its credential
+    /// helpers, credential store or auths. Defaults to `$DOCKER_CONFIG/config.json` or
+    /// `~/.docker/config.json` when no PATH is given.
+    #[arg(long, value_name = "PATH", num_args = 0..=1)]
+    #[allow(clippy::option_option)]
+    docker_config: Option<Option<PathBuf>>,
+
+    /// A bearer token sent as is with every request, without asking the realm of the server for
+    /// one.
+    #[arg(long = "reg-token", value_name = "TOKEN")]
+    token: Option<String>,
+
+    /// An identity token, also known as a refresh token, exchanged for access tokens with the
+    /// realm of the server.
+    #[arg(long = "reg-identity-token", value_name = "TOKEN")]
+    identity_token: Option<String>,
+}
+
+impl From<AuthArgs> for oci_tester::AuthOptions {
+    fn from(args: AuthArgs) -> Self {
+        oci_tester::AuthOptions {
+            userpass: args.userpass,
+            token: args.token,
+            identity_token: args.identity_token,
+            creds_file: args.creds_file,
+            creds_stdin: args.creds_stdin,
+            docker_config: args
+                .docker_config
+                .map(|path| path.unwrap_or_else(oci_tester::docker_config_path)),
+        }
+    }
+}
+
+/// How to connect to the OCI distribution server: over HTTPS or plain HTTP, through which proxy,
+/// and how long requests may take.
+#[derive(Debug, Args)]
+struct NetworkArgs {
+    /// A PEM file of root certificates to trust on top of the built in ones, such as the CA of a
+    /// private registry. Can be repeated.
+    #[arg(long = "ca-cert", value_name = "PATH")]
+    ca_certs: Vec<PathBuf>,
+
+    /// A PEM file of the client certificate presented to servers asking for mutual TLS.
+    #[arg(long, value_name = "PATH", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// A PEM file of the private key of the client certificate.
+    #[arg(long, value_name = "PATH", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Accept any certificate from the server, even expired, self signed or for another host.
+    /// Only meant for lab environments.
+    #[arg(long)]
+    insecure_skip_verify: bool,
+
+    /// A host (with an optional port, as in the registry URL) reached over plain HTTP even though
+    /// its URL is HTTPS: a registry such as a mirror, or the host of the token realm or upload
+    /// locations a registry sends to. docker.io matches all the names of Docker Hub.
+    /// Can be repeated.
+    #[arg(long = "plain-http-host", value_name = "HOST")]
+    plain_http_hosts: Vec<String>,
+
+    /// The URL of a proxy every request goes through, such as `http://proxy:3128`. Defaults to
+    /// the proxies of `$HTTP_PROXY`, `$HTTPS_PROXY` and `$ALL_PROXY`. The hosts of `$NO_PROXY` are
+    /// reached directly either way.
+    #[arg(long, value_name = "PROXY_URL")]
+    proxy: Option<String>,
+
+    /// The longest wait to connect to the server. 0 waits forever.
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = oci_tester::parse_duration)]
+    connect_timeout: Duration,
+
+    /// The longest wait for the response headers of a request, from when it starts being sent.
+    /// With --min-throughput, the time the request body may take to upload is added on top. 0
+    /// waits forever.
+    #[arg(long, value_name = "DURATION", default_value = "0s", value_parser = oci_tester::parse_duration)]
+    first_byte_timeout: Duration,
+
+    /// The longest a whole request may take, the upload and download included. 0 waits forever.
+    #[arg(long, value_name = "DURATION", default_value = "0s", value_parser = oci_tester::parse_duration)]
+    request_timeout: Duration,
+
+    /// The least throughput, in bytes per second such as `100KiB`, a download or upload has to
+    /// keep up over every stall window, below which it's abandoned as stalled. Uploads are also
+    /// given a stall window plus the time to send them at this throughput, on top of the first
+    /// byte timeout, to be answered. Stalls aren't detected by default.
+    #[arg(long, value_name = "SIZE", value_parser = oci_tester::parse_size)]
+    min_throughput: Option<usize>,
+
+    /// The period the throughput of downloads and uploads is checked over.
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = oci_tester::parse_duration)]
+    stall_window: Duration,
+}
+
+impl From<NetworkArgs> for oci_tester::NetworkOptions {
+    fn from(args: NetworkArgs) -> Self {
+        oci_tester::NetworkOptions {
+            ca_certs: args.ca_certs,
+            client_cert: args.client_cert,
+            client_key: args.client_key,
+            insecure_skip_verify: args.insecure_skip_verify,
+            plain_http_hosts: args.plain_http_hosts,
+            proxy: args.proxy,
+            timeouts: oci_tester::Timeouts {
+                connect: Some(args.connect_timeout).filter(|t| !t.is_zero()),
+                first_byte: Some(args.first_byte_timeout).filter(|t| !t.is_zero()),
+                request: Some(args.request_timeout).filter(|t| !t.is_zero()),
+                min_throughput: args.min_throughput.map(|min| min as u64),
+                stall_window: args.stall_window,
+            },
+        }
+    }
+}
+
+/// How requests failing with a transient error are retried: a connection error, a timeout, or a
+/// 429, 502, 503 or 504 status.
+#[derive(Debug, Args)]
+struct RetryArgs {
+    /// The most times a request is retried. Requests aren't retried by default, so that every
+    /// transient failure fails its operation.
+    #[arg(long, value_name = "RETRIES", default_value_t = 0)]
+    retries: u32,
+
+    /// The delay before the first retry of a request, doubled for every retry after it and
+    /// jittered down by up to half. A `Retry-After` on a 429 or 503 is waited for instead.
+    #[arg(long, value_name = "DURATION", default_value = "100ms", value_parser = oci_tester::parse_duration)]
+    retry_backoff: Duration,
+
+    /// The longest delay between retries, unless the registry asks for longer with a
+    /// `Retry-After`.
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = oci_tester::parse_duration)]
+    retry_max_backoff: Duration,
+}
+
+impl From<RetryArgs> for oci_tester::RetryPolicy {
+    fn from(args: RetryArgs) -> Self {
+        oci_tester::RetryPolicy {
+            max_retries: args.retries,
+            initial_backoff: args.retry_backoff,
+            max_backoff: args.retry_max_backoff,
+        }
+    }
+}
+
+/// How the operations of a load test are scheduled and connect to the registry.
+#[derive(Debug, Args)]
+struct LoadArgs {
+    /// The maximum amount of operations in flight at once. Unbounded by default.
+    #[arg(long, value_name = "CONCURRENCY")]
+    concurrency: Option<usize>,
+
+    /// The amount of operations started per second. All operations start at once by default.
+    #[arg(long, value_name = "OPS_PER_SEC")]
+    rate: Option<f64>,
+
+    /// A stage of a time driven run, moving linearly to TARGET virtual users over DURATION.
+    /// Can be repeated to ramp up, hold and ramp down. Replaces --count.
+    /// Example: --stage 2m:200 --stage 11m:200 --stage 2m:0
+    #[arg(
+        long = "stage",
+        value_name = "DURATION:TARGET",
+        conflicts_with_all = ["count", "concurrency", "rate"]
+    )]
+    stages: Vec<oci_tester::Stage>,
+
+    /// Whether operations share a pool of connections and reuse tokens (warm), or each
+    /// connect and authenticate anew (cold).
+    #[arg(long, value_name = "CONNECTIONS", value_enum, default_value_t)]
+    connections: oci_tester::Connections,
+}
+
+impl From<LoadArgs> for oci_tester::LoadOptions {
+    fn from(args: LoadArgs) -> Self {
+        oci_tester::LoadOptions {
+            concurrency: args.concurrency,
+            rate: args.rate,
+            stages: args.stages,
+            connections: args.connections,
+            ..Default::default()
+        }
+    }
+}
+
+/// Where and how to write a machine readable report of the run.
+#[derive(Debug, Args)]
+struct ReportArgs {
+    /// Write a report of the run to this file.
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// The format of the report.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t)]
+    report_format: oci_tester::ReportFormat,
+}
+
+impl ReportArgs {
+    fn into_options(self) -> Option<oci_tester::ReportOptions> {
+        self.report.map(|path| oci_tester::ReportOptions {
+            path,
+            format: self.report_format,
+        })
+    }
+}
+
+/// The limits a run has to stay within to succeed.
+#[derive(Debug, Args)]
+struct ThresholdArgs {
+    /// The highest rate of failed operations allowed, as a fraction or a percentage.
+    /// By default any failure fails the run.
+    /// Example: 0.1%
+    #[arg(long, value_name = "RATE", value_parser = oci_tester::parse_rate)]
+    max_error_rate: Option<f64>,
+
+    /// The highest 99th percentile latency of successful operations allowed.
+    /// Example: 2s
+    #[arg(long, value_name = "DURATION", value_parser = oci_tester::parse_duration)]
+    max_p99: Option<Duration>,
+}
+
+impl From<ThresholdArgs> for oci_tester::Thresholds {
+    fn from(args: ThresholdArgs) -> Self {
+        oci_tester::Thresholds {
+            max_error_rate: args.max_error_rate,
+            max_p99: args.max_p99,
+        }
+    }
+}
+
+/// How images are pushed.
+#[derive(Debug, Args)]
+struct PushArgs {
+    /// How blobs are uploaded.
+    #[arg(long, value_name = "MODE", value_enum, default_value_t)]
+    upload_mode: oci_tester::UploadMode,
+
+    /// The size of the chunks blobs are uploaded in with --upload-mode chunked.
+    /// Example: 512KiB, 5MiB
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value = "5MiB",
+        value_parser = oci_tester::parse_size
+    )]
+    chunk_size: usize,
+
+    /// Check whether the registry already has a blob with a HEAD request before uploading it.
+    #[arg(long)]
+    check_blob_exists: bool,
+
+    /// Spread the images over this many repositories instead of one repository per image.
+    /// Example: <namespace>/<image>-<count % repositories>:<tag>-<count>
+    #[arg(long, value_name = "COUNT")]
+    repositories: Option<usize>,
+
+    /// The seed of the generated images. The same seed always pushes byte-identical images with
+    /// the same digests. A random seed is picked and logged by default.
+    #[arg(long, value_name = "SEED")]
+    seed: Option<u64>,
+}
+
+impl From<PushArgs> for oci_tester::PushOptions {
+    fn from(args: PushArgs) -> Self {
+        oci_tester::PushOptions {
+            upload_mode: args.upload_mode,
+            chunk_size: args.chunk_size,
+            check_exists: args.check_blob_exists,
+            repositories: args.repositories,
+            seed: args.seed,
+            ..oci_tester::PushOptions::default()
+        }
+    }
+}
+
+/// The layers of the pushed images.
+#[derive(Debug, Args)]
+struct LayerArgs {
+    /// The amount of layers of every image, as a number or a distribution like the layer size.
+    /// Example: 5, uniform:3..12
+    #[arg(long, value_name = "COUNT", default_value = "1")]
+    layers: oci_tester::Distribution,
+
+    /// The size of every layer, as a size or a distribution of sizes:
+    /// fixed:SIZE, uniform:MIN..MAX, lognormal:MEDIAN:SIGMA,
+    /// or histogram:FILE of "SIZE WEIGHT" lines sorted by size.
+    /// Example: 10MiB, uniform:1MiB..50MiB, lognormal:8MiB:1.5
+    #[arg(long, value_name = "DISTRIBUTION", default_value = "10MiB")]
+    layer_size: oci_tester::Distribution,
+
+    /// How layers are compressed: none, gzip, zstd, or a mix of them weighted like
+    /// COMPRESSION=WEIGHT,... where every layer is compressed a way picked in proportion to its
+    /// weight. Half of the layers are gzipped by default.
+    /// Example: zstd, gzip=3,zstd=1
+    #[arg(long, value_name = "MIX", default_value = "none,gzip")]
+    compression: oci_tester::CompressionMix,
+
+    /// How well the content of layers compresses: random like already compressed content,
+    /// binary (about 2:1), text (about 4:1), or a RATIO of at least 1.
+    /// Example: text, 3
+    #[arg(long, value_name = "COMPRESSIBILITY", default_value = "random")]
+    compressibility: oci_tester::Compressibility,
+
+    /// The amount of entries of every layer, directories aside. Layers of a single entry hold a
+    /// single file, and larger layers a tree of directories, files, symlinks, hardlinks, whiteouts
+    /// and opaque directories sharing the layer size.
+    #[arg(long, value_name = "COUNT", default_value_t = 1)]
+    files: usize,
+
+    /// The depth of the directories of the files of every layer. A single file is always this
+    /// deep, and the files of larger layers are at most this deep.
+    #[arg(long, value_name = "DEPTH", default_value_t = 3)]
+    depth: usize,
+
+    /// The fraction of the layers of every image picked from a pool of shared layers, as a
+    /// fraction or a percentage. All layers are new by default.
+    /// Example: 80%
+    #[arg(
+        long,
+        value_name = "RATE",
+        default_value = "0",
+        value_parser = oci_tester::parse_rate
+    )]
+    shared_layers: f64,
+
+    /// The amount of layers in the pool of shared layers.
+    #[arg(long, value_name = "COUNT", default_value_t = 10)]
+    layer_pool: usize,
+
+    /// Generate the content of layers as it's uploaded instead of in memory, to push layers of
+    /// many gigabytes in constant memory. Streamed layers are uploaded in chunks of --chunk-size
+    /// whatever the upload mode, and are read twice: once for their digest and once to upload them.
+    #[arg(long)]
+    stream_layers: bool,
+}
+
+impl LayerArgs {
+    /// Returns the push options of `push` and of these layers.
+    fn into_options(self, push: PushArgs) -> oci_tester::PushOptions {
+        oci_tester::PushOptions {
+            layers: self.layers,
+            layer_size: self.layer_size,
+            compression: self.compression,
+            compressibility: self.compressibility,
+            files: self.files,
+            depth: self.depth,
+            shared_layers: self.shared_layers,
+            layer_pool: self.layer_pool,
+            stream_layers: self.stream_layers,
+            ..push.into()
+        }
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     #[command(hide = true)]
@@ -36,9 +408,14 @@ enum Commands {
         )]
         reg_url: String,
 
-        /// The user+password to authenticate against the OCI distribution server in the format user:password.
-        #[arg(long, value_name = "REGISTRY_USERPASS")]
-        reg_userpass: Option<String>,
+        #[command(flatten)]
+        auth: AuthArgs,
+
+        #[command(flatten)]
+        network: NetworkArgs,
+
+        #[command(flatten)]
+        retry: RetryArgs,
 
         /// The image namespace. This will be used to generate the complete image.
         /// Example: <namespace>/<image>-<count>:<tag>
@@ -54,6 +431,78 @@ enum Commands {
         /// Example: <namespace>/<image>-<count>:<tag>
         #[arg(short, long, value_name = "IMAGE_TAG", default_value = "latest")]
         tag: String,
+
+        #[command(flatten)]
+        load: LoadArgs,
+
+        #[command(flatten)]
+        push: PushArgs,
+
+        #[command(flatten)]
+        layers: LayerArgs,
+
+        #[command(flatten)]
+        report: ReportArgs,
+
+        #[command(flatten)]
+        thresholds: ThresholdArgs,
+    },
+
+    /// Pushes a generated base image to an OCI distribution server once, then images derived from
+    /// it into other repositories, mounting the blobs they share with the base image. Derived
+    /// images add layers of their own on top of the layers of the base image.
+    #[command()]
+    MountImages {
+        /// The amount of derived images to push.
+        #[arg(short, long, value_name = "COUNT", default_value_t = 1)]
+        count: usize,
+
+        /// The OCI distribution server url.
+        #[arg(
+            long,
+            value_name = "REGISTRY_URL",
+            default_value = "http://localhost:6000"
+        )]
+        reg_url: String,
+
+        #[command(flatten)]
+        auth: AuthArgs,
+
+        #[command(flatten)]
+        network: NetworkArgs,
+
+        #[command(flatten)]
+        retry: RetryArgs,
+
+        /// The image namespace. This will be used to generate the complete images.
+        /// Example: <namespace>/<image>-base:<tag> and <namespace>/<image>-<count>:<tag>
+        #[arg(short, long, value_name = "IMAGE_NAMESPACE", default_value = "test")]
+        namespace: String,
+
+        /// The image name. This will be used to generate the complete images.
+        /// Example: <namespace>/<image>-base:<tag> and <namespace>/<image>-<count>:<tag>
+        #[arg(short, long, value_name = "IMAGE", default_value = "this")]
+        image: String,
+
+        /// The image tag. This will be used to generate the complete images.
+        /// Example: <namespace>/<image>-base:<tag> and <namespace>/<image>-<count>:<tag>
+        #[arg(short, long, value_name = "IMAGE_TAG", default_value = "latest")]
+        tag: String,
+
+        #[command(flatten)]
+        load: LoadArgs,
+
+        #[command(flatten)]
+        push: PushArgs,
+
+        #[command(flatten)]
+        layers: LayerArgs,
+
+        #[command(flatten)]
+        report: ReportArgs,
+
+        #[command(flatten)]
+        thresholds: ThresholdArgs,
     },
 
     /// Pulls OCI images from an OCI distribution server.
@@ -71,13 +520,42 @@ enum Commands {
         )]
         reg_url: String,
 
-        /// The user+password to authenticate against the OCI distribution server in the format user:password.
-        #[arg(long, value_name = "REGISTRY_USERPASS")]
-        reg_userpass: Option<String>,
+        #[command(flatten)]
+        auth: AuthArgs,
+
+        #[command(flatten)]
+        network: NetworkArgs,
+
+        #[command(flatten)]
+        retry: RetryArgs,
 
         /// The image to pull.
         #[arg(short, long, value_name = "IMAGE", default_value = "alpine:latest")]
         image: String,
+
+        /// The platform of the image to pull from an image index. Any variant matches a platform
+        /// without one.
+        /// Example: linux/arm64, linux/arm/v7
+        #[arg(
+            long,
+            value_name = "OS/ARCH[/VARIANT]",
+            default_value = "linux/amd64",
+            value_parser = oci_tester::parse_platform
+        )]
+        platform: oci_distribution::manifest::Platform,
+
+        /// Pull the images of every platform of an image index instead of a single one.
+        #[arg(long, conflicts_with = "platform")]
+        all_platforms: bool,
+
+        #[command(flatten)]
+        load: LoadArgs,
+
+        #[command(flatten)]
+        report: ReportArgs,
+
+        #[command(flatten)]
+        thresholds: ThresholdArgs,
     },
 
     PushImageList {
@@ -89,13 +567,118 @@ enum Commands {
         )]
         reg_url: String,
 
-        /// The user+password to authenticate against the OCI distribution server in the format user:password.
-        #[arg(long, value_name = "REGISTRY_USERPASS")]
-        reg_userpass: Option<String>,
+        #[command(flatten)]
+        auth: AuthArgs,
+
+        #[command(flatten)]
+        network: NetworkArgs,
+
+        #[command(flatten)]
+        retry: RetryArgs,
 
         /// Where to push the image list.
         #[arg(short, long, value_name = "IMAGE", default_value = "test/this:cache")]
         image: String,
+
+        /// The platforms to push an image for, by digest into the repository of the image list,
+        /// before pushing the image list of them. Without platforms, the image list references a
+        /// linux/ppc64le image which was never pushed.
+        /// Example: linux/amd64,linux/arm64/v8,windows/amd64
+        #[arg(
+            long = "platform",
+            value_name = "OS/ARCH[/VARIANT]",
+            value_delimiter = ',',
+            value_parser = oci_tester::parse_platform
+        )]
+        platforms: Vec<oci_distribution::manifest::Platform>,
+
+        #[command(flatten)]
+        push: PushArgs,
+
+        #[command(flatten)]
+        layers: LayerArgs,
+
+        #[command(flatten)]
+        report: ReportArgs,
+
+        #[command(flatten)]
+        thresholds: ThresholdArgs,
+    },
+
+    /// Checks an OCI distribution server against the distribution specification.
+    #[command()]
+    Conformance {
+        /// The OCI distribution server url.
+        #[arg(
+            long,
+            value_name = "REGISTRY_URL",
+            default_value = "http://localhost:6000"
+        )]
+        reg_url: String,
+
+        #[command(flatten)]
+        auth: AuthArgs,
+
+        #[command(flatten)]
+        network: NetworkArgs,
+
+        /// The repository the checks push to and delete from.
+        #[arg(
+            short,
+            long,
+            value_name = "REPOSITORY",
+            default_value = "conformance/test"
+        )]
+        repository: String,
+
+        #[command(flatten)]
+        report: ReportArgs,
+    },
+
+    /// Runs a mock token service in front of an OCI distribution server, to test token
+    /// authentication offline. Requests need a bearer token issued by the service, and are
+    /// forwarded to the server once authorized.
+    #[command()]
+    TokenServer {
+        /// The address to listen on. Point --reg-url at it.
+        #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:6001")]
+        listen: std::net::SocketAddr,
+
+        /// The OCI distribution server url authorized requests are forwarded to.
+        #[arg(
+            long,
+            value_name = "REGISTRY_URL",
+            default_value = "http://localhost:6000"
+        )]
+        upstream: String,
+
+        /// The user+password tokens are issued to, in the format user:password.
+        /// Tokens are issued to anyone by default.
+        #[arg(long, value_name = "USERPASS")]
+        userpass: Option<String>,
+
+        /// An identity token accepted in exchange for access tokens, on top of the refresh tokens
+        /// the service issues.
+        #[arg(long, value_name = "TOKEN")]
+        identity_token: Option<String>,
+
+        /// How long issued tokens are valid.
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "5m",
+            value_parser = oci_tester::parse_duration
+        )]
+        expires_in: Duration,
+
+        /// How long the service waits before answering token requests.
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "0s",
+            value_parser = oci_tester::parse_duration
+        )]
+        latency: Duration,
     },
 }
 
@@ -125,21 +708,156 @@ async fn main() -> anyhow::Result<()> {
         Commands::PullImages {
             reg_url,
             count,
-            reg_userpass,
+            auth,
+            network,
+            retry,
             image,
-        } => oci_tester::pull_images(reg_url, count, reg_userpass, image).await,
+            platform,
+            all_platforms,
+            load,
+            report,
+            thresholds,
+        } => {
+            let platform = if all_platforms {
+                oci_tester::PullPlatform::All
+            } else {
+                oci_tester::PullPlatform::Only(platform)
+            };
+            oci_tester::pull_images(
+                reg_url,
+                count,
+                auth.into(),
+                network.into(),
+                retry.into(),
+                image,
+                platform,
+                load.into(),
+                report.into_options(),
+                thresholds.into(),
+            )
+            .await
+        }
         Commands::PushImages {
             reg_url,
             count,
-            reg_userpass,
+            auth,
+            network,
+            retry,
             namespace,
             image,
             tag,
-        } => oci_tester::push_images(reg_url, count, reg_userpass, namespace, image, tag).await,
+            load,
+            push,
+            layers,
+            report,
+            thresholds,
+        } => {
+            let push = layers.into_options(push);
+            oci_tester::push_images(
+                reg_url,
+                count,
+                auth.into(),
+                network.into(),
+                retry.into(),
+                namespace,
+                image,
+                tag,
+                load.into(),
+                push,
+                report.into_options(),
+                thresholds.into(),
+            )
+            .await
+        }
+        Commands::MountImages {
+            reg_url,
+            count,
+            auth,
+            network,
+            retry,
+            namespace,
+            image,
+            tag,
+            load,
+            push,
+            layers,
+            report,
+            thresholds,
+        } => {
+            oci_tester::mount_images(
+                reg_url,
+                count,
+                auth.into(),
+                network.into(),
+                retry.into(),
+                namespace,
+                image,
+                tag,
+                load.into(),
+                layers.into_options(push),
+                report.into_options(),
+                thresholds.into(),
+            )
+            .await
+        }
         Commands::PushImageList {
             reg_url,
-            reg_userpass,
+            auth,
+            network,
+            retry,
             image,
-        } => oci_tester::push_image_index(reg_url, reg_userpass, image).await,
+            platforms,
+            push,
+            layers,
+            report,
+            thresholds,
+        } => {
+            oci_tester::push_image_index(
+                reg_url,
+                auth.into(),
+                network.into(),
+                retry.into(),
+                image,
+                layers.into_options(push),
+                platforms,
+                report.into_options(),
+                thresholds.into(),
+            )
+            .await
+        }
+        Commands::Conformance {
+            reg_url,
+            auth,
+            network,
+            repository,
+            report,
+        } => {
+            oci_tester::conformance(
+                reg_url,
+                auth.into(),
+                network.into(),
+                repository,
+                report.into_options(),
+            )
+            .await
+        }
+        Commands::TokenServer {
+            listen,
+            upstream,
+            userpass,
+            identity_token,
+            expires_in,
+            latency,
+        } => {
+            oci_tester::token_server(
+                listen,
+                upstream,
+                userpass,
+                identity_token,
+                expires_in,
+                latency,
+            )
+            .await
+        }
     }
 }
@@ -0,0 +1,277 @@
+//! Timing of the individual registry operations performed during a load test.
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use hdrhistogram::Histogram;
+
+/// A registry operation that is timed on its own.
+///
+/// A `HEAD` of a blob is a `BlobExists` when the registry has the blob and a `BlobMissing` when it
+/// doesn't, in which case the blob is uploaded. A mount the registry answers by starting an upload
+/// instead is a `BlobMountFallback`, followed by the `BlobUpload` of the blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    ImagePush,
+    ImagePull,
+    Auth,
+    BlobUpload,
+    BlobChunkUpload,
+    BlobExists,
+    BlobMissing,
+    BlobMount,
+    BlobMountFallback,
+    BlobGet,
+    ManifestPut,
+    ManifestGet,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::ImagePush => write!(f, "image-push"),
+            Operation::ImagePull => write!(f, "image-pull"),
+            Operation::Auth => write!(f, "auth"),
+            Operation::BlobUpload => write!(f, "blob-upload"),
+            Operation::BlobChunkUpload => write!(f, "blob-chunk-upload"),
+            Operation::BlobExists => write!(f, "blob-exists"),
+            Operation::BlobMissing => write!(f, "blob-missing"),
+            Operation::BlobMount => write!(f, "blob-mount"),
+            Operation::BlobMountFallback => write!(f, "blob-mount-fallback"),
+            Operation::BlobGet => write!(f, "blob-get"),
+            Operation::ManifestPut => write!(f, "manifest-put"),
+            Operation::ManifestGet => write!(f, "manifest-get"),
+        }
+    }
+}
+
+/// How long a single registry operation took.
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    pub operation: Operation,
+    pub elapsed: Duration,
+    /// The amount of bytes sent or received by the operation.
+    pub bytes: u64,
+    pub success: bool,
+}
+
+/// Collects the timings of the registry operations performed by a single load test operation,
+/// counts the requests it retried, and marks when it started.
+#[derive(Debug, Clone, Default)]
+pub struct Timer {
+    timings: Arc<Mutex<Vec<Timing>>>,
+    retries: Arc<AtomicU32>,
+    started: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Timer {
+    /// Records a timing.
+    pub fn record(&self, timing: Timing) {
+        self.timings
+            .lock()
+            .expect("timer lock is never poisoned")
+            .push(timing);
+    }
+
+    /// Times `fut` as `operation`, which sends or receives `bytes`.
+    pub async fn time<T, E>(
+        &self,
+        operation: Operation,
+        bytes: u64,
+        fut: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let start = Instant::now();
+        let result = fut.await;
+        self.record(Timing {
+            operation,
+            elapsed: start.elapsed(),
+            bytes,
+            success: result.is_ok(),
+        });
+        result
+    }
+
+    /// Counts a retried request.
+    pub fn retried(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the amount of requests retried so far.
+    pub fn retries(&self) -> u32 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Marks the operation as started, once what it sends has been generated, so that generating
+    /// it isn't part of its latency.
+    pub fn start(&self) {
+        *self.started.lock().expect("timer lock is never poisoned") = Some(Instant::now());
+    }
+
+    /// Returns when the operation was marked as started, if it was.
+    pub fn started(&self) -> Option<Instant> {
+        *self.started.lock().expect("timer lock is never poisoned")
+    }
+
+    /// Takes the timings recorded so far.
+    pub fn take(&self) -> Vec<Timing> {
+        std::mem::take(&mut *self.timings.lock().expect("timer lock is never poisoned"))
+    }
+}
+
+/// The highest latency histograms track, in microseconds. Higher latencies are recorded as this.
+const MAX_LATENCY_MICROS: u64 = 60 * 60 * 1_000_000;
+
+/// Creates a histogram for latencies in microseconds.
+///
+/// The bounds are fixed up front, because `saturating_record` clamps to the current bounds of an
+/// auto resizing histogram instead of growing it.
+pub fn latency_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, 3).expect("the latency bounds are valid")
+}
+
+/// Latency and throughput statistics of a single kind of operation.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// The latency of successful operations, in microseconds.
+    pub latency: Histogram<u64>,
+    pub success: u64,
+    pub errors: u64,
+    /// The amount of bytes sent or received by successful operations.
+    pub bytes: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            latency: latency_histogram(),
+            success: 0,
+            errors: 0,
+            bytes: 0,
+        }
+    }
+}
+
+impl Stats {
+    /// Adds a timing to the statistics.
+    pub fn add(&mut self, timing: &Timing) {
+        if timing.success {
+            self.success += 1;
+            self.bytes += timing.bytes;
+            self.latency
+                .saturating_record(u64::try_from(timing.elapsed.as_micros()).unwrap_or(u64::MAX));
+        } else {
+            self.errors += 1;
+        }
+    }
+
+    /// Returns the latency at `quantile` (0.0 to 1.0).
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        Duration::from_micros(self.latency.value_at_quantile(quantile))
+    }
+
+    pub fn min(&self) -> Duration {
+        Duration::from_micros(self.latency.min())
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn mean(&self) -> Duration {
+        Duration::from_micros(self.latency.mean() as u64)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.latency.max())
+    }
+
+    /// Returns the amount of successful operations per second over `elapsed`.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn ops_per_sec(&self, elapsed: Duration) -> f64 {
+        self.success as f64 / elapsed.as_secs_f64()
+    }
+
+    /// Returns the amount of megabytes transferred per second over `elapsed`.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn megabytes_per_sec(&self, elapsed: Duration) -> f64 {
+        self.bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    }
+}
+
+/// Groups timings into statistics per operation.
+pub fn stats<'a>(timings: impl IntoIterator<Item = &'a Timing>) -> BTreeMap<Operation, Stats> {
+    let mut stats: BTreeMap<Operation, Stats> = BTreeMap::new();
+    for timing in timings {
+        stats.entry(timing.operation).or_default().add(timing);
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(operation: Operation, elapsed: Duration, success: bool) -> Timing {
+        Timing {
+            operation,
+            elapsed,
+            bytes: 1024 * 1024,
+            success,
+        }
+    }
+
+    #[test]
+    fn stats_are_grouped_by_operation() {
+        let timings = [
+            timing(Operation::BlobUpload, Duration::from_millis(10), true),
+            timing(Operation::BlobUpload, Duration::from_millis(30), false),
+            timing(Operation::ManifestPut, Duration::from_millis(5), true),
+        ];
+        let stats = stats(&timings);
+        assert_eq!(stats.len(), 2);
+
+        let upload = &stats[&Operation::BlobUpload];
+        assert_eq!((upload.success, upload.errors), (1, 1));
+        assert_eq!(upload.bytes, 1024 * 1024);
+        assert_eq!(upload.latency.len(), 1);
+        assert!((upload.megabytes_per_sec(Duration::from_secs(2)) - 0.5).abs() < f64::EPSILON);
+        assert!((upload.ops_per_sec(Duration::from_secs(2)) - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn latencies_keep_their_precision() {
+        let mut stats = Stats::default();
+        for ms in 1..=100 {
+            stats.add(&timing(Operation::BlobGet, Duration::from_millis(ms), true));
+        }
+        let within = |actual: Duration, expected: Duration| {
+            actual.abs_diff(expected) <= expected / 1000 + Duration::from_micros(1)
+        };
+        assert!(within(stats.min(), Duration::from_millis(1)));
+        assert!(within(stats.quantile(0.5), Duration::from_millis(50)));
+        assert!(within(stats.quantile(0.99), Duration::from_millis(99)));
+        assert!(within(stats.max(), Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn long_latencies_are_not_clamped() {
+        let mut stats = Stats::default();
+        stats.add(&timing(
+            Operation::ImagePush,
+            Duration::from_millis(1),
+            true,
+        ));
+        stats.add(&timing(Operation::ImagePush, Duration::from_mins(10), true));
+        let max = stats.max();
+        assert!(max >= Duration::from_secs(599) && max <= Duration::from_secs(601));
+
+        // Beyond the bounds, latencies are recorded as the highest one tracked.
+        stats.add(&timing(Operation::ImagePush, Duration::from_hours(2), true));
+        assert!(stats.max() >= Duration::from_micros(MAX_LATENCY_MICROS));
+        assert_eq!(stats.success, 3);
+    }
+}
@@ -0,0 +1,154 @@
+//! Parsing of the durations, rates and sizes given on the command line and in files.
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Parses a duration such as `90s`, `2m`, `1h30m` or `250ms`. A bare number is read as seconds.
+///
+/// # Errors
+///
+/// * If the duration is empty
+/// * If a number is missing its unit or the unit is unknown
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let duration = duration.trim();
+    if duration.is_empty() {
+        bail!("duration is empty");
+    }
+    if let Ok(secs) = duration.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(|| anyhow!("missing unit in duration: {duration}"))?;
+        let value: u64 = rest[..digits]
+            .parse()
+            .with_context(|| format!("invalid duration: {duration}"))?;
+        rest = &rest[digits..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        total += match &rest[..unit_len] {
+            "h" => Duration::from_secs(value * 60 * 60),
+            "m" => Duration::from_secs(value * 60),
+            "s" => Duration::from_secs(value),
+            "ms" => Duration::from_millis(value),
+            unit => bail!("unknown unit {unit} in duration: {duration}"),
+        };
+        rest = &rest[unit_len..];
+    }
+    Ok(total)
+}
+
+/// Parses a rate given as a fraction such as `0.001`, or as a percentage such as `0.1%`.
+///
+/// # Errors
+///
+/// * If the rate is not a number
+/// * If the rate is not between 0 and 1 (0% and 100%)
+pub fn parse_rate(rate: &str) -> Result<f64> {
+    let parsed = match rate.trim().strip_suffix('%') {
+        Some(percentage) => percentage.trim().parse::<f64>().map(|p| p / 100.0),
+        None => rate.trim().parse::<f64>(),
+    }
+    .with_context(|| format!("invalid rate: {rate}"))?;
+    if !(0.0..=1.0).contains(&parsed) {
+        bail!("rate must be between 0 and 1 (0% and 100%): {rate}");
+    }
+    Ok(parsed)
+}
+
+/// Parses a size in bytes such as `512KiB`, `5MiB` or `1GiB`. Units are powers of 1024, so
+/// `MB` and `MiB` are the same, and a bare number is read as bytes.
+///
+/// # Errors
+///
+/// * If the size is not a number followed by an optional unit
+/// * If the unit is unknown
+pub fn parse_size(size: &str) -> Result<usize> {
+    let trimmed = size.trim();
+    let digits = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let value: usize = trimmed[..digits]
+        .parse()
+        .with_context(|| format!("invalid size: {size}"))?;
+    let multiplier: usize = match trimmed[digits..].trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        unit => bail!("unknown unit {unit} in size: {size}"),
+    };
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("size is too large: {size}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_reads_units() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_mins(2));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_mins(90));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration(" 1m30s ").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("0s").unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn parse_duration_rejects_malformed_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("1m30").is_err());
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("-5s").is_err());
+    }
+
+    #[test]
+    fn parse_rate_reads_fractions_and_percentages() {
+        assert!((parse_rate("0.001").unwrap() - 0.001).abs() < f64::EPSILON);
+        assert!((parse_rate("0.1%").unwrap() - 0.001).abs() < f64::EPSILON);
+        assert!((parse_rate(" 80 % ").unwrap() - 0.8).abs() < f64::EPSILON);
+        assert!(parse_rate("0").unwrap().abs() < f64::EPSILON);
+        assert!((parse_rate("100%").unwrap() - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn parse_rate_rejects_rates_out_of_range() {
+        assert!(parse_rate("1.5").is_err());
+        assert!(parse_rate("-0.1").is_err());
+        assert!(parse_rate("101%").is_err());
+        assert!(parse_rate("half").is_err());
+        assert!(parse_rate("").is_err());
+    }
+
+    #[test]
+    fn parse_size_reads_units_of_1024() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("512B").unwrap(), 512);
+        assert_eq!(parse_size("512KiB").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("5MB").unwrap(), 5 * 1024 * 1024);
+        assert_eq!(parse_size("5 mib").unwrap(), 5 * 1024 * 1024);
+        assert_eq!(parse_size("1G").unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_size(" 0 ").unwrap(), 0);
+    }
+
+    #[test]
+    fn parse_size_rejects_malformed_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MiB").is_err());
+        assert!(parse_size("1.5MiB").is_err());
+        assert!(parse_size("5TiB").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size(&format!("{}GiB", usize::MAX)).is_err());
+    }
+}
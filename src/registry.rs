@@ -0,0 +1,1443 @@
+//! A client for the OCI distribution HTTP API, for the requests `oci_distribution` doesn't let us
+//! perform or time one by one.
+use std::{
+    fmt::Display,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use hyper::body::Bytes;
+use oci_distribution::{
+    client::ClientProtocol,
+    errors::OciDistributionError,
+    manifest::{
+        OciImageIndex, OciImageManifest, OciManifest, IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+        IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+    },
+};
+use rand::Rng;
+use reqwest::{
+    header, Certificate, Identity, Method, Proxy, RequestBuilder, Response, StatusCode, Url,
+};
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument};
+
+use crate::metrics::Timer;
+
+type Result<T> = std::result::Result<T, OciDistributionError>;
+
+/// How blobs are uploaded to a registry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UploadMode {
+    /// A POST to start the upload followed by a single PUT of the whole blob.
+    #[default]
+    Monolithic,
+    /// A POST to start the upload, a PATCH per chunk and a PUT to complete it.
+    Chunked,
+}
+
+impl Display for UploadMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadMode::Monolithic => write!(f, "monolithic"),
+            UploadMode::Chunked => write!(f, "chunked"),
+        }
+    }
+}
+
+/// How the HTTP connections to registries are made.
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    /// Root certificates trusted on top of the built in ones, such as the one of a private CA.
+    pub root_certificates: Vec<Certificate>,
+    /// The client certificate and key presented to registries asking for mutual TLS.
+    pub identity: Option<Identity>,
+    /// Whether to accept any certificate, even invalid or self-signed ones. Only meant for lab
+    /// environments.
+    pub accept_invalid_certificates: bool,
+    /// A proxy every request goes through. The proxies of the environment are used when `None`.
+    pub proxy: Option<Proxy>,
+    /// How requests failing with a transient error are retried.
+    pub retry: RetryPolicy,
+    /// How long requests may take before they time out.
+    pub timeouts: Timeouts,
+}
+
+/// How long requests may take before they fail with a timeout. Nothing times out when `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// The longest wait to connect to a registry.
+    pub connect: Option<Duration>,
+    /// The longest wait for the response headers of a request, from when it starts being sent.
+    /// The time allowed to upload the body of the request at the minimum throughput is added on
+    /// top.
+    pub first_byte: Option<Duration>,
+    /// The longest a whole request may take, the transfer of both bodies included.
+    pub request: Option<Duration>,
+    /// The least bytes per second a download or upload has to keep up over every stall window,
+    /// below which it is abandoned as stalled. The time to upload at this throughput is also
+    /// added to the first byte timeout.
+    pub min_throughput: Option<u64>,
+    /// The period the throughput of transfers is checked over.
+    pub stall_window: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Some(Duration::from_secs(30)),
+            first_byte: None,
+            request: None,
+            min_throughput: None,
+            stall_window: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Returns the longest wait for the response headers of a request uploading `len` bytes.
+    #[allow(clippy::cast_precision_loss)]
+    fn headers(&self, len: usize) -> Option<Duration> {
+        let upload = self
+            .min_throughput
+            .filter(|_| len > 0)
+            .map(|min| self.stall_window + Duration::from_secs_f64(len as f64 / min.max(1) as f64));
+        match (self.first_byte, upload) {
+            (Some(first_byte), Some(upload)) => Some(first_byte + upload),
+            (first_byte, upload) => first_byte.or(upload),
+        }
+    }
+}
+
+/// Returns an error for a request which timed out, which load tests report as a timeout.
+fn timed_out(message: String) -> OciDistributionError {
+    OciDistributionError::IoError(io::Error::new(io::ErrorKind::TimedOut, message))
+}
+
+/// The size of the pieces uploads are streamed in when their throughput is checked.
+const UPLOAD_PIECE: usize = 16 * 1024;
+
+/// The statuses of transient failures, after which a request is retried.
+const RETRY_STATUSES: [StatusCode; 4] = [
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// How requests failing with a transient error are retried: a connection error, a timeout, or a
+/// 429, 502, 503 or 504 status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The most times a request is retried. Requests aren't retried when 0.
+    pub max_retries: u32,
+    /// The delay before the first retry, doubled for every retry after it.
+    pub initial_backoff: Duration,
+    /// The longest delay between retries, unless the registry asks for longer with a
+    /// `Retry-After` on a 429 or 503.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait before the retry following `attempt` (from 0). The exponential
+    /// backoff is jittered down by up to half, so that failed clients don't retry in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        backoff / 2 + backoff.mul_f64(rand::thread_rng().gen_range(0.0..=0.5))
+    }
+}
+
+impl HttpOptions {
+    /// Builds an HTTP client with a connection pool of its own.
+    ///
+    /// # Errors
+    ///
+    /// * If the TLS configuration is rejected, such as for a key which doesn't match the
+    ///   certificate
+    /// * If the proxy is rejected
+    pub fn client(&self) -> reqwest::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .danger_accept_invalid_certs(self.accept_invalid_certificates);
+        if let Some(connect) = self.timeouts.connect {
+            builder = builder.connect_timeout(connect);
+        }
+        if let Some(request) = self.timeouts.request {
+            builder = builder.timeout(request);
+        }
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        builder.build()
+    }
+}
+
+/// The credentials to authenticate against a registry with.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub enum Credentials {
+    /// No credentials. Registries asking for a token still get an anonymous one from their realm.
+    #[default]
+    Anonymous,
+    /// A user and a password, sent with basic authentication or exchanged for a token with the
+    /// realm of the registry.
+    Basic(String, String),
+    /// A bearer token, sent as is without asking the registry for one.
+    Bearer(String),
+    /// An identity token, also known as a refresh token, exchanged for an access token with the
+    /// realm of the registry, following OAuth 2.0.
+    IdentityToken(String),
+}
+
+/// Keeps secrets out of logs.
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Anonymous => write!(f, "Anonymous"),
+            Credentials::Basic(user, _) => write!(f, "Basic({user}, ***)"),
+            Credentials::Bearer(_) => write!(f, "Bearer(***)"),
+            Credentials::IdentityToken(_) => write!(f, "IdentityToken(***)"),
+        }
+    }
+}
+
+/// The credentials sent along with every request once authenticated.
+#[derive(Debug, Clone)]
+enum Authorization {
+    Basic(String, String),
+    Bearer(String),
+}
+
+/// A `WWW-Authenticate` challenge returned by a registry.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Challenge {
+    scheme: String,
+    realm: Option<String>,
+    service: Option<String>,
+}
+
+impl Challenge {
+    /// Parses a challenge such as `Bearer realm="https://auth.example.com/token",service="registry"`.
+    fn parse(value: &str) -> Challenge {
+        let (scheme, params) = value.trim().split_once(' ').unwrap_or((value.trim(), ""));
+        let mut challenge = Challenge {
+            scheme: scheme.to_ascii_lowercase(),
+            ..Challenge::default()
+        };
+        for param in params.split(',') {
+            let Some((key, value)) = param.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"').to_string();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "realm" => challenge.realm = Some(value),
+                "service" => challenge.service = Some(value),
+                _ => {}
+            }
+        }
+        challenge
+    }
+}
+
+/// What a registry did when asked to mount a blob from another repository.
+#[derive(Debug)]
+pub enum Mount {
+    /// The blob was mounted, at this location.
+    Mounted(String),
+    /// The blob wasn't mounted and an upload was started at this location instead.
+    Upload(Url),
+}
+
+/// The OAuth 2.0 client ID sent when exchanging identity tokens.
+const CLIENT_ID: &str = env!("CARGO_PKG_NAME");
+
+/// The manifests accepted when pulling, OCI and Docker images and indexes.
+const MANIFEST_MEDIA_TYPES: [&str; 4] = [
+    OCI_IMAGE_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE,
+    IMAGE_MANIFEST_MEDIA_TYPE,
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+];
+
+/// How long bearer tokens are valid when the token response doesn't say.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_mins(1);
+
+/// The most a token is considered expired before the end of its lifetime, so that requests still
+/// in flight when it's renewed don't fail with a 401.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(5);
+
+/// Returns how long a token valid for `lifetime` is used for: a tenth of its lifetime, up to
+/// [`TOKEN_EXPIRY_MARGIN`], before it expires.
+fn usable_lifetime(lifetime: Duration) -> Duration {
+    lifetime.saturating_sub((lifetime / 10).min(TOKEN_EXPIRY_MARGIN))
+}
+
+/// Returns the token scope for `actions` (such as `pull,push`) on `repository`.
+pub fn scope(repository: &str, actions: &str) -> String {
+    format!("repository:{repository}:{actions}")
+}
+
+/// A client for a single registry.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    http: reqwest::Client,
+    base_url: String,
+    /// The hosts reached over plain HTTP even when their URLs are HTTPS.
+    plain_http_hosts: Vec<String>,
+    credentials: Credentials,
+    authorization: Option<Authorization>,
+    expires: Option<Instant>,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+    /// Counts the retries of the load test operation the client is used by.
+    timer: Timer,
+}
+
+impl Registry {
+    /// Creates a client for `registry` (a host with an optional port) which sends its requests
+    /// with `http`, sharing the connections of its pool. See [`HttpOptions::client`].
+    ///
+    /// The hosts of [`ClientProtocol::HttpsExcept`] are reached over plain HTTP, whether they are
+    /// the registry or the hosts of the token realms and upload locations the registry sends the
+    /// client to.
+    pub fn new(
+        http: reqwest::Client,
+        protocol: &ClientProtocol,
+        registry: &str,
+        credentials: Credentials,
+    ) -> Registry {
+        let plain_http_hosts = match protocol {
+            ClientProtocol::HttpsExcept(hosts) => hosts.clone(),
+            _ => Vec::new(),
+        };
+        let scheme = match protocol {
+            ClientProtocol::Http => "http",
+            _ if is_plain_http(&plain_http_hosts, registry) => "http",
+            _ => "https",
+        };
+        Registry {
+            http,
+            base_url: format!("{scheme}://{registry}"),
+            plain_http_hosts,
+            credentials,
+            authorization: None,
+            expires: None,
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            timer: Timer::default(),
+        }
+    }
+
+    /// Retries and times out the requests of the client as `options` ask, counting the retries
+    /// with `timer`. The connect and request timeouts are those of the HTTP client.
+    #[must_use]
+    pub fn with_options(mut self, options: &HttpOptions, timer: &Timer) -> Registry {
+        self.retry = options.retry;
+        self.timeouts = options.timeouts;
+        self.timer = timer.clone();
+        self
+    }
+
+    /// Returns whether the token obtained by [`Registry::authenticate`] has expired, or is about
+    /// to, so that the client has to authenticate again.
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| Instant::now() >= expires)
+    }
+
+    /// Authenticates for the token `scopes` (see [`scope`]), following the challenge returned by
+    /// the registry's `/v2/` endpoint. A [`Credentials::Bearer`] token is used as is, without
+    /// any request.
+    ///
+    /// # Errors
+    ///
+    /// * If the registry can't be reached
+    /// * If the registry rejects the credentials
+    #[instrument(level = "trace", skip(self))]
+    pub async fn authenticate(&mut self, scopes: &[String]) -> Result<()> {
+        if let Credentials::Bearer(token) = &self.credentials {
+            self.authorization = Some(Authorization::Bearer(token.clone()));
+            return Ok(());
+        }
+
+        let url = format!("{}/v2/", self.base_url);
+        let res = self.send(self.http.get(&url)).await?;
+        if res.status() != StatusCode::UNAUTHORIZED {
+            debug!("Registry doesn't require authentication");
+            return Ok(());
+        }
+
+        let challenge = res
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .map(|v| v.to_str().map(Challenge::parse))
+            .transpose()?
+            .ok_or_else(|| OciDistributionError::UnauthorizedError { url: url.clone() })?;
+
+        self.authorization = match (challenge.scheme.as_str(), &self.credentials) {
+            ("basic", Credentials::Basic(user, password)) => {
+                Some(Authorization::Basic(user.clone(), password.clone()))
+            }
+            ("basic", _) => {
+                return Err(OciDistributionError::AuthenticationFailure(format!(
+                    "{url} requires basic authentication"
+                )));
+            }
+            ("bearer", _) => {
+                let (token, lifetime) = self.fetch_token(&challenge, scopes).await?;
+                self.expires = Some(Instant::now() + usable_lifetime(lifetime));
+                Some(Authorization::Bearer(token))
+            }
+            (scheme, _) => {
+                return Err(OciDistributionError::AuthenticationFailure(format!(
+                    "unsupported authentication scheme {scheme} for {url}"
+                )));
+            }
+        };
+        Ok(())
+    }
+
+    /// Fetches a token from the realm of a bearer challenge, returning it with how long it's valid.
+    /// Identity tokens are exchanged with an OAuth 2.0 `POST`, and anything else with a `GET`.
+    async fn fetch_token(
+        &self,
+        challenge: &Challenge,
+        scopes: &[String],
+    ) -> Result<(String, Duration)> {
+        let realm = challenge.realm.as_deref().ok_or_else(|| {
+            OciDistributionError::AuthenticationFailure("bearer challenge without realm".into())
+        })?;
+        let realm = self.resolve(realm)?;
+        let realm = realm.as_str();
+
+        let req = if let Credentials::IdentityToken(token) = &self.credentials {
+            let mut form = vec![
+                ("grant_type", "refresh_token".to_string()),
+                ("refresh_token", token.clone()),
+                ("client_id", CLIENT_ID.to_string()),
+                ("scope", scopes.join(" ")),
+            ];
+            if let Some(service) = &challenge.service {
+                form.push(("service", service.clone()));
+            }
+            self.http.post(realm).form(&form)
+        } else {
+            let mut req = self.http.get(realm);
+            for scope in scopes {
+                req = req.query(&[("scope", scope)]);
+            }
+            if let Some(service) = &challenge.service {
+                req = req.query(&[("service", service)]);
+            }
+            if let Credentials::Basic(user, password) = &self.credentials {
+                req = req.basic_auth(user, Some(password));
+            }
+            req
+        };
+
+        let res = check(self.send(req).await?).await?;
+        let body: serde_json::Value = serde_json::from_slice(&self.read(res).await?)?;
+        let token = body
+            .get("token")
+            .or_else(|| body.get("access_token"))
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| {
+                OciDistributionError::AuthenticationFailure(format!(
+                    "token response from {realm} has no token"
+                ))
+            })?;
+        let lifetime = body
+            .get("expires_in")
+            .and_then(serde_json::Value::as_u64)
+            .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs);
+        Ok((token, lifetime))
+    }
+
+    /// Sends a request, retrying it after a transient failure as the retry policy allows. The
+    /// `Retry-After` of a 429 or 503 is waited for instead of the backoff.
+    async fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let mut req = req.build()?;
+        let mut attempt = 0;
+        loop {
+            let next = if attempt < self.retry.max_retries {
+                req.try_clone()
+            } else {
+                None
+            };
+            let Some(next) = next else {
+                return self.execute(req).await;
+            };
+            let delay = match self.execute(req).await {
+                Ok(res) if RETRY_STATUSES.contains(&res.status()) => {
+                    debug!(status = %res.status(), url = %res.url(), attempt, "Retrying a request");
+                    retry_after(&res).unwrap_or_else(|| self.retry.backoff(attempt))
+                }
+                Err(OciDistributionError::RequestError(e)) if e.is_connect() || e.is_timeout() => {
+                    debug!(error = %e, attempt, "Retrying a request");
+                    self.retry.backoff(attempt)
+                }
+                Err(OciDistributionError::IoError(e)) if e.kind() == io::ErrorKind::TimedOut => {
+                    debug!(error = %e, attempt, "Retrying a request");
+                    self.retry.backoff(attempt)
+                }
+                result => return result,
+            };
+            self.timer.retried();
+            tokio::time::sleep(delay).await;
+            req = next;
+            attempt += 1;
+        }
+    }
+
+    /// Sends a request once, timing out when the response headers take longer than the first
+    /// byte timeout, plus the time the body may take to upload at the minimum throughput. The
+    /// upload is abandoned as stalled when less than the minimum throughput is sent over a stall
+    /// window.
+    async fn execute(&self, mut req: reqwest::Request) -> Result<Response> {
+        let len = req
+            .body()
+            .and_then(reqwest::Body::as_bytes)
+            .map_or(0, <[u8]>::len);
+        let url = req.url().clone();
+        let sent = self.watch_upload(&mut req);
+        let response = async {
+            let Some(timeout) = self.timeouts.headers(len) else {
+                return Ok(self.http.execute(req).await?);
+            };
+            match tokio::time::timeout(timeout, self.http.execute(req)).await {
+                Ok(res) => Ok(res?),
+                Err(_) => Err(timed_out(format!(
+                    "no response from {url} within {timeout:?}, after sending {len} bytes"
+                ))),
+            }
+        };
+        match sent {
+            Some(sent) => tokio::select! {
+                res = response => res,
+                error = self.stalled_upload(&sent, len as u64, &url) => Err(error),
+            },
+            None => response.await,
+        }
+    }
+
+    /// Streams the body of a request in pieces when there is a minimum throughput to check its
+    /// upload against, returning the count of bytes handed to the connection so far.
+    fn watch_upload(&self, req: &mut reqwest::Request) -> Option<Arc<AtomicU64>> {
+        self.timeouts.min_throughput?;
+        let body = Bytes::copy_from_slice(req.body().and_then(reqwest::Body::as_bytes)?);
+        if body.is_empty() {
+            return None;
+        }
+        req.headers_mut()
+            .entry(header::CONTENT_LENGTH)
+            .or_insert_with(|| body.len().into());
+        let sent = Arc::new(AtomicU64::new(0));
+        let counted = sent.clone();
+        let pieces = (0..body.len()).step_by(UPLOAD_PIECE).map(move |start| {
+            let piece = body.slice(start..body.len().min(start + UPLOAD_PIECE));
+            counted.fetch_add(piece.len() as u64, Ordering::Relaxed);
+            Ok::<_, io::Error>(piece)
+        });
+        *req.body_mut() = Some(reqwest::Body::wrap_stream(futures::stream::iter(pieces)));
+        Some(sent)
+    }
+
+    /// Returns an error once less than the minimum throughput of an upload of `len` bytes is
+    /// `sent` over a stall window. Never returns once the whole body is sent.
+    async fn stalled_upload(&self, sent: &AtomicU64, len: u64, url: &Url) -> OciDistributionError {
+        let min_throughput = self.timeouts.min_throughput.unwrap_or_default();
+        let window = self.timeouts.stall_window;
+        let needed = min_throughput
+            .saturating_mul(u64::try_from(window.as_millis()).unwrap_or(u64::MAX))
+            / 1000;
+        let mut last = 0;
+        loop {
+            tokio::time::sleep(window).await;
+            let now = sent.load(Ordering::Relaxed);
+            if now >= len {
+                return std::future::pending().await;
+            }
+            if now - last < needed {
+                return timed_out(format!(
+                    "upload to {url} stalled at {} bytes in {window:?}, under {min_throughput} \
+                     bytes/s, after {now} of {len} bytes",
+                    now - last
+                ));
+            }
+            last = now;
+        }
+    }
+
+    /// Reads the body of a response, abandoning it as stalled when less than the minimum
+    /// throughput arrives over a stall window.
+    async fn read(&self, mut res: Response) -> Result<Vec<u8>> {
+        let Some(min_throughput) = self.timeouts.min_throughput else {
+            return Ok(res.bytes().await?.to_vec());
+        };
+        let window = self.timeouts.stall_window;
+        let needed = min_throughput
+            .saturating_mul(u64::try_from(window.as_millis()).unwrap_or(u64::MAX))
+            / 1000;
+        let url = res.url().clone();
+
+        let mut body = Vec::new();
+        let mut deadline = tokio::time::Instant::now() + window;
+        let mut received = 0;
+        loop {
+            match tokio::time::timeout_at(deadline, res.chunk())
+                .await
+                .map(std::result::Result::transpose)
+            {
+                Ok(Some(chunk)) => {
+                    let chunk = chunk?;
+                    received += chunk.len() as u64;
+                    body.extend_from_slice(&chunk);
+                }
+                Ok(None) => return Ok(body),
+                Err(_) => {}
+            }
+            if tokio::time::Instant::now() >= deadline {
+                if received < needed {
+                    return Err(timed_out(format!(
+                        "download from {url} stalled at {received} bytes in {window:?}, under \
+                         {min_throughput} bytes/s, after {} bytes",
+                        body.len()
+                    )));
+                }
+                deadline += window;
+                received = 0;
+            }
+        }
+    }
+
+    /// Adds the credentials obtained by [`Registry::authenticate`] to a request.
+    fn authorize(&self, req: RequestBuilder) -> RequestBuilder {
+        match &self.authorization {
+            Some(Authorization::Basic(user, password)) => req.basic_auth(user, Some(password)),
+            Some(Authorization::Bearer(token)) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+
+    /// Starts an authorized request to `path` on the registry, such as `/v2/<name>/tags/list`.
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.authorize(
+            self.http
+                .request(method, format!("{}{path}", self.base_url)),
+        )
+    }
+
+    /// Starts an authorized request to a URL returned by the registry, such as an upload
+    /// location.
+    pub fn request_url(&self, method: Method, url: Url) -> RequestBuilder {
+        self.authorize(self.http.request(method, url))
+    }
+
+    /// Resolves a `Location` header, which may be relative to the registry. Locations on hosts
+    /// reached over plain HTTP are switched to HTTP.
+    ///
+    /// # Errors
+    ///
+    /// * If the location is not a valid URL
+    pub fn resolve(&self, location: &str) -> Result<Url> {
+        let location = if location.starts_with('/') {
+            format!("{}{location}", self.base_url)
+        } else {
+            location.to_string()
+        };
+        let mut url = Url::parse(&location).map_err(|e| {
+            OciDistributionError::SpecViolationError(format!("invalid location {location}: {e}"))
+        })?;
+        if url.scheme() == "https" {
+            let host = match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{host}:{port}"),
+                (Some(host), None) => host.to_string(),
+                (None, _) => String::new(),
+            };
+            if is_plain_http(&self.plain_http_hosts, &host) {
+                url.set_scheme("http")
+                    .expect("HTTPS URLs can be switched to HTTP");
+            }
+        }
+        Ok(url)
+    }
+
+    /// Checks whether `repository` has the blob `digest` with a `HEAD`.
+    ///
+    /// # Errors
+    ///
+    /// * If the registry answers with anything but the blob or a 404
+    #[instrument(level = "trace", skip(self))]
+    pub async fn blob_exists(&self, repository: &str, digest: &str) -> Result<bool> {
+        let url = format!("{}/v2/{repository}/blobs/{digest}", self.base_url);
+        let res = self.send(self.authorize(self.http.head(&url))).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check(res).await?;
+        Ok(true)
+    }
+
+    /// Gets the manifest `reference` (a tag or digest) of `repository`, an image or an index,
+    /// returning it with its digest.
+    ///
+    /// # Errors
+    ///
+    /// * If the registry doesn't have the manifest
+    /// * If the manifest doesn't match the digest it was asked for with
+    /// * If the manifest can't be parsed
+    #[instrument(level = "trace", skip(self))]
+    pub async fn get_manifest(
+        &self,
+        repository: &str,
+        reference: &str,
+    ) -> Result<(OciManifest, String)> {
+        let url = format!("{}/v2/{repository}/manifests/{reference}", self.base_url);
+        let req = self
+            .http
+            .get(&url)
+            .header(header::ACCEPT, MANIFEST_MEDIA_TYPES.join(", "));
+        let res = check(self.send(self.authorize(req)).await?).await?;
+        let body = self.read(res).await?;
+        verify_digest(reference, &body)?;
+        let digest = format!("sha256:{:x}", Sha256::digest(&body));
+
+        let manifest: serde_json::Value = serde_json::from_slice(&body)?;
+        let manifest = if manifest.get("manifests").is_some() {
+            OciManifest::ImageIndex(serde_json::from_value::<OciImageIndex>(manifest)?)
+        } else {
+            OciManifest::Image(serde_json::from_value::<OciImageManifest>(manifest)?)
+        };
+        Ok((manifest, digest))
+    }
+
+    /// Gets the blob `digest` of `repository`.
+    ///
+    /// # Errors
+    ///
+    /// * If the registry doesn't have the blob
+    /// * If the blob doesn't match its digest
+    #[instrument(level = "trace", skip(self))]
+    pub async fn get_blob(&self, repository: &str, digest: &str) -> Result<Vec<u8>> {
+        let url = format!("{}/v2/{repository}/blobs/{digest}", self.base_url);
+        let res = check(self.send(self.authorize(self.http.get(&url))).await?).await?;
+        let data = self.read(res).await?;
+        verify_digest(digest, &data)?;
+        Ok(data)
+    }
+
+    /// Asks the registry to mount the blob `digest` from the repository `from` into `repository`
+    /// with a `POST`.
+    ///
+    /// # Errors
+    ///
+    /// * If the registry rejects the request
+    #[instrument(level = "trace", skip(self))]
+    pub async fn mount_blob(&self, repository: &str, digest: &str, from: &str) -> Result<Mount> {
+        let url = format!("{}/v2/{repository}/blobs/uploads/", self.base_url);
+        let req = self
+            .http
+            .post(&url)
+            .query(&[("mount", digest), ("from", from)]);
+        let res = check(self.send(self.authorize(req)).await?).await?;
+        let location = location(&res, &url)?;
+        if res.status() == StatusCode::CREATED {
+            Ok(Mount::Mounted(location))
+        } else {
+            Ok(Mount::Upload(self.resolve(&location)?))
+        }
+    }
+
+    /// Starts a blob upload with a `POST`, returning the location to upload the data to.
+    ///
+    /// # Errors
+    ///
+    /// * If the registry rejects the upload
+    pub async fn start_upload(&self, repository: &str) -> Result<Url> {
+        let url = format!("{}/v2/{repository}/blobs/uploads/", self.base_url);
+        let res = check(self.send(self.authorize(self.http.post(&url))).await?).await?;
+        self.resolve(&location(&res, &url)?)
+    }
+
+    /// Uploads the chunk of a blob starting at `offset` with a `PATCH`, returning the location to
+    /// upload the next chunk to.
+    ///
+    /// # Errors
+    ///
+    /// * If the registry rejects the chunk
+    #[instrument(level = "trace", skip(self, chunk), fields(len = chunk.len()))]
+    pub async fn upload_chunk(&self, upload: Url, offset: usize, chunk: Vec<u8>) -> Result<Url> {
+        let url = upload.to_string();
+        let end = (offset + chunk.len()).saturating_sub(1);
+        let req = self
+            .http
+            .patch(upload)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_RANGE, format!("{offset}-{end}"))
+            .header(header::CONTENT_LENGTH, chunk.len())
+            .body(chunk);
+        let res = check(self.send(self.authorize(req)).await?).await?;
+        self.resolve(&location(&res, &url)?)
+    }
+
+    /// Completes a blob upload with a `PUT` of the remaining `data`, returning the location of the
+    /// blob.
+    ///
+    /// # Errors
+    ///
+    /// * If the registry rejects the upload, such as when the digest doesn't match the data
+    pub async fn finish_upload(
+        &self,
+        mut upload: Url,
+        digest: &str,
+        data: Vec<u8>,
+    ) -> Result<String> {
+        let url = upload.to_string();
+        upload.query_pairs_mut().append_pair("digest", digest);
+        let req = self
+            .http
+            .put(upload)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, data.len())
+            .body(data);
+        let res = check(self.send(self.authorize(req)).await?).await?;
+        location(&res, &url)
+    }
+
+    /// Puts a manifest under `reference` (a tag or digest), returning the location of the
+    /// manifest.
+    ///
+    /// # Errors
+    ///
+    /// * If the registry rejects the manifest
+    #[instrument(level = "trace", skip(self, manifest))]
+    pub async fn put_manifest(
+        &self,
+        repository: &str,
+        reference: &str,
+        media_type: &str,
+        manifest: Vec<u8>,
+    ) -> Result<String> {
+        let url = format!("{}/v2/{repository}/manifests/{reference}", self.base_url);
+        let req = self
+            .http
+            .put(&url)
+            .header(header::CONTENT_TYPE, media_type)
+            .body(manifest);
+        let res = check(self.send(self.authorize(req)).await?).await?;
+        location(&res, &url)
+    }
+}
+
+/// Returns how long a 429 or 503 response asks to wait with its `Retry-After`, in seconds or as
+/// an HTTP date.
+fn retry_after(res: &Response) -> Option<Duration> {
+    if ![
+        StatusCode::TOO_MANY_REQUESTS,
+        StatusCode::SERVICE_UNAVAILABLE,
+    ]
+    .contains(&res.status())
+    {
+        return None;
+    }
+    let value = res
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok(),
+    }
+}
+
+/// Returns the `Location` header of a response.
+fn location(res: &Response, url: &str) -> Result<String> {
+    res.headers()
+        .get(header::LOCATION)
+        .ok_or(OciDistributionError::RegistryNoLocationError)?
+        .to_str()
+        .map(str::to_string)
+        .map_err(|e| {
+            OciDistributionError::SpecViolationError(format!("invalid location from {url}: {e}"))
+        })
+}
+
+/// Turns unsuccessful responses into errors. The body, which is an OCI error envelope for most
+/// registries, is kept as the message along with the status, which load tests classify errors by.
+async fn check(res: Response) -> Result<Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    let url = res.url().to_string();
+    if status == StatusCode::UNAUTHORIZED {
+        return Err(OciDistributionError::UnauthorizedError { url });
+    }
+    Err(OciDistributionError::ServerError {
+        code: status.as_u16(),
+        url,
+        message: res.text().await?,
+    })
+}
+
+/// Returns whether `host` (a host with an optional port) is one of `plain_http_hosts`. Docker Hub
+/// is known by several names, which all match each other.
+fn is_plain_http(plain_http_hosts: &[String], host: &str) -> bool {
+    let canonical = |host: &str| match host {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io".to_string(),
+        host => host.to_ascii_lowercase(),
+    };
+    plain_http_hosts
+        .iter()
+        .any(|plain| canonical(plain) == canonical(host))
+}
+
+/// Returns the code of the first error of an OCI error envelope, such as `BLOB_UNKNOWN`.
+pub(crate) fn oci_error_code(body: &str) -> Option<String> {
+    let envelope: serde_json::Value = serde_json::from_str(body).ok()?;
+    envelope
+        .get("errors")?
+        .get(0)?
+        .get("code")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// The error of content which doesn't match the digest it was asked for with.
+#[derive(Debug)]
+pub struct DigestMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Display for DigestMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expected digest {} but got {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+/// Checks that `data` has the sha256 `digest`. Digests of other algorithms aren't checked.
+fn verify_digest(digest: &str, data: &[u8]) -> Result<()> {
+    if !digest.starts_with("sha256:") {
+        return Ok(());
+    }
+    let actual = format!("sha256:{:x}", Sha256::digest(data));
+    if actual == digest {
+        return Ok(());
+    }
+    Err(OciDistributionError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData,
+        DigestMismatch {
+            expected: digest.to_string(),
+            actual,
+        },
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        future::Future,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body,
+    };
+
+    use super::*;
+
+    /// Serves the responses `respond` returns for every request, given the index of the request,
+    /// and returns the registry (the host and port) it serves at.
+    fn serve<F, Fut>(respond: F) -> String
+    where
+        F: Fn(usize) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = hyper::Response<Body>> + Send + 'static,
+    {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let make_service = make_service_fn(move |_| {
+            let (requests, respond) = (requests.clone(), respond.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let response = respond(requests.fetch_add(1, Ordering::SeqCst));
+                    async move { Ok::<_, Infallible>(response.await) }
+                }))
+            }
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let registry = server.local_addr().to_string();
+        tokio::spawn(server);
+        registry
+    }
+
+    fn status(status: StatusCode) -> hyper::Response<Body> {
+        hyper::Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Returns an anonymous client of `registry`, retrying and timing out as `options` ask.
+    fn registry(registry: &str, options: &HttpOptions, timer: &Timer) -> Registry {
+        let http = reqwest::Client::builder().no_proxy().build().unwrap();
+        Registry::new(
+            http,
+            &ClientProtocol::Http,
+            registry,
+            Credentials::Anonymous,
+        )
+        .with_options(options, timer)
+    }
+
+    fn retrying(max_retries: u32) -> HttpOptions {
+        HttpOptions {
+            retry: RetryPolicy {
+                max_retries,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(10),
+            },
+            ..HttpOptions::default()
+        }
+    }
+
+    #[test]
+    fn oci_error_code_reads_the_first_error() {
+        assert_eq!(
+            oci_error_code(
+                r#"{"errors":[{"code":"BLOB_UNKNOWN","message":"blob unknown"},{"code":"DENIED"}]}"#
+            )
+            .as_deref(),
+            Some("BLOB_UNKNOWN")
+        );
+        assert_eq!(oci_error_code(r#"{"errors":[]}"#), None);
+        assert_eq!(
+            oci_error_code(r#"{"errors":[{"message":"no code"}]}"#),
+            None
+        );
+        assert_eq!(oci_error_code("<html>Bad Gateway</html>"), None);
+        assert_eq!(oci_error_code(""), None);
+    }
+
+    #[tokio::test]
+    async fn unsuccessful_responses_keep_their_status_and_body() {
+        let reg = serve(|_| async {
+            hyper::Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from(r#"{"errors":[{"code":"UNAVAILABLE"}]}"#))
+                .unwrap()
+        });
+        let client = registry(&reg, &HttpOptions::default(), &Timer::default());
+        match client.get_blob("test", "sha256:0").await.unwrap_err() {
+            OciDistributionError::ServerError { code, message, .. } => {
+                assert_eq!(code, 503);
+                assert_eq!(oci_error_code(&message).as_deref(), Some("UNAVAILABLE"));
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+
+        let reg = serve(|_| async { status(StatusCode::UNAUTHORIZED) });
+        let client = registry(&reg, &HttpOptions::default(), &Timer::default());
+        assert!(matches!(
+            client.get_blob("test", "sha256:0").await.unwrap_err(),
+            OciDistributionError::UnauthorizedError { .. }
+        ));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let retry = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        let between = |attempt, min, max| {
+            for _ in 0..100 {
+                let backoff = retry.backoff(attempt);
+                assert!(
+                    backoff >= Duration::from_millis(min) && backoff <= Duration::from_millis(max),
+                    "attempt {attempt}: {backoff:?}"
+                );
+            }
+        };
+        between(0, 50, 100);
+        between(1, 100, 200);
+        between(2, 200, 400);
+        between(4, 500, 1000);
+        between(u32::MAX, 500, 1000);
+    }
+
+    #[test]
+    fn retry_after_reads_seconds_and_dates() {
+        let response = |code, retry_after: &str| {
+            Response::from(
+                hyper::Response::builder()
+                    .status(code)
+                    .header(header::RETRY_AFTER, retry_after)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        assert_eq!(
+            retry_after(&response(503, "2")),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(retry_after(&response(429, " 0 ")), Some(Duration::ZERO));
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(30));
+        let wait = retry_after(&response(429, &date)).unwrap();
+        assert!(wait > Duration::from_secs(28) && wait <= Duration::from_secs(30));
+
+        let past = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(30));
+        assert_eq!(retry_after(&response(503, &past)), None);
+        assert_eq!(retry_after(&response(503, "soon")), None);
+        assert_eq!(retry_after(&response(502, "2")), None);
+        assert_eq!(
+            retry_after(&Response::from(status(StatusCode::SERVICE_UNAVAILABLE))),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn transient_statuses_are_retried() {
+        let reg = serve(|request| async move {
+            match request {
+                0 => status(StatusCode::SERVICE_UNAVAILABLE),
+                1 => status(StatusCode::TOO_MANY_REQUESTS),
+                _ => status(StatusCode::OK),
+            }
+        });
+        let timer = Timer::default();
+        let client = registry(&reg, &retrying(3), &timer);
+        assert!(client.blob_exists("test", "sha256:0").await.unwrap());
+        assert_eq!(timer.retries(), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_give_up_after_the_most_retries() {
+        let reg = serve(|_| async { status(StatusCode::BAD_GATEWAY) });
+        let timer = Timer::default();
+        let client = registry(&reg, &retrying(2), &timer);
+        let error = client.blob_exists("test", "sha256:0").await.unwrap_err();
+        assert!(matches!(
+            error,
+            OciDistributionError::ServerError { code: 502, .. }
+        ));
+        assert_eq!(timer.retries(), 2);
+    }
+
+    #[tokio::test]
+    async fn other_failures_are_not_retried() {
+        let reg = serve(|_| async { status(StatusCode::INTERNAL_SERVER_ERROR) });
+        let timer = Timer::default();
+        let client = registry(&reg, &retrying(3), &timer);
+        assert!(client.blob_exists("test", "sha256:0").await.is_err());
+        assert_eq!(timer.retries(), 0);
+    }
+
+    #[test]
+    fn challenge_parse_reads_bearer_challenges() {
+        assert_eq!(
+            Challenge::parse(
+                r#"Bearer realm="https://auth.example.com/token",service="registry.example.com""#
+            ),
+            Challenge {
+                scheme: "bearer".to_string(),
+                realm: Some("https://auth.example.com/token".to_string()),
+                service: Some("registry.example.com".to_string()),
+            }
+        );
+        assert_eq!(
+            Challenge::parse(r#"Bearer Realm="https://auth/token", scope="repository:a:pull""#),
+            Challenge {
+                scheme: "bearer".to_string(),
+                realm: Some("https://auth/token".to_string()),
+                service: None,
+            }
+        );
+    }
+
+    #[test]
+    fn challenge_parse_reads_challenges_without_params() {
+        assert_eq!(
+            Challenge::parse(r#"Basic realm="Registry""#).scheme,
+            "basic".to_string()
+        );
+        assert_eq!(
+            Challenge::parse("Basic"),
+            Challenge {
+                scheme: "basic".to_string(),
+                ..Challenge::default()
+            }
+        );
+    }
+
+    fn timing_out(timeouts: Timeouts) -> HttpOptions {
+        HttpOptions {
+            timeouts,
+            ..HttpOptions::default()
+        }
+    }
+
+    fn is_timeout(error: &OciDistributionError) -> bool {
+        matches!(error, OciDistributionError::IoError(e) if e.kind() == io::ErrorKind::TimedOut)
+    }
+
+    #[test]
+    fn headers_timeout_allows_for_the_upload() {
+        let timeouts = |first_byte, min_throughput| Timeouts {
+            first_byte,
+            min_throughput,
+            stall_window: Duration::from_secs(10),
+            ..Timeouts::default()
+        };
+        assert_eq!(timeouts(None, None).headers(1000), None);
+        assert_eq!(timeouts(None, Some(100)).headers(0), None);
+        let first_byte = Some(Duration::from_secs(5));
+        assert_eq!(timeouts(first_byte, None).headers(1000), first_byte);
+        assert_eq!(timeouts(first_byte, Some(100)).headers(0), first_byte);
+        assert_eq!(
+            timeouts(None, Some(100)).headers(1000),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            timeouts(first_byte, Some(100)).headers(1000),
+            Some(Duration::from_secs(25))
+        );
+        // A minimum throughput of 0 is read as 1 byte per second rather than dividing by 0.
+        assert_eq!(
+            timeouts(None, Some(0)).headers(5),
+            Some(Duration::from_secs(15))
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_responses_time_out() {
+        let reg = serve(|_| async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            status(StatusCode::OK)
+        });
+        let options = timing_out(Timeouts {
+            first_byte: Some(Duration::from_millis(50)),
+            ..Timeouts::default()
+        });
+        let client = registry(&reg, &options, &Timer::default());
+        let error = client.blob_exists("test", "sha256:0").await.unwrap_err();
+        assert!(is_timeout(&error), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn stalled_downloads_time_out() {
+        let reg = serve(|_| async {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                let _ = sender.send_data(vec![0; 10].into()).await;
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            });
+            hyper::Response::new(body)
+        });
+        let options = timing_out(Timeouts {
+            min_throughput: Some(1024),
+            stall_window: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        let client = registry(&reg, &options, &Timer::default());
+        let error = client.get_blob("test", "sha256:0").await.unwrap_err();
+        assert!(is_timeout(&error), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn downloads_keeping_up_do_not_time_out() {
+        let data = b"layer".repeat(100);
+        let digest = format!("sha256:{:x}", Sha256::digest(&data));
+        let reg = serve(move |_| {
+            let data = data.clone();
+            async move {
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    for chunk in data.chunks(100) {
+                        let _ = sender.send_data(chunk.to_vec().into()).await;
+                        tokio::time::sleep(Duration::from_millis(30)).await;
+                    }
+                });
+                hyper::Response::new(body)
+            }
+        });
+        let options = timing_out(Timeouts {
+            min_throughput: Some(100),
+            stall_window: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        let client = registry(&reg, &options, &Timer::default());
+        assert_eq!(client.get_blob("test", &digest).await.unwrap().len(), 500);
+    }
+
+    #[tokio::test]
+    async fn stalled_uploads_time_out() {
+        // The body is never read, so the upload stalls once the socket buffers are full.
+        let reg = serve(|_| async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            hyper::Response::new(Body::empty())
+        });
+        let options = timing_out(Timeouts {
+            min_throughput: Some(1024 * 1024),
+            stall_window: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        let client = registry(&reg, &options, &Timer::default());
+        let upload = Url::parse(&format!("http://{reg}/v2/test/blobs/uploads/1")).unwrap();
+        let error = client
+            .finish_upload(upload, "sha256:0", vec![0; 64 * 1024 * 1024])
+            .await
+            .unwrap_err();
+        assert!(is_timeout(&error), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn uploads_keeping_up_do_not_time_out() {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+                let len = req.headers()[header::CONTENT_LENGTH].clone();
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                assert_eq!(len, body.len().to_string().as_str());
+                assert!(body.iter().all(|&b| b == 1));
+                let res = hyper::Response::builder()
+                    .status(StatusCode::CREATED)
+                    .header(header::LOCATION, format!("/v2/test/blobs/{}", body.len()))
+                    .body(Body::empty());
+                Ok::<_, Infallible>(res.unwrap())
+            }))
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let reg = server.local_addr().to_string();
+        tokio::spawn(server);
+
+        let options = timing_out(Timeouts {
+            min_throughput: Some(1024),
+            stall_window: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        let client = registry(&reg, &options, &Timer::default());
+        let upload = Url::parse(&format!("http://{reg}/v2/test/blobs/uploads/1")).unwrap();
+        let location = client
+            .finish_upload(upload, "sha256:0", vec![1; UPLOAD_PIECE * 3 + 1])
+            .await
+            .unwrap();
+        assert_eq!(location, format!("/v2/test/blobs/{}", UPLOAD_PIECE * 3 + 1));
+    }
+
+    #[test]
+    fn tokens_are_renewed_before_they_expire() {
+        assert_eq!(
+            usable_lifetime(Duration::from_mins(5)),
+            Duration::from_secs(295)
+        );
+        assert_eq!(
+            usable_lifetime(Duration::from_secs(10)),
+            Duration::from_secs(9)
+        );
+        assert_eq!(usable_lifetime(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn plain_http_hosts_apply_to_every_location() {
+        let protocol =
+            ClientProtocol::HttpsExcept(vec!["mirror:5000".to_string(), "docker.io".to_string()]);
+        let client = |registry| {
+            Registry::new(
+                reqwest::Client::new(),
+                &protocol,
+                registry,
+                Credentials::Anonymous,
+            )
+        };
+        let resolve = |registry, location| client(registry).resolve(location).unwrap().to_string();
+        assert_eq!(resolve("mirror:5000", "/v2/"), "http://mirror:5000/v2/");
+        assert_eq!(
+            resolve("registry", "https://mirror:5000/v2/test/blobs/uploads/1"),
+            "http://mirror:5000/v2/test/blobs/uploads/1"
+        );
+        assert_eq!(resolve("registry", "/v2/"), "https://registry/v2/");
+        assert_eq!(
+            resolve("mirror:5000", "https://mirror/v2/"),
+            "https://mirror/v2/"
+        );
+        assert_eq!(
+            resolve("index.docker.io", "/v2/"),
+            "http://index.docker.io/v2/"
+        );
+    }
+
+    #[tokio::test]
+    async fn token_realms_on_plain_http_hosts_are_reached_over_http() {
+        let token_server = serve(|_| async {
+            hyper::Response::new(Body::from(r#"{"token":"secret","expires_in":300}"#))
+        });
+        let port = token_server.rsplit(':').next().unwrap().to_string();
+        let realm = format!("https://localhost:{port}/token");
+        let registry = serve(move |_| {
+            let challenge = format!(r#"Bearer realm="{realm}",service="registry""#);
+            async move {
+                hyper::Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(header::WWW_AUTHENTICATE, challenge)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        });
+        let authenticate = |hosts: Vec<String>| {
+            let mut client = Registry::new(
+                reqwest::Client::builder().no_proxy().build().unwrap(),
+                &ClientProtocol::HttpsExcept(hosts),
+                &registry,
+                Credentials::Anonymous,
+            );
+            async move { client.authenticate(&[scope("test", "pull")]).await }
+        };
+
+        authenticate(vec![registry.clone(), format!("localhost:{port}")])
+            .await
+            .unwrap();
+        assert!(authenticate(vec![registry.clone()]).await.is_err());
+    }
+}
@@ -0,0 +1,586 @@
+//! Machine readable reports of a load test run.
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    metrics::{self, Stats},
+    scheduler::{self, Stage},
+    slo::Check,
+    tester::{LoadTestError, Sample},
+};
+
+/// The format of a run report.
+// The variant docs are shown as CLI help, where backticks would be printed as is.
+#[allow(clippy::doc_markdown)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// The complete report as a single JSON document.
+    #[default]
+    Json,
+    /// One row per timed registry operation of every sample, and one for every sample without any.
+    Csv,
+    /// A JUnit XML test suite per phase, with a test case per registry operation.
+    Junit,
+}
+
+/// Where and how to write a run report.
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    /// The file to write the report to.
+    pub path: PathBuf,
+    /// The format of the report.
+    pub format: ReportFormat,
+}
+
+/// Describes the run a report is written for.
+pub struct Run<'a> {
+    /// The subcommand that was run.
+    pub command: &'static str,
+    /// The options the subcommand was run with.
+    pub config: BTreeMap<&'static str, String>,
+    pub stages: &'a [Stage],
+    /// When the run started.
+    pub started_at: SystemTime,
+    /// How long the run took.
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    command: &'a str,
+    config: &'a BTreeMap<&'static str, String>,
+    started_at_unix_ms: u128,
+    elapsed_ms: f64,
+    total: usize,
+    success: usize,
+    /// The samples which succeeded without retrying any request.
+    first_attempt_success: usize,
+    retries: u64,
+    operations: Vec<OperationReport>,
+    phases: Vec<PhaseReport>,
+    errors: Vec<ErrorReport>,
+    thresholds: Vec<ThresholdReport>,
+    samples: Vec<SampleReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct OperationReport {
+    operation: String,
+    success: u64,
+    errors: u64,
+    bytes: u64,
+    min_ms: f64,
+    mean_ms: f64,
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+    p999_ms: f64,
+    max_ms: f64,
+    ops_per_sec: f64,
+    mb_per_sec: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct PhaseReport {
+    stage: Option<usize>,
+    phase: String,
+    duration_ms: f64,
+    target: Option<usize>,
+    total: usize,
+    success: usize,
+    first_attempt_success: usize,
+    retries: u64,
+    operations: Vec<OperationReport>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ErrorReport {
+    /// The category of the errors, such as `503 UNAVAILABLE` or `timeout`.
+    category: String,
+    variant: &'static str,
+    status: Option<u16>,
+    count: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ThresholdReport {
+    name: &'static str,
+    limit: String,
+    actual: String,
+    passed: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SampleReport {
+    index: usize,
+    stage: Option<usize>,
+    started_ms: f64,
+    elapsed_ms: f64,
+    success: bool,
+    retries: u32,
+    error_variant: Option<&'static str>,
+    error_category: Option<String>,
+    status: Option<u16>,
+    error: Option<String>,
+    timings: Vec<TimingReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct TimingReport {
+    operation: String,
+    elapsed_ms: f64,
+    bytes: u64,
+    success: bool,
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn operation_reports(
+    stats: BTreeMap<metrics::Operation, Stats>,
+    elapsed: Duration,
+) -> Vec<OperationReport> {
+    stats
+        .into_iter()
+        .map(|(operation, stats)| OperationReport {
+            operation: operation.to_string(),
+            success: stats.success,
+            errors: stats.errors,
+            bytes: stats.bytes,
+            min_ms: ms(stats.min()),
+            mean_ms: ms(stats.mean()),
+            p50_ms: ms(stats.quantile(0.5)),
+            p90_ms: ms(stats.quantile(0.9)),
+            p99_ms: ms(stats.quantile(0.99)),
+            p999_ms: ms(stats.quantile(0.999)),
+            max_ms: ms(stats.max()),
+            ops_per_sec: stats.ops_per_sec(elapsed),
+            mb_per_sec: stats.megabytes_per_sec(elapsed),
+        })
+        .collect()
+}
+
+/// Returns whether a sample succeeded without retrying any request.
+fn first_attempt<T>(sample: &Sample<T>) -> bool {
+    sample.result.is_ok() && sample.retries == 0
+}
+
+fn phase_report<'a, T: 'a>(
+    stage: Option<(usize, &Stage, scheduler::Phase)>,
+    samples: impl Iterator<Item = &'a Sample<T>> + Clone,
+    elapsed: Duration,
+) -> PhaseReport {
+    let duration = stage.map_or(elapsed, |(_, stage, _)| stage.duration);
+    PhaseReport {
+        stage: stage.map(|(index, _, _)| index),
+        phase: stage.map_or_else(|| "all".to_string(), |(_, _, phase)| phase.to_string()),
+        duration_ms: ms(duration),
+        target: stage.map(|(_, stage, _)| stage.target),
+        total: samples.clone().count(),
+        success: samples.clone().filter(|s| s.result.is_ok()).count(),
+        first_attempt_success: samples.clone().filter(|s| first_attempt(s)).count(),
+        retries: samples.clone().map(|s| u64::from(s.retries)).sum(),
+        operations: operation_reports(metrics::stats(samples.flat_map(|s| &s.timings)), duration),
+    }
+}
+
+impl<'a> Report<'a> {
+    fn new<T>(run: &'a Run<'a>, samples: &[Sample<T>], checks: &[Check]) -> Report<'a> {
+        let phases = if run.stages.is_empty() {
+            vec![phase_report(None, samples.iter(), run.elapsed)]
+        } else {
+            run.stages
+                .iter()
+                .zip(scheduler::phases(run.stages))
+                .enumerate()
+                .map(|(index, (stage, phase))| {
+                    phase_report(
+                        Some((index, stage, phase)),
+                        samples.iter().filter(move |s| s.stage == Some(index)),
+                        run.elapsed,
+                    )
+                })
+                .collect()
+        };
+
+        let mut errors: BTreeMap<(String, &'static str, Option<u16>), usize> = BTreeMap::new();
+        for e in samples.iter().filter_map(|s| s.result.as_ref().err()) {
+            *errors
+                .entry((e.category().to_string(), e.variant(), e.status()))
+                .or_default() += 1;
+        }
+
+        Report {
+            command: run.command,
+            config: &run.config,
+            started_at_unix_ms: run
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            elapsed_ms: ms(run.elapsed),
+            total: samples.len(),
+            success: samples.iter().filter(|s| s.result.is_ok()).count(),
+            first_attempt_success: samples.iter().filter(|s| first_attempt(s)).count(),
+            retries: samples.iter().map(|s| u64::from(s.retries)).sum(),
+            operations: operation_reports(
+                metrics::stats(samples.iter().flat_map(|s| &s.timings)),
+                run.elapsed,
+            ),
+            phases,
+            errors: errors
+                .into_iter()
+                .map(|((category, variant, status), count)| ErrorReport {
+                    category,
+                    variant,
+                    status,
+                    count,
+                })
+                .collect(),
+            thresholds: checks
+                .iter()
+                .map(|check| ThresholdReport {
+                    name: check.name,
+                    limit: check.limit.clone(),
+                    actual: check.actual.clone(),
+                    passed: check.passed,
+                })
+                .collect(),
+            samples: samples
+                .iter()
+                .enumerate()
+                .map(|(index, sample)| {
+                    let error = sample.result.as_ref().err();
+                    SampleReport {
+                        index,
+                        stage: sample.stage,
+                        started_ms: ms(sample.started),
+                        elapsed_ms: ms(sample.elapsed),
+                        success: error.is_none(),
+                        retries: sample.retries,
+                        error_variant: error.map(LoadTestError::variant),
+                        error_category: error.map(|e| e.category().to_string()),
+                        status: error.and_then(LoadTestError::status),
+                        error: error.map(ToString::to_string),
+                        timings: sample
+                            .timings
+                            .iter()
+                            .map(|t| TimingReport {
+                                operation: t.operation.to_string(),
+                                elapsed_ms: ms(t.elapsed),
+                                bytes: t.bytes,
+                                success: t.success,
+                            })
+                            .collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "sample,stage,started_ms,operation,elapsed_ms,bytes,success,error_variant,status,error,error_category\n",
+        );
+        for sample in &self.samples {
+            // Samples that failed before any registry operation still get a row, with no timing.
+            let timings: Vec<Option<&TimingReport>> = if sample.timings.is_empty() {
+                vec![None]
+            } else {
+                sample.timings.iter().map(Some).collect()
+            };
+            for timing in timings {
+                let _ = writeln!(
+                    csv,
+                    "{},{},{:.3},{},{},{},{},{},{},{},{}",
+                    sample.index,
+                    sample.stage.map(|s| s.to_string()).unwrap_or_default(),
+                    sample.started_ms,
+                    timing.map(|t| t.operation.as_str()).unwrap_or_default(),
+                    timing
+                        .map(|t| format!("{:.3}", t.elapsed_ms))
+                        .unwrap_or_default(),
+                    timing.map(|t| t.bytes.to_string()).unwrap_or_default(),
+                    timing.map_or(sample.success, |t| t.success),
+                    sample.error_variant.unwrap_or_default(),
+                    sample.status.map(|s| s.to_string()).unwrap_or_default(),
+                    csv_field(sample.error.as_deref().unwrap_or_default()),
+                    csv_field(sample.error_category.as_deref().unwrap_or_default()),
+                );
+            }
+        }
+        csv
+    }
+
+    fn to_junit(&self) -> String {
+        let operations = self.phases.iter().flat_map(|p| &p.operations);
+        let tests = operations.clone().count() + self.thresholds.len();
+        let failures = operations.filter(|o| o.errors > 0).count()
+            + self.thresholds.iter().filter(|t| !t.passed).count();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            "<testsuites name=\"oci-tester {}\" tests=\"{tests}\" failures=\"{failures}\" time=\"{:.3}\">",
+            xml_escape(self.command),
+            self.elapsed_ms / 1000.0,
+        );
+        for phase in &self.phases {
+            let name = match phase.stage {
+                Some(stage) => format!("stage {stage} ({})", phase.phase),
+                None => phase.phase.clone(),
+            };
+            let _ = writeln!(
+                xml,
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+                xml_escape(&name),
+                phase.operations.len(),
+                phase.operations.iter().filter(|o| o.errors > 0).count(),
+                phase.duration_ms / 1000.0,
+            );
+            xml.push_str("    <properties>\n");
+            for (key, value) in self.config {
+                let _ = writeln!(
+                    xml,
+                    "      <property name=\"{}\" value=\"{}\"/>",
+                    xml_escape(key),
+                    xml_escape(value),
+                );
+            }
+            xml.push_str("    </properties>\n");
+            for operation in &phase.operations {
+                let _ = writeln!(
+                    xml,
+                    "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\">",
+                    xml_escape(self.command),
+                    xml_escape(&operation.operation),
+                    operation.mean_ms / 1000.0,
+                );
+                if operation.errors > 0 {
+                    let _ = writeln!(
+                        xml,
+                        "      <failure message=\"{} of {} operations failed\"/>",
+                        operation.errors,
+                        operation.errors + operation.success,
+                    );
+                }
+                let _ = writeln!(
+                    xml,
+                    "      <system-out>min: {:.2}ms, mean: {:.2}ms, p50: {:.2}ms, p90: {:.2}ms, p99: {:.2}ms, p99.9: {:.2}ms, max: {:.2}ms, {:.2} ops/s, {:.2} MB/s</system-out>",
+                    operation.min_ms,
+                    operation.mean_ms,
+                    operation.p50_ms,
+                    operation.p90_ms,
+                    operation.p99_ms,
+                    operation.p999_ms,
+                    operation.max_ms,
+                    operation.ops_per_sec,
+                    operation.mb_per_sec,
+                );
+                xml.push_str("    </testcase>\n");
+            }
+            xml.push_str("  </testsuite>\n");
+        }
+
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"thresholds\" tests=\"{}\" failures=\"{}\">",
+            self.thresholds.len(),
+            self.thresholds.iter().filter(|t| !t.passed).count(),
+        );
+        for threshold in &self.thresholds {
+            let _ = writeln!(
+                xml,
+                "    <testcase classname=\"{}\" name=\"{}\">",
+                xml_escape(self.command),
+                xml_escape(threshold.name),
+            );
+            if !threshold.passed {
+                let _ = writeln!(
+                    xml,
+                    "      <failure message=\"{} exceeds the limit of {}\"/>",
+                    xml_escape(&threshold.actual),
+                    xml_escape(&threshold.limit),
+                );
+            }
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n");
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+}
+
+/// Quotes a CSV field when needed.
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub(crate) fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes the report of a run.
+///
+/// # Errors
+///
+/// * If the report can't be serialized
+/// * If the report file can't be written
+pub fn write<T>(
+    options: &ReportOptions,
+    run: &Run<'_>,
+    samples: &[Sample<T>],
+    checks: &[Check],
+) -> Result<()> {
+    let report = Report::new(run, samples, checks);
+    let contents = match options.format {
+        ReportFormat::Json => {
+            serde_json::to_string_pretty(&report).context("failed to serialize the report")?
+        }
+        ReportFormat::Csv => report.to_csv(),
+        ReportFormat::Junit => report.to_junit(),
+    };
+    std::fs::write(&options.path, contents).with_context(|| {
+        format!(
+            "failed to write the report to {path}",
+            path = options.path.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_distribution::errors::OciDistributionError;
+
+    use super::*;
+    use crate::metrics::{Operation, Timing};
+
+    fn run(stages: &[Stage]) -> Run<'_> {
+        Run {
+            command: "push-images",
+            config: BTreeMap::from([("count", "2".to_string())]),
+            stages,
+            started_at: UNIX_EPOCH,
+            elapsed: Duration::from_secs(1),
+        }
+    }
+
+    fn samples() -> Vec<Sample<()>> {
+        vec![
+            Sample {
+                stage: None,
+                started: Duration::from_millis(1),
+                elapsed: Duration::from_millis(20),
+                timings: vec![
+                    Timing {
+                        operation: Operation::BlobUpload,
+                        elapsed: Duration::from_millis(15),
+                        bytes: 100,
+                        success: true,
+                    },
+                    Timing {
+                        operation: Operation::ManifestPut,
+                        elapsed: Duration::from_millis(5),
+                        bytes: 0,
+                        success: true,
+                    },
+                ],
+                retries: 1,
+                result: Ok(()),
+            },
+            Sample {
+                stage: None,
+                started: Duration::from_millis(2),
+                elapsed: Duration::from_millis(1),
+                timings: Vec::new(),
+                retries: 0,
+                result: Err(LoadTestError::OciDistributionError(
+                    OciDistributionError::ServerError {
+                        code: 503,
+                        url: String::new(),
+                        message: String::new(),
+                    },
+                )),
+            },
+        ]
+    }
+
+    #[test]
+    fn csv_has_a_row_per_timing_and_per_sample_without_timings() {
+        let run = run(&[]);
+        let samples = samples();
+        let csv = Report::new(&run, &samples, &[]).to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("sample,stage,started_ms,operation,elapsed_ms,bytes,success,"));
+        assert_eq!(lines[1], "0,,1.000,blob-upload,15.000,100,true,,,,");
+        assert_eq!(lines[2], "0,,1.000,manifest-put,5.000,0,true,,,,");
+        assert!(lines[3].starts_with("1,,2.000,,,,false,OciDistributionError,503,"));
+        assert!(lines[3].ends_with(",503"));
+    }
+
+    #[test]
+    fn json_counts_every_sample() {
+        let run = run(&[]);
+        let samples = samples();
+        let report = serde_json::to_value(Report::new(&run, &samples, &[])).unwrap();
+        assert_eq!(report["total"], 2);
+        assert_eq!(report["success"], 1);
+        assert_eq!(report["first_attempt_success"], 0);
+        assert_eq!(report["retries"], 1);
+        assert_eq!(report["errors"][0]["status"], 503);
+        assert_eq!(report["errors"][0]["count"], 1);
+        assert_eq!(report["samples"].as_array().unwrap().len(), 2);
+        assert_eq!(report["phases"][0]["phase"], "all");
+    }
+
+    #[test]
+    fn junit_reports_failed_thresholds() {
+        let run = run(&[]);
+        let samples = samples();
+        let checks = [Check {
+            name: "error-rate",
+            limit: "0.000%".to_string(),
+            actual: "50.000%".to_string(),
+            passed: false,
+        }];
+        let xml = Report::new(&run, &samples, &checks).to_junit();
+        assert!(xml.contains(r#"<testsuites name="oci-tester push-images" tests="3" failures="1""#));
+        assert!(xml.contains(r#"<failure message="50.000% exceeds the limit of 0.000%"/>"#));
+        assert!(xml.contains(r#"<property name="count" value="2"/>"#));
+    }
+
+    #[test]
+    fn fields_are_quoted_and_escaped() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\"\n"), "\"say \"\"hi\"\"\n\"");
+        assert_eq!(
+            xml_escape("<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&apos;"
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Time driven scheduling of load test operations.
+use std::{
+    fmt::Display,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use oci_distribution::errors::OciDistributionError;
+use tokio::{task::JoinHandle, time::Instant};
+use tracing::debug;
+
+use crate::{
+    metrics::Timer,
+    parse::parse_duration,
+    tester::{LoadTestError, Sample},
+};
+
+/// How often the amount of virtual users is adjusted during a stage.
+const TICK: Duration = Duration::from_millis(100);
+
+/// The pause of a virtual user after a failed operation, doubled after each further failure up
+/// to [`MAX_FAILURE_BACKOFF`].
+const MIN_FAILURE_BACKOFF: Duration = Duration::from_millis(50);
+
+/// The longest pause of a virtual user after consecutive failed operations.
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A stage of a time driven load test.
+///
+/// The amount of virtual users moves linearly from the target of the previous stage (or zero) to
+/// `target` over `duration`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stage {
+    /// How long the stage lasts.
+    pub duration: Duration,
+    /// The amount of virtual users at the end of the stage.
+    pub target: usize,
+}
+
+impl FromStr for Stage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (duration, target) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("stage must be in the format <duration>:<target>: {s}"))?;
+        Ok(Stage {
+            duration: parse_duration(duration)?,
+            target: target
+                .parse()
+                .map_err(|e| anyhow!("invalid stage target {target}: {e}"))?,
+        })
+    }
+}
+
+/// The phase of a load test a stage belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    RampUp,
+    Steady,
+    RampDown,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::RampUp => write!(f, "ramp-up"),
+            Phase::Steady => write!(f, "steady"),
+            Phase::RampDown => write!(f, "ramp-down"),
+        }
+    }
+}
+
+/// Returns the phase of every stage, based on the target of the stage before it.
+pub fn phases(stages: &[Stage]) -> Vec<Phase> {
+    let mut from = 0;
+    stages
+        .iter()
+        .map(|stage| {
+            let phase = match stage.target.cmp(&from) {
+                std::cmp::Ordering::Greater => Phase::RampUp,
+                std::cmp::Ordering::Equal => Phase::Steady,
+                std::cmp::Ordering::Less => Phase::RampDown,
+            };
+            from = stage.target;
+            phase
+        })
+        .collect()
+}
+
+/// Returns the amount of virtual users wanted `progress` (0.0 to 1.0) of the way through a stage.
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn wanted_users(from: usize, to: usize, progress: f64) -> usize {
+    let from = from as f64;
+    let to = to as f64;
+    (from + (to - from) * progress).round() as usize
+}
+
+struct VirtualUser<T> {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Vec<Sample<T>>>,
+}
+
+/// Runs operations created by `op` from a pool of virtual users shaped by `stages`.
+///
+/// Every virtual user runs operations back to back until it is stopped, either because the
+/// amount of virtual users decreased or the last stage ended. Operations in flight are allowed to
+/// finish and are tagged with the stage they started in.
+pub async fn run_stages<F, Fut, T>(stages: &[Stage], op: F) -> Vec<Sample<T>>
+where
+    F: Fn(usize, Timer) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, OciDistributionError>> + Send + 'static,
+    T: Send + 'static,
+{
+    let op = Arc::new(op);
+    let run_start = Instant::now();
+    let iterations = Arc::new(AtomicUsize::new(0));
+    let current_stage = Arc::new(AtomicUsize::new(0));
+
+    let mut users: Vec<VirtualUser<T>> = Vec::new();
+    let mut stopped: Vec<VirtualUser<T>> = Vec::new();
+    let mut from = 0;
+
+    for (index, stage) in stages.iter().enumerate() {
+        debug!(
+            stage = index,
+            duration = ?stage.duration,
+            target = stage.target,
+            "Starting stage"
+        );
+        current_stage.store(index, Ordering::Relaxed);
+
+        let start = Instant::now();
+        let end = start + stage.duration;
+        loop {
+            let now = Instant::now();
+            let progress = if stage.duration.is_zero() {
+                1.0
+            } else {
+                ((now - start).as_secs_f64() / stage.duration.as_secs_f64()).min(1.0)
+            };
+
+            let wanted = wanted_users(from, stage.target, progress);
+            while users.len() < wanted {
+                let stop = Arc::new(AtomicBool::new(false));
+                let handle = tokio::task::spawn(virtual_user(
+                    run_start,
+                    stop.clone(),
+                    current_stage.clone(),
+                    iterations.clone(),
+                    op.clone(),
+                ));
+                users.push(VirtualUser { stop, handle });
+            }
+            while users.len() > wanted {
+                if let Some(user) = users.pop() {
+                    user.stop.store(true, Ordering::Relaxed);
+                    stopped.push(user);
+                }
+            }
+
+            if now >= end {
+                break;
+            }
+            tokio::time::sleep(TICK.min(end - now)).await;
+        }
+        from = stage.target;
+    }
+
+    debug!("Waiting for all virtual users to complete");
+    for user in &users {
+        user.stop.store(true, Ordering::Relaxed);
+    }
+    let mut samples = Vec::new();
+    for user in users.into_iter().chain(stopped) {
+        match user.handle.await {
+            Ok(s) => samples.extend(s),
+            Err(e) => samples.push(Sample {
+                stage: None,
+                started: run_start.elapsed(),
+                elapsed: Duration::ZERO,
+                timings: Vec::new(),
+                retries: 0,
+                result: Err(LoadTestError::JoinError(e)),
+            }),
+        }
+    }
+    samples
+}
+
+/// Runs operations one after the other until `stop` is set, backing off after consecutive
+/// failures so that a failing registry isn't hammered in a tight loop.
+async fn virtual_user<F, Fut, T>(
+    run_start: Instant,
+    stop: Arc<AtomicBool>,
+    current_stage: Arc<AtomicUsize>,
+    iterations: Arc<AtomicUsize>,
+    op: Arc<F>,
+) -> Vec<Sample<T>>
+where
+    F: Fn(usize, Timer) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, OciDistributionError>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut samples = Vec::new();
+    let mut backoff = Duration::ZERO;
+    while !stop.load(Ordering::Relaxed) {
+        let i = iterations.fetch_add(1, Ordering::Relaxed);
+        let stage = current_stage.load(Ordering::Relaxed);
+        let timer = Timer::default();
+        let called = Instant::now();
+        let result = tokio::task::spawn(op(i, timer.clone()))
+            .await
+            .map_err(LoadTestError::JoinError)
+            .and_then(|r| r.map_err(LoadTestError::OciDistributionError));
+        let started = timer.started().map_or(called, Instant::from_std);
+        let failed = result.is_err();
+        samples.push(Sample {
+            stage: Some(stage),
+            started: started - run_start,
+            elapsed: started.elapsed(),
+            timings: timer.take(),
+            retries: timer.retries(),
+            result,
+        });
+        backoff = if failed {
+            (backoff * 2).clamp(MIN_FAILURE_BACKOFF, MAX_FAILURE_BACKOFF)
+        } else {
+            Duration::ZERO
+        };
+        if !backoff.is_zero() && !stop.load(Ordering::Relaxed) {
+            tokio::time::sleep(backoff).await;
+        }
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stage_from_str() {
+        assert_eq!(
+            "2m:200".parse::<Stage>().unwrap(),
+            Stage {
+                duration: Duration::from_mins(2),
+                target: 200,
+            }
+        );
+        assert_eq!(
+            "30s:0".parse::<Stage>().unwrap(),
+            Stage {
+                duration: Duration::from_secs(30),
+                target: 0,
+            }
+        );
+        assert!("2m".parse::<Stage>().is_err());
+        assert!("2m:".parse::<Stage>().is_err());
+        assert!("2x:10".parse::<Stage>().is_err());
+        assert!("2m:-1".parse::<Stage>().is_err());
+    }
+
+    #[test]
+    fn phases_follow_the_targets() {
+        let stage = |target| Stage {
+            duration: Duration::from_secs(1),
+            target,
+        };
+        assert_eq!(
+            phases(&[stage(10), stage(10), stage(20), stage(0)]),
+            [Phase::RampUp, Phase::Steady, Phase::RampUp, Phase::RampDown]
+        );
+        assert_eq!(phases(&[stage(0)]), [Phase::Steady]);
+    }
+
+    #[test]
+    fn wanted_users_interpolates_linearly() {
+        assert_eq!(wanted_users(0, 100, 0.0), 0);
+        assert_eq!(wanted_users(0, 100, 0.25), 25);
+        assert_eq!(wanted_users(0, 100, 1.0), 100);
+        assert_eq!(wanted_users(100, 0, 0.25), 75);
+        assert_eq!(wanted_users(10, 10, 0.5), 10);
+        assert_eq!(wanted_users(0, 3, 0.5), 2);
+    }
+
+    #[tokio::test]
+    async fn failing_users_back_off() {
+        let stages = [
+            Stage {
+                duration: Duration::ZERO,
+                target: 1,
+            },
+            Stage {
+                duration: Duration::from_millis(300),
+                target: 1,
+            },
+        ];
+        let samples = run_stages(&stages, |_, _| async {
+            Err::<(), _>(OciDistributionError::GenericError(None))
+        })
+        .await;
+        assert!(!samples.is_empty());
+        assert!(samples.len() < 10, "{} operations", samples.len());
+    }
+
+    #[tokio::test]
+    async fn generating_before_starting_is_not_part_of_the_latency() {
+        let stages = [
+            Stage {
+                duration: Duration::ZERO,
+                target: 1,
+            },
+            Stage {
+                duration: Duration::from_millis(100),
+                target: 1,
+            },
+        ];
+        let samples = run_stages(&stages, |_, timer: Timer| async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            timer.start();
+            Ok(())
+        })
+        .await;
+        assert_eq!(samples.len(), 1);
+        assert!(samples[0].started >= Duration::from_millis(200));
+        assert!(samples[0].elapsed < Duration::from_millis(200));
+    }
+}
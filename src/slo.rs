@@ -0,0 +1,148 @@
+//! Service level objectives a load test has to meet to pass.
+use std::time::Duration;
+
+use crate::{metrics, tester::Sample};
+
+/// The limits a load test has to stay within to pass.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Thresholds {
+    /// The highest fraction (0.0 to 1.0) of operations allowed to fail. No failures are allowed
+    /// when `None`.
+    pub max_error_rate: Option<f64>,
+    /// The highest 99th percentile latency allowed for successful operations.
+    pub max_p99: Option<Duration>,
+}
+
+/// The outcome of checking a single threshold.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub limit: String,
+    pub actual: String,
+    pub passed: bool,
+}
+
+fn percent(rate: f64) -> String {
+    format!("{:.3}%", rate * 100.0)
+}
+
+fn millis(latency: Duration) -> String {
+    format!("{:.2}ms", latency.as_secs_f64() * 1000.0)
+}
+
+/// Checks the samples of a load test against the thresholds.
+#[allow(clippy::cast_precision_loss)]
+pub fn check<T>(thresholds: &Thresholds, samples: &[Sample<T>]) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let errors = samples.iter().filter(|s| s.result.is_err()).count();
+    let error_rate = if samples.is_empty() {
+        0.0
+    } else {
+        errors as f64 / samples.len() as f64
+    };
+    let max_error_rate = thresholds.max_error_rate.unwrap_or(0.0);
+    checks.push(Check {
+        name: "error-rate",
+        limit: percent(max_error_rate),
+        actual: percent(error_rate),
+        passed: error_rate <= max_error_rate,
+    });
+
+    if let Some(max_p99) = thresholds.max_p99 {
+        let mut latency = metrics::latency_histogram();
+        for sample in samples.iter().filter(|s| s.result.is_ok()) {
+            latency
+                .saturating_record(u64::try_from(sample.elapsed.as_micros()).unwrap_or(u64::MAX));
+        }
+        let p99 = Duration::from_micros(latency.value_at_quantile(0.99));
+        checks.push(Check {
+            name: "p99",
+            limit: millis(max_p99),
+            actual: millis(p99),
+            passed: p99 <= max_p99,
+        });
+    }
+
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_distribution::errors::OciDistributionError;
+
+    use super::*;
+    use crate::tester::LoadTestError;
+
+    fn sample(elapsed_ms: u64, success: bool) -> Sample<()> {
+        Sample {
+            stage: None,
+            started: Duration::ZERO,
+            elapsed: Duration::from_millis(elapsed_ms),
+            timings: Vec::new(),
+            retries: 0,
+            result: if success {
+                Ok(())
+            } else {
+                Err(LoadTestError::OciDistributionError(
+                    OciDistributionError::ServerError {
+                        code: 503,
+                        url: String::new(),
+                        message: String::new(),
+                    },
+                ))
+            },
+        }
+    }
+
+    #[test]
+    fn no_errors_are_allowed_by_default() {
+        let checks = check(
+            &Thresholds::default(),
+            &[sample(10, true), sample(10, false)],
+        );
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].name, "error-rate");
+        assert_eq!(checks[0].actual, "50.000%");
+        assert!(!checks[0].passed);
+
+        let checks = check(&Thresholds::default(), &[sample(10, true)]);
+        assert!(checks[0].passed);
+    }
+
+    #[test]
+    fn error_rate_is_checked_against_the_limit() {
+        let samples: Vec<_> = (0..10).map(|i| sample(10, i != 0)).collect();
+        let thresholds = |rate| Thresholds {
+            max_error_rate: Some(rate),
+            max_p99: None,
+        };
+        assert!(check(&thresholds(0.1), &samples)[0].passed);
+        assert!(!check(&thresholds(0.05), &samples)[0].passed);
+    }
+
+    #[test]
+    fn an_empty_run_passes() {
+        let checks = check::<()>(&Thresholds::default(), &[]);
+        assert_eq!(checks[0].actual, "0.000%");
+        assert!(checks[0].passed);
+    }
+
+    #[test]
+    fn p99_only_counts_successful_operations() {
+        let mut samples: Vec<_> = (0..100).map(|_| sample(10, true)).collect();
+        samples.push(sample(5000, false));
+        let thresholds = |max_p99| Thresholds {
+            max_error_rate: Some(1.0),
+            max_p99: Some(max_p99),
+        };
+
+        let checks = check(&thresholds(Duration::from_millis(20)), &samples);
+        assert_eq!(checks[1].name, "p99");
+        assert!(checks[1].passed);
+
+        samples.push(sample(100, true));
+        samples.push(sample(100, true));
+        assert!(!check(&thresholds(Duration::from_millis(20)), &samples)[1].passed);
+    }
+}
@@ -1,17 +1,34 @@
-use std::fmt::Display;
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use futures::future;
 use oci_distribution::{
     client::{ClientProtocol, PushResponse},
     errors::OciDistributionError,
-    secrets::RegistryAuth,
+    manifest::{ImageIndexEntry, Platform, OCI_IMAGE_MEDIA_TYPE},
     Reference,
 };
+use rand::rngs::StdRng;
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::Semaphore,
+    time::{interval, MissedTickBehavior},
+};
 use tracing::{debug, instrument};
 
 use crate::{
-    client,
-    fake::{self, MEGABYTE},
+    client::{self, Clients, Connections, MountFrom, PullPlatform},
+    compression::{Compressibility, CompressionMix},
+    distribution::Distribution,
+    fake::{self, Layer, LayerOptions, LayerPool, Tree, MEGABYTE},
+    metrics::{Operation, Timer, Timing},
+    registry::{oci_error_code, Credentials, DigestMismatch, HttpOptions, UploadMode},
+    scheduler::{self, Stage},
 };
 
 pub enum LoadTestError {
@@ -19,116 +36,1121 @@ pub enum LoadTestError {
     JoinError(tokio::task::JoinError),
 }
 
+impl LoadTestError {
+    /// Returns the name of the error variant, or `Timeout` for a request which timed out or
+    /// stalled.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            _ if self.is_timeout() => "Timeout",
+            LoadTestError::OciDistributionError(_) => "OciDistributionError",
+            LoadTestError::JoinError(_) => "JoinError",
+        }
+    }
+
+    /// Returns whether a request of the operation timed out, or its transfer stalled.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            LoadTestError::OciDistributionError(OciDistributionError::RequestError(e)) => {
+                e.is_timeout()
+            }
+            LoadTestError::OciDistributionError(OciDistributionError::IoError(e)) => {
+                e.kind() == std::io::ErrorKind::TimedOut
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the category of the error.
+    pub fn category(&self) -> ErrorCategory {
+        let e = match self {
+            LoadTestError::OciDistributionError(e) => e,
+            LoadTestError::JoinError(_) => return ErrorCategory::Task,
+        };
+        match e {
+            OciDistributionError::AuthenticationFailure(_)
+            | OciDistributionError::UnauthorizedError { .. }
+            | OciDistributionError::ServerError { code: 401, .. } => ErrorCategory::Auth,
+            OciDistributionError::ServerError { code, message, .. } => {
+                match oci_error_code(message) {
+                    Some(oci) if oci == "DIGEST_INVALID" => ErrorCategory::DigestMismatch,
+                    oci => ErrorCategory::Status(Some(*code), oci),
+                }
+            }
+            OciDistributionError::RegistryError { envelope, .. } => ErrorCategory::Status(
+                None,
+                envelope
+                    .errors
+                    .first()
+                    .and_then(|e| serde_json::to_value(&e.code).ok())
+                    .and_then(|code| code.as_str().map(str::to_string)),
+            ),
+            OciDistributionError::RequestError(e) => request_category(e),
+            OciDistributionError::IoError(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                ErrorCategory::Timeout
+            }
+            OciDistributionError::IoError(e)
+                if e.get_ref()
+                    .and_then(|e| e.downcast_ref::<DigestMismatch>())
+                    .is_some() =>
+            {
+                ErrorCategory::DigestMismatch
+            }
+            OciDistributionError::JsonError(_)
+            | OciDistributionError::HeaderValueError(_)
+            | OciDistributionError::ManifestEncodingError(_)
+            | OciDistributionError::ManifestParsingError(_)
+            | OciDistributionError::RegistryNoLocationError
+            | OciDistributionError::RegistryNoDigestError
+            | OciDistributionError::SpecViolationError(_)
+            | OciDistributionError::UnsupportedMediaTypeError(_)
+            | OciDistributionError::UnsupportedSchemaVersionError(_)
+            | OciDistributionError::VersionedParsingError(_) => ErrorCategory::InvalidResponse,
+            _ => ErrorCategory::Other,
+        }
+    }
+
+    /// Returns the HTTP status code the registry responded with, when known.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            LoadTestError::OciDistributionError(OciDistributionError::ServerError {
+                code, ..
+            }) => Some(*code),
+            LoadTestError::OciDistributionError(OciDistributionError::UnauthorizedError {
+                ..
+            }) => Some(401),
+            LoadTestError::OciDistributionError(OciDistributionError::RequestError(e)) => {
+                e.status().map(|s| s.as_u16())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What kind of failure an operation failed with, which the failures of a run are counted by.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorCategory {
+    /// The registry or its token service rejected the credentials, or asked for an
+    /// authentication the client can't perform.
+    Auth,
+    /// The registry answered with an unsuccessful status, and the code of the OCI error in the
+    /// body when there is one, such as `503 UNAVAILABLE`.
+    Status(Option<u16>, Option<String>),
+    /// A request timed out, or its transfer stalled.
+    Timeout,
+    /// The connection to the registry couldn't be made.
+    Connect,
+    /// The connection was reset or closed before the response was complete.
+    ConnectionReset,
+    /// The TLS handshake failed, such as for an untrusted certificate.
+    Tls,
+    /// Content didn't match its digest, when downloaded or according to the registry.
+    DigestMismatch,
+    /// The response couldn't be parsed or violated the distribution specification.
+    InvalidResponse,
+    /// The task running the operation panicked or was cancelled.
+    Task,
+    /// A failure that fits none of the other categories, such as an unexpected client error.
+    Other,
+}
+
+impl Display for ErrorCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCategory::Auth => write!(f, "auth failure"),
+            ErrorCategory::Status(Some(status), Some(code)) => write!(f, "{status} {code}"),
+            ErrorCategory::Status(Some(status), None) => write!(f, "{status}"),
+            ErrorCategory::Status(None, Some(code)) => write!(f, "{code}"),
+            ErrorCategory::Status(None, None) => write!(f, "unknown status"),
+            ErrorCategory::Timeout => write!(f, "timeout"),
+            ErrorCategory::Connect => write!(f, "connection failure"),
+            ErrorCategory::ConnectionReset => write!(f, "connection reset"),
+            ErrorCategory::Tls => write!(f, "TLS handshake"),
+            ErrorCategory::DigestMismatch => write!(f, "digest mismatch"),
+            ErrorCategory::InvalidResponse => write!(f, "invalid response"),
+            ErrorCategory::Task => write!(f, "task failure"),
+            ErrorCategory::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Classifies a failed request by the errors it was caused by: the I/O errors of the
+/// connection, and the errors of `hyper` for connections closed early.
+fn request_category(e: &reqwest::Error) -> ErrorCategory {
+    if e.is_timeout() {
+        return ErrorCategory::Timeout;
+    }
+    if let Some(status) = e.status() {
+        return ErrorCategory::Status(Some(status.as_u16()), None);
+    }
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(error) = source {
+        if let Some(io) = error.downcast_ref::<std::io::Error>() {
+            match io.kind() {
+                std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::UnexpectedEof => return ErrorCategory::ConnectionReset,
+                // rustls reports the failures of the handshake as invalid data.
+                std::io::ErrorKind::InvalidData if e.is_connect() => return ErrorCategory::Tls,
+                _ => {}
+            }
+        }
+        if let Some(hyper) = error.downcast_ref::<hyper::Error>() {
+            if hyper.is_incomplete_message() || hyper.is_closed() || hyper.is_canceled() {
+                return ErrorCategory::ConnectionReset;
+            }
+        }
+        // I/O errors skip the error they wrap when asked for their source.
+        source = match error.downcast_ref::<std::io::Error>() {
+            Some(io) => io
+                .get_ref()
+                .map(|e| e as &(dyn std::error::Error + 'static)),
+            None => error.source(),
+        };
+    }
+    if e.is_connect() {
+        ErrorCategory::Connect
+    } else if e.is_decode() {
+        ErrorCategory::InvalidResponse
+    } else {
+        ErrorCategory::Other
+    }
+}
+
 impl Display for LoadTestError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            LoadTestError::OciDistributionError(e) if self.is_timeout() => {
+                write!(f, "Timeout: {e}")
+            }
             LoadTestError::OciDistributionError(e) => write!(f, "OciDistributionError: {e}"),
             LoadTestError::JoinError(e) => write!(f, "JoinError: {e}"),
         }
     }
 }
 
+/// The outcome of a single load test operation.
+pub struct Sample<T> {
+    /// The stage the operation started in, for time driven load tests.
+    pub stage: Option<usize>,
+    /// When the operation started, relative to the start of the load test.
+    pub started: Duration,
+    /// How long the operation took.
+    pub elapsed: Duration,
+    /// The timings of the registry operations performed.
+    pub timings: Vec<Timing>,
+    /// The amount of requests retried after a transient failure. The operation succeeded at the
+    /// first attempt when it succeeded without any.
+    pub retries: u32,
+    pub result: Result<T, LoadTestError>,
+}
+
+/// Controls how the operations of a load test are scheduled and connect to the registry.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// The maximum amount of operations in flight at once. Unbounded when `None`.
+    pub concurrency: Option<usize>,
+    /// The amount of operations started per second. All operations start at once when `None`.
+    pub rate: Option<f64>,
+    /// The stages of a time driven load test. When not empty, the operation count, concurrency
+    /// and rate are ignored and operations are run by virtual users instead.
+    pub stages: Vec<Stage>,
+    /// Whether operations share connections and tokens, or each start cold.
+    pub connections: Connections,
+    /// How the HTTP connections to the registry are made. Set from the network options by the
+    /// commands.
+    pub http: HttpOptions,
+}
+
+/// Controls how images are pushed.
+#[derive(Debug, Clone)]
+pub struct PushOptions {
+    /// How blobs are uploaded.
+    pub upload_mode: UploadMode,
+    /// The size of the chunks blobs are uploaded in with [`UploadMode::Chunked`].
+    pub chunk_size: usize,
+    /// Whether to check if the registry has a blob with a `HEAD` before uploading it.
+    pub check_exists: bool,
+    /// The distribution of the amount of layers of every image, which is at least 1.
+    pub layers: Distribution,
+    /// The distribution of the sizes of the layers.
+    pub layer_size: Distribution,
+    /// The mix of compressions of the layers.
+    pub compression: CompressionMix,
+    /// How well the content of the layers compresses.
+    pub compressibility: Compressibility,
+    /// The amount of entries of every layer, directories aside. Layers of more than one entry hold
+    /// a tree of files, directories, symlinks, hardlinks and whiteouts.
+    pub files: usize,
+    /// The depth of the directories of the files of every layer.
+    pub depth: usize,
+    /// The fraction (0.0 to 1.0) of the layers of every image picked from a pool of shared layers.
+    pub shared_layers: f64,
+    /// The amount of layers in the pool of shared layers.
+    pub layer_pool: usize,
+    /// Whether to generate the content of layers as it's uploaded instead of holding it in memory,
+    /// so that layers of any size can be pushed. Streamed layers are always uploaded in chunks.
+    pub stream_layers: bool,
+    /// The amount of repositories to spread the images over. Every image gets its own repository
+    /// when `None`.
+    pub repositories: Option<usize>,
+    /// The seed of the generated content. The same seed always generates the same images, and a
+    /// random seed is used when `None`.
+    pub seed: Option<u64>,
+}
+
+impl PushOptions {
+    /// Returns the random number generator for the `index`th generated image.
+    fn rng(&self, index: u64) -> StdRng {
+        fake::seeded_rng(self.seed.unwrap_or_else(rand::random), index)
+    }
+
+    /// Returns how the layers of the generated images are generated.
+    fn layer_options(&self) -> LayerOptions<'_> {
+        LayerOptions {
+            sizes: &self.layer_size,
+            compression: &self.compression,
+            compressibility: self.compressibility,
+            tree: Tree {
+                files: self.files,
+                depth: self.depth,
+            },
+            stream: self.stream_layers,
+        }
+    }
+}
+
+/// The generator index of the pool of shared layers, which image indexes never reach.
+const LAYER_POOL_INDEX: u64 = u64::MAX;
+/// The generator index of the base image of [`load_test_mount`].
+const BASE_IMAGE_INDEX: u64 = u64::MAX - 1;
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        PushOptions {
+            upload_mode: UploadMode::default(),
+            chunk_size: 5 * MEGABYTE,
+            check_exists: false,
+            layers: Distribution::Fixed(1),
+            layer_size: Distribution::Fixed(10 * MEGABYTE),
+            compression: CompressionMix::default(),
+            compressibility: Compressibility::default(),
+            files: Tree::default().files,
+            depth: Tree::default().depth,
+            shared_layers: 0.0,
+            layer_pool: 10,
+            stream_layers: false,
+            repositories: None,
+            seed: None,
+        }
+    }
+}
+
+/// Runs the operations created by `op`, either `count` times or driven by the stages in `options`.
+async fn run<F, Fut, T>(count: usize, options: &LoadOptions, op: F) -> Vec<Sample<T>>
+where
+    F: Fn(usize, Timer) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, OciDistributionError>> + Send + 'static,
+    T: Send + 'static,
+{
+    if options.stages.is_empty() {
+        run_operations(count, options, op).await
+    } else {
+        scheduler::run_stages(&options.stages, op).await
+    }
+}
+
+/// Runs `count` operations created by `op`, honouring the concurrency limit and arrival rate.
+///
+/// Arrivals are open-loop: operations are started at `rate` regardless of how many are still in
+/// flight, and wait for a free worker when the concurrency limit is reached. Operations are only
+/// created once they have a worker, so that waiting operations hold nothing.
+async fn run_operations<F, Fut, T>(count: usize, options: &LoadOptions, op: F) -> Vec<Sample<T>>
+where
+    F: Fn(usize, Timer) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, OciDistributionError>> + Send + 'static,
+    T: Send + 'static,
+{
+    let op = Arc::new(op);
+    let workers = options.concurrency.map(|c| Arc::new(Semaphore::new(c)));
+    let mut arrivals = options.rate.map(|rate| {
+        // Rates beyond a nanosecond apart would make a zero period, which intervals reject.
+        let period = Duration::from_secs_f64(1.0 / rate).max(Duration::from_nanos(1));
+        let mut arrivals = interval(period);
+        arrivals.set_missed_tick_behavior(MissedTickBehavior::Burst);
+        arrivals
+    });
+
+    let start = Instant::now();
+    let mut timers = Vec::with_capacity(count);
+    let mut handles = Vec::with_capacity(count);
+    for i in 0..count {
+        if let Some(arrivals) = arrivals.as_mut() {
+            arrivals.tick().await;
+        }
+        debug!("Kicking off operation {i}");
+
+        let op = op.clone();
+        let workers = workers.clone();
+        let timer = Timer::default();
+        timers.push((start.elapsed(), timer.clone()));
+        handles.push(tokio::task::spawn(async move {
+            let _permit = match workers {
+                Some(workers) => Some(
+                    workers
+                        .acquire_owned()
+                        .await
+                        .expect("worker pool is never closed"),
+                ),
+                None => None,
+            };
+            let acquired = Instant::now();
+            let result = op(i, timer.clone()).await;
+            let started = timer.started().unwrap_or(acquired);
+            (started - start, started.elapsed(), result)
+        }));
+    }
+    debug!("Waiting for all operations to complete");
+    let results = future::join_all(handles).await;
+    results
+        .into_iter()
+        .zip(timers)
+        .map(|(r, (spawned, timer))| match r {
+            Ok((started, elapsed, result)) => Sample {
+                stage: None,
+                started,
+                elapsed,
+                timings: timer.take(),
+                retries: timer.retries(),
+                result: result.map_err(LoadTestError::OciDistributionError),
+            },
+            Err(e) => Sample {
+                stage: None,
+                started: spawned,
+                elapsed: Duration::ZERO,
+                timings: timer.take(),
+                retries: timer.retries(),
+                result: Err(LoadTestError::JoinError(e)),
+            },
+        })
+        .collect()
+}
+
 /// Load tests a registry by pushing images to it.
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip(auth, protocol), level = "debug")]
 pub async fn load_test_push(
     image_count: usize,
     host: String,
-    auth: RegistryAuth,
+    auth: Credentials,
     protocol: ClientProtocol,
     namespace: String,
     image: String,
     tag: String,
-) -> Vec<Result<PushResponse, LoadTestError>> {
-    let mut handles = Vec::new();
-
-    for i in 0..image_count {
-        debug!("Kicking off push for image {i}");
+    options: LoadOptions,
+    push: PushOptions,
+) -> Vec<Sample<PushResponse>> {
+    let pool = Arc::new(gen_layer_pool(&push).await);
+    let clients = Clients::new(options.connections, options.http.clone());
+    run(image_count, &options, move |i, timer| {
+        let reference = push_reference(&host, &namespace, &image, &tag, i, push.repositories);
+        let pool = pool.clone();
+        let clients = clients.clone();
+        let auth = auth.clone();
+        let protocol = protocol.clone();
+        let push = push.clone();
+        async move {
+            let layers = gen_image_layers(pool, push.clone(), i).await;
+            timer.start();
+            push_reg_image(
+                clients, reference, layers, auth, protocol, push, None, timer,
+            )
+            .await
+        }
+    })
+    .await
+}
 
-        let reference: Reference = format!("{host}/{namespace}/{image}-{i}:{tag}")
-            .parse()
-            .unwrap();
-        let h = tokio::task::spawn(push_reg_image(reference, auth.clone(), protocol.clone()));
-        handles.push(h);
+/// Generates the pool of shared layers, which is empty when no layers are shared.
+async fn gen_layer_pool(push: &PushOptions) -> LayerPool {
+    if push.shared_layers <= 0.0 {
+        return LayerPool::default();
     }
-    debug!("Waiting for all pushes to complete");
-    let results = future::join_all(handles).await;
-    let results: Vec<Result<PushResponse, LoadTestError>> = results
-        .into_iter()
-        .map(|r| {
-            r.map_err(LoadTestError::JoinError)
-                .and_then(|r| r.map_err(LoadTestError::OciDistributionError))
+    let push = push.clone();
+    spawn_blocking(move || {
+        LayerPool::new(
+            &mut push.rng(LAYER_POOL_INDEX),
+            push.layer_options(),
+            push.layer_pool,
+        )
+    })
+    .await
+}
+
+/// Generates the layers of the `i`th pushed image, some of them picked from `pool`. The layers are
+/// generated on the blocking thread pool, so that generating them doesn't hold up the operations
+/// in flight.
+async fn gen_image_layers(pool: Arc<LayerPool>, push: PushOptions, i: usize) -> Vec<Layer> {
+    spawn_blocking(move || {
+        let mut rng = push.rng(i as u64);
+        let count = push.layers.sample(&mut rng).max(1);
+        pool.gen_layers(&mut rng, push.layer_options(), count, push.shared_layers)
+    })
+    .await
+}
+
+/// Runs the CPU bound `f` on the blocking thread pool, passing its panics on.
+pub(crate) async fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+/// Returns the reference of the `i`th pushed image, in a repository of its own or in one of
+/// `repositories` repositories.
+fn push_reference(
+    host: &str,
+    namespace: &str,
+    image: &str,
+    tag: &str,
+    i: usize,
+    repositories: Option<usize>,
+) -> Reference {
+    let reference = match repositories {
+        Some(repositories) => format!("{host}/{namespace}/{image}-{}:{tag}-{i}", i % repositories),
+        None => format!("{host}/{namespace}/{image}-{i}:{tag}"),
+    };
+    reference.parse().unwrap()
+}
+
+/// Load tests cross repository blob mounts, by pushing a base image once and then images derived
+/// from it into other repositories, mounting the blobs they share with the base image.
+///
+/// The base image and the derived images have as many layers as `push` asks for. Every derived
+/// image adds its own layers, some of them from the pool of shared layers, on top of the layers of
+/// the base image.
+///
+/// # Errors
+///
+/// * If the base image can't be pushed
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(auth, protocol), level = "debug")]
+pub async fn load_test_mount(
+    image_count: usize,
+    host: String,
+    auth: Credentials,
+    protocol: ClientProtocol,
+    namespace: String,
+    image: String,
+    tag: String,
+    options: LoadOptions,
+    push: PushOptions,
+) -> Result<Vec<Sample<PushResponse>>, OciDistributionError> {
+    let base_repository = format!("{namespace}/{image}-base");
+    let base_layers = {
+        let push = push.clone();
+        spawn_blocking(move || {
+            let mut rng = push.rng(BASE_IMAGE_INDEX);
+            let count = push.layers.sample(&mut rng).max(1);
+            fake::gen_layers(&mut rng, push.layer_options(), count)
         })
-        .collect();
-    results
+        .await
+    };
+    let (config, manifest) = fake::gen_manifest(&base_layers)?;
+    let mut digests: HashSet<String> = base_layers.iter().map(Layer::digest).collect();
+    digests.insert(manifest.config.digest.clone());
+
+    let clients = Clients::new(options.connections, options.http.clone());
+    let reference: Reference = format!("{host}/{base_repository}:{tag}").parse().unwrap();
+    debug!(reference = reference.whole(), "Pushing the base image");
+    Box::pin(client::push_image(
+        &clients,
+        base_layers.clone(),
+        config,
+        reference,
+        manifest,
+        &auth,
+        protocol.clone(),
+        &push,
+        None,
+        &Timer::default(),
+    ))
+    .await?;
+
+    let mount = Arc::new(MountFrom {
+        repository: base_repository,
+        digests,
+    });
+    let pool = Arc::new(gen_layer_pool(&push).await);
+    let samples = run(image_count, &options, move |i, timer| {
+        let reference = push_reference(&host, &namespace, &image, &tag, i, push.repositories);
+        let mut layers = base_layers.clone();
+        let clients = clients.clone();
+        let auth = auth.clone();
+        let protocol = protocol.clone();
+        let push = push.clone();
+        let mount = mount.clone();
+        let pool = pool.clone();
+        async move {
+            layers.extend(gen_image_layers(pool, push.clone(), i).await);
+            timer.start();
+            let mount = Some(mount);
+            push_reg_image(
+                clients, reference, layers, auth, protocol, push, mount, timer,
+            )
+            .await
+        }
+    })
+    .await;
+    Ok(samples)
 }
 
-#[instrument(level = "debug", skip(auth, protocol))]
+#[allow(clippy::too_many_arguments)]
+#[instrument(
+    level = "debug",
+    skip(clients, layers, auth, protocol, push, mount, timer)
+)]
 async fn push_reg_image(
+    clients: Clients,
     reference: Reference,
-    auth: RegistryAuth,
+    layers: Vec<Layer>,
+    auth: Credentials,
     protocol: ClientProtocol,
+    push: PushOptions,
+    mount: Option<Arc<MountFrom>>,
+    timer: Timer,
 ) -> Result<PushResponse, OciDistributionError> {
-    let layers = crate::fake::gen_rand_layers(10 * MEGABYTE, 1);
-    let image = crate::fake::gen_image(layers).unwrap();
+    let (config, manifest) = crate::fake::gen_manifest(&layers).unwrap();
 
-    let res = crate::client::push_image(
-        image.layers,
-        image.config,
-        reference,
-        image.manifest,
-        &auth,
-        protocol,
-    )
-    .await?;
+    let bytes = layers.iter().map(Layer::len).sum();
+    let res = timer
+        .time(
+            Operation::ImagePush,
+            bytes,
+            Box::pin(crate::client::push_image(
+                &clients,
+                layers,
+                config,
+                reference,
+                manifest,
+                &auth,
+                protocol,
+                &push,
+                mount.as_deref(),
+                &timer,
+            )),
+        )
+        .await?;
     Ok(res)
 }
 
-/// Load tests a registry by pulling an image from it.
+/// Pushes the `index`th image of an index, for `platform`, by digest into the repository of
+/// `image`. Returns the entry of the image in the index.
+#[allow(clippy::too_many_arguments)]
+async fn push_platform_image(
+    clients: &Clients,
+    image: &Reference,
+    auth: &Credentials,
+    protocol: &ClientProtocol,
+    push: &PushOptions,
+    platform: &Platform,
+    index: u64,
+    timer: &Timer,
+) -> Result<ImageIndexEntry, OciDistributionError> {
+    let layers = {
+        let push = push.clone();
+        spawn_blocking(move || {
+            let mut rng = push.rng(index);
+            let count = push.layers.sample(&mut rng).max(1);
+            fake::gen_layers(&mut rng, push.layer_options(), count)
+        })
+        .await
+    };
+    let (config, manifest) = fake::gen_platform_manifest(&layers, platform)?;
+    let body = serde_json::to_vec(&manifest)?;
+    let digest = format!("sha256:{:x}", Sha256::digest(&body));
+    let reference = Reference::with_digest(
+        image.registry().to_string(),
+        image.repository().to_string(),
+        digest.clone(),
+    );
+    debug!(
+        reference = reference.whole(),
+        "Pushing the image of {platform:?}"
+    );
+
+    let bytes = layers.iter().map(Layer::len).sum();
+    timer
+        .time(
+            Operation::ImagePush,
+            bytes,
+            Box::pin(client::push_image(
+                clients,
+                layers,
+                config,
+                reference,
+                manifest,
+                auth,
+                protocol.clone(),
+                push,
+                None,
+                timer,
+            )),
+        )
+        .await?;
+
+    #[allow(clippy::cast_possible_wrap)]
+    let size = body.len() as i64;
+    Ok(ImageIndexEntry {
+        media_type: OCI_IMAGE_MEDIA_TYPE.to_string(),
+        digest,
+        size,
+        platform: Some(platform.clone()),
+        annotations: None,
+    })
+}
+
+/// Load tests a registry by pulling an image, or the images of an index `platform` asks for,
+/// from it.
 #[instrument(skip(auth, protocol), level = "debug")]
 pub async fn load_test_pull(
     image_count: usize,
     image: Reference,
-    auth: RegistryAuth,
+    auth: Credentials,
     protocol: ClientProtocol,
-) -> Vec<Result<(), LoadTestError>> {
-    let mut handles = Vec::new();
-
-    for _ in 0..image_count {
-        debug!("Kicking off pull for image {image}");
-        let h = tokio::task::spawn(pull_reg_image(
+    platform: PullPlatform,
+    options: LoadOptions,
+) -> Vec<Sample<()>> {
+    let clients = Clients::new(options.connections, options.http.clone());
+    run(image_count, &options, move |_, timer| {
+        pull_reg_image(
+            clients.clone(),
             image.clone(),
             auth.clone(),
             protocol.clone(),
-        ));
-        handles.push(h);
-    }
-    debug!("Waiting for all pulls to complete");
-    let results = future::join_all(handles).await;
-    let results: Vec<Result<(), LoadTestError>> = results
-        .into_iter()
-        .map(|r| {
-            r.map_err(LoadTestError::JoinError)
-                .and_then(|r| r.map_err(LoadTestError::OciDistributionError))
-        })
-        .collect();
-    results
+            platform.clone(),
+            timer,
+        )
+    })
+    .await
 }
 
-#[instrument(level = "debug", skip(auth, protocol))]
+#[instrument(level = "debug", skip(clients, auth, protocol, timer))]
 async fn pull_reg_image(
+    clients: Clients,
     image: Reference,
-    auth: RegistryAuth,
+    auth: Credentials,
     protocol: ClientProtocol,
+    platform: PullPlatform,
+    timer: Timer,
 ) -> Result<(), OciDistributionError> {
-    crate::client::pull_image(protocol, image, auth).await?;
+    let start = Instant::now();
+    let result =
+        crate::client::pull_image(&clients, protocol, image, auth, &platform, &timer).await;
+    timer.record(Timing {
+        operation: Operation::ImagePull,
+        elapsed: start.elapsed(),
+        bytes: result.as_ref().map_or(0, |images| {
+            images
+                .iter()
+                .flat_map(|image| &image.layers)
+                .map(|l| l.data.len() as u64)
+                .sum()
+        }),
+        success: result.is_ok(),
+    });
+    result?;
     Ok(())
 }
 
+/// Pushes an image index. Pushes an image for every platform of `platforms` by digest first and
+/// indexes them, or indexes an image which was never pushed when there are no platforms.
 pub async fn push_image_index(
     image: Reference,
-    auth: RegistryAuth,
+    auth: Credentials,
     protocol: ClientProtocol,
+    push: &PushOptions,
+    platforms: &[Platform],
+    http: &HttpOptions,
+    timer: &Timer,
 ) -> Result<String, OciDistributionError> {
-    let index = fake::gen_oci_image_index();
-    client::push_image_list(image, index, &auth, protocol).await
+    let index = if platforms.is_empty() {
+        fake::gen_oci_image_index()
+    } else {
+        let clients = Clients::new(Connections::Cold, http.clone());
+        let mut manifests = Vec::with_capacity(platforms.len());
+        for (i, platform) in platforms.iter().enumerate() {
+            manifests.push(
+                push_platform_image(
+                    &clients, &image, &auth, &protocol, push, platform, i as u64, timer,
+                )
+                .await?,
+            );
+        }
+        fake::gen_platform_image_index(manifests)
+    };
+    let size = serde_json::to_vec(&index).map_or(0, |index| index.len() as u64);
+    timer
+        .time(
+            Operation::ManifestPut,
+            size,
+            client::push_image_list(image, index, &auth, protocol, http, timer),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    use hyper::{
+        header,
+        service::{make_service_fn, service_fn},
+        Body, Method, Request, Response, Server, StatusCode,
+    };
+    use oci_distribution::manifest::OciImageIndex;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::cli::parse_platform;
+
+    fn server_error(code: u16, message: &str) -> LoadTestError {
+        LoadTestError::OciDistributionError(OciDistributionError::ServerError {
+            code,
+            url: String::new(),
+            message: message.to_string(),
+        })
+    }
+
+    /// Accepts connections, reads the request, and answers with `response` or closes the
+    /// connection without answering when `None`. Returns the address it listens at.
+    async fn serve(response: Option<&'static [u8]>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let _ = socket.read(&mut buf).await;
+                    match response {
+                        Some(response) => {
+                            let _ = socket.write_all(response).await;
+                            tokio::time::sleep(Duration::from_secs(5)).await;
+                        }
+                        None => drop(socket),
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// Returns the category of the error of a `GET` of `url`.
+    async fn get_category(url: String) -> ErrorCategory {
+        let http = reqwest::Client::builder()
+            .no_proxy()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let error = http.get(url).send().await.unwrap_err();
+        LoadTestError::OciDistributionError(OciDistributionError::RequestError(error)).category()
+    }
+
+    #[test]
+    fn server_errors_are_categorized_by_status_and_code() {
+        assert_eq!(
+            server_error(503, r#"{"errors":[{"code":"UNAVAILABLE"}]}"#).category(),
+            ErrorCategory::Status(Some(503), Some("UNAVAILABLE".to_string()))
+        );
+        assert_eq!(
+            server_error(502, "Bad Gateway").category(),
+            ErrorCategory::Status(Some(502), None)
+        );
+        assert_eq!(server_error(401, "").category(), ErrorCategory::Auth);
+        assert_eq!(
+            server_error(400, r#"{"errors":[{"code":"DIGEST_INVALID"}]}"#).category(),
+            ErrorCategory::DigestMismatch
+        );
+        assert_eq!(server_error(503, "").status(), Some(503));
+    }
+
+    #[test]
+    fn io_errors_are_categorized() {
+        let timed_out = LoadTestError::OciDistributionError(OciDistributionError::IoError(
+            io::Error::new(io::ErrorKind::TimedOut, "stalled"),
+        ));
+        assert_eq!(timed_out.category(), ErrorCategory::Timeout);
+        assert_eq!(timed_out.variant(), "Timeout");
+
+        let mismatch =
+            LoadTestError::OciDistributionError(OciDistributionError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                DigestMismatch {
+                    expected: "sha256:0".to_string(),
+                    actual: "sha256:1".to_string(),
+                },
+            )));
+        assert_eq!(mismatch.category(), ErrorCategory::DigestMismatch);
+        assert_eq!(mismatch.variant(), "OciDistributionError");
+    }
+
+    #[test]
+    fn categories_are_displayed_for_the_summary() {
+        assert_eq!(
+            ErrorCategory::Status(Some(503), Some("UNAVAILABLE".to_string())).to_string(),
+            "503 UNAVAILABLE"
+        );
+        assert_eq!(ErrorCategory::Status(Some(502), None).to_string(), "502");
+        assert_eq!(ErrorCategory::Tls.to_string(), "TLS handshake");
+    }
+
+    #[tokio::test]
+    async fn refused_connections_are_connection_failures() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert_eq!(
+            get_category(format!("http://{addr}/v2/")).await,
+            ErrorCategory::Connect
+        );
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_connection_resets() {
+        let addr = serve(None).await;
+        assert_eq!(
+            get_category(format!("http://{addr}/v2/")).await,
+            ErrorCategory::ConnectionReset
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_requests_are_timeouts() {
+        let addr = serve(Some(b"")).await;
+        assert_eq!(
+            get_category(format!("http://{addr}/v2/")).await,
+            ErrorCategory::Timeout
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_handshakes_are_tls_failures() {
+        let addr = serve(Some(b"HTTP/1.1 400 Bad Request\r\n\r\n")).await;
+        assert_eq!(
+            get_category(format!("https://{addr}/v2/")).await,
+            ErrorCategory::Tls
+        );
+    }
+
+    #[tokio::test]
+    async fn unsuccessful_statuses_are_categorized_by_status() {
+        let addr = serve(Some(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")).await;
+        let http = reqwest::Client::builder().no_proxy().build().unwrap();
+        let error = http
+            .get(format!("http://{addr}/v2/"))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap_err();
+        assert_eq!(
+            LoadTestError::OciDistributionError(OciDistributionError::RequestError(error))
+                .category(),
+            ErrorCategory::Status(Some(404), None)
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrency_bounds_the_operations_created_and_in_flight() {
+        let options = LoadOptions {
+            concurrency: Some(3),
+            ..LoadOptions::default()
+        };
+        let created = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let high_water = Arc::new(AtomicUsize::new(0));
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let samples = {
+            let (created, finished) = (created.clone(), finished.clone());
+            let (in_flight, high_water) = (in_flight.clone(), high_water.clone());
+            let waiting = waiting.clone();
+            run_operations(20, &options, move |_, _| {
+                let alive =
+                    created.fetch_add(1, Ordering::SeqCst) + 1 - finished.load(Ordering::SeqCst);
+                waiting.fetch_max(alive, Ordering::SeqCst);
+                let (finished, in_flight) = (finished.clone(), in_flight.clone());
+                let high_water = high_water.clone();
+                async move {
+                    let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    high_water.fetch_max(n, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    finished.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            })
+            .await
+        };
+
+        assert_eq!(samples.len(), 20);
+        assert!(samples.iter().all(|s| s.result.is_ok()));
+        assert_eq!(created.load(Ordering::SeqCst), 20);
+        assert_eq!(high_water.load(Ordering::SeqCst), 3);
+        assert!(waiting.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_rate_spaces_the_arrivals() {
+        let options = LoadOptions {
+            rate: Some(10.0),
+            ..LoadOptions::default()
+        };
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let samples = {
+            let arrivals = arrivals.clone();
+            run_operations(5, &options, move |_, _| {
+                arrivals.lock().unwrap().push(tokio::time::Instant::now());
+                async { Ok(()) }
+            })
+            .await
+        };
+
+        assert_eq!(samples.len(), 5);
+        let mut arrivals = arrivals.lock().unwrap().clone();
+        arrivals.sort();
+        let gaps: Vec<Duration> = arrivals.windows(2).map(|w| w[1] - w[0]).collect();
+        assert_eq!(gaps, vec![Duration::from_millis(100); 4]);
+    }
+
+    #[tokio::test]
+    async fn huge_rates_start_everything_at_once() {
+        let options = LoadOptions {
+            rate: Some(1e12),
+            ..LoadOptions::default()
+        };
+        let samples = run_operations(3, &options, |_, _| async { Ok(()) }).await;
+        assert_eq!(samples.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn generating_before_starting_is_not_part_of_the_latency() {
+        let samples = run_operations(1, &LoadOptions::default(), |_, timer: Timer| async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            timer.start();
+            Ok(())
+        })
+        .await;
+        assert!(samples[0].started >= Duration::from_millis(200));
+        assert!(samples[0].elapsed < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn references_are_spread_over_the_repositories() {
+        let reference = |i, repositories| {
+            push_reference("localhost:6000", "test", "this", "latest", i, repositories).whole()
+        };
+        assert_eq!(reference(7, None), "localhost:6000/test/this-7:latest");
+        assert_eq!(reference(0, Some(3)), "localhost:6000/test/this-0:latest-0");
+        assert_eq!(reference(4, Some(3)), "localhost:6000/test/this-1:latest-4");
+        assert_eq!(reference(5, Some(3)), "localhost:6000/test/this-2:latest-5");
+        assert_eq!(reference(6, Some(3)), "localhost:6000/test/this-0:latest-6");
+    }
+
+    /// The bodies of the manifests put to a registry, by path.
+    type Manifests = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Serves a registry which doesn't require authentication and accepts every blob and manifest,
+    /// recording the manifests put to it.
+    fn serve_manifests(manifests: Manifests) -> SocketAddr {
+        let make_service = make_service_fn(move |_| {
+            let manifests = manifests.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |req: Request<Body>| {
+                    let manifests = manifests.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let status = match *req.method() {
+                            Method::POST => StatusCode::ACCEPTED,
+                            Method::PUT => StatusCode::CREATED,
+                            _ => StatusCode::OK,
+                        };
+                        if req.method() == Method::PUT && path.contains("/manifests/") {
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            manifests.lock().unwrap().insert(path, body.to_vec());
+                        }
+                        Ok::<_, std::convert::Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .header(header::LOCATION, "/v2/test/index/blobs/uploads/1")
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn index_entries_describe_the_manifests_put() {
+        let manifests = Manifests::default();
+        let addr = serve_manifests(manifests.clone());
+        let platforms = [
+            parse_platform("linux/amd64").unwrap(),
+            parse_platform("linux/arm64/v8").unwrap(),
+        ];
+        let push = PushOptions {
+            layer_size: Distribution::Fixed(4096),
+            ..PushOptions::default()
+        };
+        push_image_index(
+            format!("{addr}/test/index:latest").parse().unwrap(),
+            Credentials::Anonymous,
+            ClientProtocol::Http,
+            &push,
+            &platforms,
+            &HttpOptions::default(),
+            &Timer::default(),
+        )
+        .await
+        .unwrap();
+
+        let manifests = manifests.lock().unwrap();
+        let put = |path: String| {
+            manifests
+                .get(&path)
+                .unwrap_or_else(|| panic!("{path} was not put"))
+        };
+        let index: OciImageIndex =
+            serde_json::from_slice(put("/v2/test/index/manifests/latest".to_string())).unwrap();
+        assert_eq!(index.manifests.len(), platforms.len());
+        for (entry, platform) in index.manifests.iter().zip(&platforms) {
+            let manifest = put(format!("/v2/test/index/manifests/{}", entry.digest));
+            assert_eq!(
+                entry.digest,
+                format!("sha256:{:x}", Sha256::digest(manifest))
+            );
+            assert_eq!(usize::try_from(entry.size).unwrap(), manifest.len());
+            assert_eq!(entry.media_type, OCI_IMAGE_MEDIA_TYPE);
+            assert_eq!(entry.platform.as_ref(), Some(platform));
+        }
+    }
 }
@@ -0,0 +1,519 @@
+//! A mock token service in front of a registry, to test token authentication offline. Requests to
+//! the registry need a bearer token issued by the service, and are forwarded to the registry once
+//! authorized. Tokens grant access to every repository, as only their expiry is checked.
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hyper::{
+    body::HttpBody as _,
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use tracing::{debug, error, info};
+
+/// The path tokens are requested at.
+const TOKEN_PATH: &str = "/token";
+/// The service tokens are issued for.
+const SERVICE: &str = "oci-tester";
+/// The headers which only apply to a single connection, and aren't forwarded.
+const HOP_HEADERS: [header::HeaderName; 4] = [
+    header::CONNECTION,
+    header::HOST,
+    header::TRANSFER_ENCODING,
+    header::AUTHORIZATION,
+];
+
+/// Controls the mock token server.
+#[derive(Clone)]
+pub struct TokenServerOptions {
+    /// The address to listen on.
+    pub listen: SocketAddr,
+    /// The URL of the registry authorized requests are forwarded to.
+    pub upstream: String,
+    /// The user and password tokens are issued to. Tokens are issued to anyone when `None`.
+    pub userpass: Option<(String, String)>,
+    /// An identity token accepted in exchange for access tokens, on top of the refresh tokens
+    /// the server issues.
+    pub identity_token: Option<String>,
+    /// How long issued tokens are valid.
+    pub expires_in: Duration,
+    /// How long the server waits before answering token requests.
+    pub latency: Duration,
+}
+
+/// The state shared by the connections of the server.
+struct TokenServer {
+    options: TokenServerOptions,
+    http: reqwest::Client,
+    /// The access tokens issued, and when they expire.
+    tokens: Mutex<HashMap<String, Instant>>,
+    /// The refresh tokens accepted in exchange for access tokens.
+    refresh_tokens: Mutex<HashSet<String>>,
+}
+
+/// Serves tokens, and forwards the requests authorized with them to the upstream registry, until
+/// the server fails.
+///
+/// # Errors
+///
+/// * If the server can't listen on the address
+pub async fn serve(options: TokenServerOptions) -> anyhow::Result<()> {
+    let (listen, server) = bind(options)?;
+    info!(listen = %listen, "Serving tokens");
+    server.await
+}
+
+/// Binds the server to the address of `options`, returning the address it listens on, with the
+/// port picked for port 0, and the server to run.
+fn bind(
+    options: TokenServerOptions,
+) -> anyhow::Result<(SocketAddr, impl Future<Output = anyhow::Result<()>>)> {
+    let listen = options.listen;
+    let server = Arc::new(TokenServer {
+        refresh_tokens: Mutex::new(options.identity_token.iter().cloned().collect()),
+        options,
+        http: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?,
+        tokens: Mutex::new(HashMap::new()),
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let server = server.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let server = server.clone();
+                async move { Ok::<_, Infallible>(server.handle(req).await) }
+            }))
+        }
+    });
+    let bound = Server::try_bind(&listen)
+        .with_context(|| format!("failed to listen on {listen}"))?
+        .serve(make_service);
+    let listen = bound.local_addr();
+    Ok((listen, async move {
+        bound.await.context("the token server failed")
+    }))
+}
+
+impl TokenServer {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        debug!(method = %req.method(), uri = %req.uri(), "Handling request");
+        let result = if req.uri().path() == TOKEN_PATH {
+            tokio::time::sleep(self.options.latency).await;
+            self.token(req).await
+        } else if self.is_authorized(&req) {
+            self.forward(req).await
+        } else {
+            Ok(self.challenge(&req))
+        };
+        result.unwrap_or_else(|e| {
+            error!("Failed to handle a request: {e:#}");
+            reply(
+                StatusCode::BAD_GATEWAY,
+                &error_body("UNKNOWN", &e.to_string()),
+            )
+        })
+    }
+
+    /// Issues a token, with a `GET` following the Docker token protocol or an OAuth 2.0 `POST`.
+    async fn token(&self, req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        let (parts, body) = req.into_parts();
+        let params: HashMap<String, String> = match parts.method {
+            Method::GET => url::form_urlencoded::parse(parts.uri.query().unwrap_or("").as_bytes())
+                .into_owned()
+                .collect(),
+            Method::POST => {
+                let body = hyper::body::to_bytes(body).await?;
+                url::form_urlencoded::parse(&body).into_owned().collect()
+            }
+            _ => return Ok(reply(StatusCode::METHOD_NOT_ALLOWED, "")),
+        };
+        let param = |name: &str| params.get(name).map(String::as_str);
+
+        let (granted, offline) = match (&parts.method, param("grant_type")) {
+            (&Method::POST, Some("refresh_token")) => (
+                param("refresh_token")
+                    .is_some_and(|token| self.refresh_tokens.lock().unwrap().contains(token)),
+                false,
+            ),
+            (&Method::POST, Some("password")) => (
+                self.accepts(param("username"), param("password")),
+                param("access_type") == Some("offline"),
+            ),
+            (&Method::POST, _) => {
+                return Ok(reply(
+                    StatusCode::BAD_REQUEST,
+                    r#"{"error":"unsupported_grant_type"}"#,
+                ))
+            }
+            _ => {
+                let basic = basic_credentials(&parts.headers);
+                (
+                    self.accepts(
+                        basic.as_ref().map(|(user, _)| user.as_str()),
+                        basic.as_ref().map(|(_, password)| password.as_str()),
+                    ),
+                    param("offline_token") == Some("true"),
+                )
+            }
+        };
+        if !granted {
+            return Ok(reply(
+                StatusCode::UNAUTHORIZED,
+                &error_body("UNAUTHORIZED", "invalid credentials"),
+            ));
+        }
+
+        let token = random_token();
+        let now = Instant::now();
+        {
+            let mut tokens = self.tokens.lock().unwrap();
+            tokens.retain(|_, expires| *expires > now);
+            tokens.insert(token.clone(), now + self.options.expires_in);
+        }
+        let mut body = serde_json::json!({
+            "token": token,
+            "access_token": token,
+            "expires_in": self.options.expires_in.as_secs(),
+        });
+        if let Some(scope) = param("scope") {
+            body["scope"] = scope.into();
+        }
+        if offline {
+            let refresh_token = random_token();
+            self.refresh_tokens
+                .lock()
+                .unwrap()
+                .insert(refresh_token.clone());
+            body["refresh_token"] = refresh_token.into();
+        }
+        Ok(reply(StatusCode::OK, &body.to_string()))
+    }
+
+    /// Returns whether tokens are issued to `user` with `password`.
+    fn accepts(&self, user: Option<&str>, password: Option<&str>) -> bool {
+        match &self.options.userpass {
+            Some((expected_user, expected_password)) => {
+                user == Some(expected_user) && password == Some(expected_password)
+            }
+            None => true,
+        }
+    }
+
+    /// Returns whether a request has a bearer token which was issued and hasn't expired.
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let Some(token) = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        self.tokens
+            .lock()
+            .unwrap()
+            .get(token)
+            .is_some_and(|expires| *expires > Instant::now())
+    }
+
+    /// Answers an unauthorized request with a challenge pointing at the token service.
+    fn challenge(&self, req: &Request<Body>) -> Response<Body> {
+        let realm = format!("http://{}{TOKEN_PATH}", host(req, self.options.listen));
+        let mut res = reply(
+            StatusCode::UNAUTHORIZED,
+            &error_body("UNAUTHORIZED", "authentication required"),
+        );
+        if let Ok(value) = format!(r#"Bearer realm="{realm}",service="{SERVICE}""#).parse() {
+            res.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+        }
+        res
+    }
+
+    /// Forwards an authorized request to the upstream registry, pointing the locations it returns
+    /// back at the server. Bodies are streamed both ways, so that blobs of any size go through in
+    /// constant memory.
+    async fn forward(&self, req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        let upstream = self.options.upstream.trim_end_matches('/');
+        let here = format!("http://{}", host(&req, self.options.listen));
+        let (parts, body) = req.into_parts();
+        let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+
+        let mut outgoing = self.http.request(parts.method, format!("{upstream}{path}"));
+        // Streamed bodies are sent chunked unless their length is known, so empty ones are left out.
+        if body.size_hint().exact() != Some(0) {
+            outgoing = outgoing.body(reqwest::Body::wrap_stream(body));
+        }
+        for (name, value) in &parts.headers {
+            if !HOP_HEADERS.contains(name) {
+                outgoing = outgoing.header(name, value);
+            }
+        }
+        let incoming = outgoing
+            .send()
+            .await
+            .with_context(|| format!("failed to forward {path} to {upstream}"))?;
+
+        let mut res = Response::builder().status(incoming.status());
+        for (name, value) in incoming.headers() {
+            if HOP_HEADERS.contains(name) {
+                continue;
+            }
+            match value.to_str() {
+                Ok(location) if name == header::LOCATION && location.starts_with(upstream) => {
+                    res = res.header(name, location.replacen(upstream, &here, 1));
+                }
+                _ => res = res.header(name, value),
+            }
+        }
+        Ok(res.body(Body::wrap_stream(incoming.bytes_stream()))?)
+    }
+}
+
+/// Returns the host a request was sent to, falling back to the address the server listens on.
+fn host(req: &Request<Body>, listen: SocketAddr) -> String {
+    req.headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map_or_else(|| listen.to_string(), str::to_string)
+}
+
+/// Returns the user and password of a basic `Authorization` header.
+fn basic_credentials(headers: &header::HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Returns an OCI error body.
+fn error_body(code: &str, message: &str) -> String {
+    serde_json::json!({ "errors": [{ "code": code, "message": message }] }).to_string()
+}
+
+fn reply(status: StatusCode, body: &str) -> Response<Body> {
+    let mut res = Response::new(Body::from(body.to_string()));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_distribution::client::ClientProtocol;
+
+    use super::*;
+    use crate::registry::{scope, Credentials, Registry};
+
+    /// Serves a registry which answers uploads with a location on itself, and any other request
+    /// with its own body. Returns its URL.
+    fn serve_upstream() -> String {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let host = host(&req, SocketAddr::from(([127, 0, 0, 1], 0)));
+                let res = if req.method() == Method::POST {
+                    Response::builder()
+                        .status(StatusCode::ACCEPTED)
+                        .header(
+                            header::LOCATION,
+                            format!("http://{host}/v2/test/blobs/uploads/1"),
+                        )
+                        .body(Body::empty())
+                } else {
+                    Response::builder().body(req.into_body())
+                };
+                Ok::<_, Infallible>(res.unwrap())
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let upstream = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        upstream
+    }
+
+    /// Runs a token server in front of a registry, and returns the registry (the host and port)
+    /// it serves at.
+    fn serve_tokens(
+        userpass: Option<(&str, &str)>,
+        identity_token: Option<&str>,
+        expires_in: Duration,
+    ) -> String {
+        let options = TokenServerOptions {
+            listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+            upstream: serve_upstream(),
+            userpass: userpass.map(|(user, password)| (user.to_string(), password.to_string())),
+            identity_token: identity_token.map(str::to_string),
+            expires_in,
+            latency: Duration::ZERO,
+        };
+        let (listen, server) = bind(options).unwrap();
+        tokio::spawn(server);
+        listen.to_string()
+    }
+
+    /// Returns a client of `registry` authenticated with `credentials`.
+    async fn authenticate(registry: &str, credentials: Credentials) -> Result<Registry, String> {
+        let http = reqwest::Client::builder().no_proxy().build().unwrap();
+        let mut client = Registry::new(http, &ClientProtocol::Http, registry, credentials);
+        client
+            .authenticate(&[scope("test", "pull,push")])
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(client)
+    }
+
+    #[tokio::test]
+    async fn tokens_authorize_forwarded_requests() {
+        let registry = serve_tokens(None, None, Duration::from_mins(5));
+        let client = authenticate(&registry, Credentials::Anonymous)
+            .await
+            .unwrap();
+
+        let res = client
+            .request(Method::PUT, "/v2/test/manifests/latest")
+            .body("manifest")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "manifest");
+
+        let res = client
+            .request(Method::POST, "/v2/test/blobs/uploads/")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            res.headers()[header::LOCATION],
+            format!("http://{registry}/v2/test/blobs/uploads/1")
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_token_are_challenged() {
+        let registry = serve_tokens(None, None, Duration::from_mins(5));
+        let res = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .get(format!("http://{registry}/v2/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers()[header::WWW_AUTHENTICATE],
+            format!(r#"Bearer realm="http://{registry}/token",service="oci-tester""#)
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_challenged() {
+        let registry = serve_tokens(None, None, Duration::from_millis(100));
+        let client = authenticate(&registry, Credentials::Anonymous)
+            .await
+            .unwrap();
+        let get = || async {
+            client
+                .request(Method::GET, "/v2/")
+                .send()
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(get().await, StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(get().await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_are_exchanged_for_access_tokens() {
+        let registry = serve_tokens(
+            Some(("user", "password")),
+            Some("identity"),
+            Duration::from_mins(5),
+        );
+        let http = reqwest::Client::builder().no_proxy().build().unwrap();
+        let token = |form: Vec<(&'static str, String)>| {
+            let req = http
+                .post(format!("http://{registry}{TOKEN_PATH}"))
+                .form(&form);
+            async move {
+                let res = req.send().await.unwrap();
+                let status = res.status();
+                (status, res.json::<serde_json::Value>().await.unwrap())
+            }
+        };
+
+        let (status, body) = token(vec![
+            ("grant_type", "password".to_string()),
+            ("username", "user".to_string()),
+            ("password", "password".to_string()),
+            ("access_type", "offline".to_string()),
+        ])
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let refresh_token = body["refresh_token"].as_str().unwrap().to_string();
+
+        let (status, body) = token(vec![
+            ("grant_type", "refresh_token".to_string()),
+            ("refresh_token", refresh_token),
+        ])
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["access_token"].is_string());
+
+        let (status, _) = token(vec![
+            ("grant_type", "refresh_token".to_string()),
+            ("refresh_token", "unknown".to_string()),
+        ])
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        authenticate(
+            &registry,
+            Credentials::IdentityToken("identity".to_string()),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrong_credentials_are_rejected() {
+        let registry = serve_tokens(Some(("user", "password")), None, Duration::from_mins(5));
+        let basic = |password: &str| Credentials::Basic("user".to_string(), password.to_string());
+        authenticate(&registry, basic("password")).await.unwrap();
+        let error = authenticate(&registry, basic("wrong")).await.unwrap_err();
+        assert!(error.starts_with("UnauthorizedError"), "{error}");
+        assert!(authenticate(&registry, Credentials::Anonymous)
+            .await
+            .is_err());
+    }
+}